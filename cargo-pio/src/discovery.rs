@@ -0,0 +1,503 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use embuild::pio::*;
+use log::*;
+
+use crate::message::{Message, MessageFormat};
+
+pub fn list_boards(
+    pio: &Pio,
+    query: Option<&str>,
+    mcu: Option<&str>,
+    platform: Option<&str>,
+    framework: Option<&str>,
+    sort: &str,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let mut boards = filter_boards(pio.boards(None::<&str>)?, query, mcu, platform, framework);
+    sort_boards(&mut boards, sort);
+
+    if message_format == MessageFormat::Json {
+        for board in &boards {
+            Message::Board {
+                board,
+                target: Resolver::derive_target(&board.mcu).ok(),
+            }
+            .print()?;
+        }
+    } else {
+        print_table(
+            &[
+                "ID",
+                "NAME",
+                "MCU",
+                "PLATFORM",
+                "FRAMEWORKS",
+                "RAM",
+                "ROM",
+                "RUST TARGET",
+            ],
+            boards.iter().map(|board| {
+                vec![
+                    board.id.clone(),
+                    board.name.clone(),
+                    board.mcu.clone(),
+                    board.platform.clone(),
+                    board.frameworks.join(", "),
+                    format!("{}K", board.ram / 1024),
+                    format!("{}K", board.rom / 1024),
+                    Resolver::derive_target(&board.mcu)
+                        .unwrap_or("-")
+                        .to_owned(),
+                ]
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn list_platforms(
+    pio: &Pio,
+    query: Option<&str>,
+    framework: Option<&str>,
+    sort: &str,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let mut platforms = pio
+        .platforms(None::<&str>)?
+        .into_iter()
+        .filter(|platform| {
+            matches(query, |query| {
+                contains(&platform.name, query) || contains(&platform.title, query)
+            }) && matches(framework, |framework| {
+                platform.frameworks.iter().any(|f| f == framework)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    platforms.sort_by(|a, b| match sort {
+        "title" => a.title.cmp(&b.title),
+        _ => a.name.cmp(&b.name),
+    });
+
+    let platform_targets = platform_targets(&pio.boards(None::<&str>)?);
+
+    if message_format == MessageFormat::Json {
+        for platform in &platforms {
+            Message::Platform {
+                platform,
+                targets: &targets(&platform_targets, [&platform.name]),
+            }
+            .print()?;
+        }
+    } else {
+        print_table(
+            &["NAME", "TITLE", "FRAMEWORKS", "RUST TARGETS"],
+            platforms.iter().map(|platform| {
+                vec![
+                    platform.name.clone(),
+                    platform.title.clone(),
+                    platform.frameworks.join(", "),
+                    targets_cell(&targets(&platform_targets, [&platform.name])),
+                ]
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn list_frameworks(
+    pio: &Pio,
+    query: Option<&str>,
+    platform: Option<&str>,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let mut frameworks = pio
+        .frameworks(None::<&str>)?
+        .into_iter()
+        .filter(|framework| {
+            matches(query, |query| {
+                contains(&framework.name, query)
+                    || matches!(&framework.title, Some(title) if contains(title, query))
+            }) && matches(platform, |platform| {
+                framework.platforms.iter().any(|p| p == platform)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    frameworks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let platform_targets = platform_targets(&pio.boards(None::<&str>)?);
+
+    if message_format == MessageFormat::Json {
+        for framework in &frameworks {
+            Message::Framework {
+                framework,
+                targets: &targets(&platform_targets, &framework.platforms),
+            }
+            .print()?;
+        }
+    } else {
+        print_table(
+            &["NAME", "TITLE", "PLATFORMS", "RUST TARGETS"],
+            frameworks.iter().map(|framework| {
+                vec![
+                    framework.name.clone(),
+                    framework.title.clone().unwrap_or_default(),
+                    framework.platforms.join(", "),
+                    targets_cell(&targets(&platform_targets, &framework.platforms)),
+                ]
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+/// The number of libraries that are sorted by anything else than relevance, because
+/// every page of results is a separate registry request.
+const SORTED_LIBRARIES_LIMIT: usize = 100;
+
+#[allow(clippy::too_many_arguments)]
+pub fn search_libraries(
+    pio: &Pio,
+    query: &[String],
+    framework: Option<&str>,
+    platform: Option<&str>,
+    limit: usize,
+    sort: &str,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let mut args = query.to_vec();
+
+    if let Some(framework) = framework {
+        args.extend(["--framework".to_owned(), framework.to_owned()]);
+    }
+
+    if let Some(platform) = platform {
+        args.extend(["--platform".to_owned(), platform.to_owned()]);
+    }
+
+    // The registry only orders by relevance, so sorting by anything else needs more
+    // results than the first `limit` of them.
+    let mut libraries = if sort == "relevance" {
+        pio.search_libraries(&args, limit)?
+    } else {
+        let fetch_limit = limit.max(SORTED_LIBRARIES_LIMIT);
+
+        // One more library tells whether there are more results than are sorted
+        let mut libraries = pio.search_libraries(&args, fetch_limit + 1)?;
+        if libraries.len() > fetch_limit {
+            libraries.truncate(fetch_limit);
+
+            warn!(
+                "Only the {} most relevant matching libraries are sorted by {}, narrow down the query to sort all of them",
+                fetch_limit, sort
+            );
+        }
+
+        sort_libraries(&mut libraries, sort);
+        libraries
+    };
+    libraries.truncate(limit);
+
+    let platform_targets = platform_targets(&pio.boards(None::<&str>)?);
+    let library_targets = |library: &Library| {
+        if library.platforms.iter().any(|p| p.name == "*") {
+            targets(&platform_targets, platform_targets.keys())
+        } else {
+            targets(&platform_targets, library.platforms.iter().map(|p| &p.name))
+        }
+    };
+
+    if message_format == MessageFormat::Json {
+        for library in &libraries {
+            Message::Library {
+                library,
+                targets: &library_targets(library),
+            }
+            .print()?;
+        }
+    } else {
+        print_table(
+            &[
+                "ID",
+                "NAME",
+                "VERSION",
+                "DOWNLOADS/MONTH",
+                "PLATFORMS",
+                "RUST TARGETS",
+            ],
+            libraries.iter().map(|library| {
+                vec![
+                    library.id.to_string(),
+                    library.name.clone(),
+                    library.versionname.clone(),
+                    library.dlmonth.to_string(),
+                    library
+                        .platforms
+                        .iter()
+                        .map(|p| &p.name[..])
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    targets_cell(&library_targets(library)),
+                ]
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+fn filter_boards(
+    boards: Vec<Board>,
+    query: Option<&str>,
+    mcu: Option<&str>,
+    platform: Option<&str>,
+    framework: Option<&str>,
+) -> Vec<Board> {
+    boards
+        .into_iter()
+        .filter(|board| {
+            matches(query, |query| {
+                contains(&board.id, query) || contains(&board.name, query)
+            }) && matches(mcu, |mcu| board.mcu.eq_ignore_ascii_case(mcu))
+                && matches(platform, |platform| board.platform == platform)
+                && matches(framework, |framework| {
+                    board.frameworks.iter().any(|f| f == framework)
+                })
+        })
+        .collect()
+}
+
+fn sort_boards(boards: &mut [Board], sort: &str) {
+    boards.sort_by(|a, b| {
+        match sort {
+            "name" => a.name.cmp(&b.name),
+            "mcu" => a.mcu.cmp(&b.mcu),
+            "platform" => a.platform.cmp(&b.platform),
+            "ram" => a.ram.cmp(&b.ram),
+            "rom" => a.rom.cmp(&b.rom),
+            _ => a.id.cmp(&b.id),
+        }
+        .then_with(|| a.id.cmp(&b.id))
+    });
+}
+
+fn sort_libraries(libraries: &mut [Library], sort: &str) {
+    match sort {
+        "name" => libraries.sort_by(|a, b| a.name.cmp(&b.name)),
+        "downloads" => libraries.sort_by_key(|library| Reverse(library.dlmonth)),
+        "updated" => libraries.sort_by(|a, b| b.updated.cmp(&a.updated)),
+        // Keep the order of the registry
+        _ => {}
+    }
+}
+
+/// Get the Rust targets derived from the MCUs of `boards`, by platform.
+fn platform_targets(boards: &[Board]) -> BTreeMap<String, BTreeSet<&'static str>> {
+    let mut targets = BTreeMap::<String, BTreeSet<&'static str>>::new();
+
+    for board in boards {
+        if let Ok(target) = Resolver::derive_target(&board.mcu) {
+            targets
+                .entry(board.platform.clone())
+                .or_default()
+                .insert(target);
+        }
+    }
+
+    targets
+}
+
+fn targets<'a>(
+    platform_targets: &BTreeMap<String, BTreeSet<&'static str>>,
+    platforms: impl IntoIterator<Item = &'a String>,
+) -> Vec<&'static str> {
+    platforms
+        .into_iter()
+        .filter_map(|platform| platform_targets.get(platform))
+        .flatten()
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+fn targets_cell(targets: &[&str]) -> String {
+    if targets.is_empty() {
+        "-".to_owned()
+    } else {
+        targets.join(", ")
+    }
+}
+
+/// Whether the optional `filter` is either not set or matched by `f`.
+fn matches(filter: Option<&str>, f: impl FnOnce(&str) -> bool) -> bool {
+    match filter {
+        Some(filter) => f(filter),
+        None => true,
+    }
+}
+
+fn contains(s: &str, query: &str) -> bool {
+    s.to_lowercase().contains(&query.to_lowercase())
+}
+
+fn print_table(header: &[&str], rows: impl Iterator<Item = Vec<String>>) {
+    let rows = rows.collect::<Vec<_>>();
+
+    let mut widths = header.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: &mut dyn Iterator<Item = &str>| {
+        cells
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_owned()
+    };
+
+    println!("{}", format_row(&mut header.iter().copied()));
+
+    for row in &rows {
+        println!("{}", format_row(&mut row.iter().map(String::as_str)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(id: &str, mcu: &str, platform: &str, ram: u64) -> Board {
+        Board {
+            id: id.into(),
+            name: id.to_uppercase(),
+            mcu: mcu.into(),
+            platform: platform.into(),
+            ram,
+            frameworks: vec!["espidf".into()],
+            ..Default::default()
+        }
+    }
+
+    fn boards() -> Vec<Board> {
+        vec![
+            board("esp32dev", "ESP32", "espressif32", 320 * 1024),
+            board("esp32-c3-devkitm-1", "ESP32C3", "espressif32", 400 * 1024),
+            board("uno", "ATMEGA328P", "atmelavr", 2 * 1024),
+            board("custom", "UNKNOWN", "espressif32", 0),
+        ]
+    }
+
+    #[test]
+    fn filter_and_sort_boards() {
+        let ids = |boards: &[Board]| boards.iter().map(|b| b.id.clone()).collect::<Vec<_>>();
+
+        assert_eq!(filter_boards(boards(), None, None, None, None).len(), 4);
+        assert_eq!(
+            ids(&filter_boards(boards(), Some("C3"), None, None, None)),
+            ["esp32-c3-devkitm-1"]
+        );
+        assert_eq!(
+            ids(&filter_boards(boards(), None, Some("esp32"), None, None)),
+            ["esp32dev"]
+        );
+        assert_eq!(
+            ids(&filter_boards(
+                boards(),
+                Some("esp"),
+                None,
+                Some("espressif32"),
+                Some("espidf")
+            )),
+            ["esp32dev", "esp32-c3-devkitm-1"]
+        );
+        assert!(filter_boards(boards(), None, None, None, Some("arduino")).is_empty());
+
+        let mut sorted = boards();
+        sort_boards(&mut sorted, "id");
+        assert_eq!(
+            ids(&sorted),
+            ["custom", "esp32-c3-devkitm-1", "esp32dev", "uno"]
+        );
+        sort_boards(&mut sorted, "ram");
+        assert_eq!(
+            ids(&sorted),
+            ["custom", "uno", "esp32dev", "esp32-c3-devkitm-1"]
+        );
+        sort_boards(&mut sorted, "platform");
+        assert_eq!(
+            ids(&sorted),
+            ["uno", "custom", "esp32-c3-devkitm-1", "esp32dev"]
+        );
+    }
+
+    #[test]
+    fn sort_libs() {
+        let library = |name: &str, dlmonth: u64, updated: &str| Library {
+            name: name.into(),
+            dlmonth,
+            updated: updated.into(),
+            ..Default::default()
+        };
+        let names =
+            |libraries: &[Library]| libraries.iter().map(|l| l.name.clone()).collect::<Vec<_>>();
+
+        let mut libraries = vec![
+            library("b", 10, "2021-05-01T00:00:00Z"),
+            library("c", 30, "2020-01-01T00:00:00Z"),
+            library("a", 20, "2022-01-01T00:00:00Z"),
+        ];
+
+        sort_libraries(&mut libraries, "relevance");
+        assert_eq!(names(&libraries), ["b", "c", "a"]);
+        sort_libraries(&mut libraries, "downloads");
+        assert_eq!(names(&libraries), ["c", "a", "b"]);
+        sort_libraries(&mut libraries, "updated");
+        assert_eq!(names(&libraries), ["a", "b", "c"]);
+        sort_libraries(&mut libraries, "name");
+        assert_eq!(names(&libraries), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn rust_targets() {
+        let platform_targets = platform_targets(&boards());
+
+        assert_eq!(
+            platform_targets["espressif32"],
+            ["riscv32imc-esp-espidf", "xtensa-esp32-espidf"]
+                .iter()
+                .copied()
+                .collect::<BTreeSet<_>>()
+        );
+        assert_eq!(
+            targets(&platform_targets, [&"atmelavr".to_owned()]),
+            ["avr-unknown-gnu-atmega328"]
+        );
+        assert_eq!(
+            targets(&platform_targets, platform_targets.keys()),
+            [
+                "avr-unknown-gnu-atmega328",
+                "riscv32imc-esp-espidf",
+                "xtensa-esp32-espidf"
+            ]
+        );
+        assert!(targets(&platform_targets, [&"ststm32".to_owned()]).is_empty());
+        assert_eq!(targets_cell(&[]), "-");
+        assert_eq!(
+            targets_cell(&["riscv32imc-esp-espidf", "xtensa-esp32-espidf"]),
+            "riscv32imc-esp-espidf, xtensa-esp32-espidf"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use embuild::pio::*;
+use embuild::{cargo, python};
+use serde::Serialize;
+
+use crate::message::MessageFormat;
+
+const PYTHON_MIN_VERSION: (u32, u32) = (3, 6);
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    /// How to fix a failed or suspicious check
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub platformio: Option<PioInstallerInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub linker: Option<PathBuf>,
+}
+
+impl Report {
+    pub fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count()
+    }
+
+    pub fn print(&self) {
+        for check in &self.checks {
+            println!(
+                "[{}] {}: {}",
+                check.status.label(),
+                check.name,
+                check.message
+            );
+
+            if let Some(hint) = &check.hint {
+                println!("       hint: {}", hint);
+            }
+        }
+    }
+
+    fn pass(&mut self, name: &'static str, message: impl Into<String>) {
+        self.push(name, Status::Pass, message.into(), None);
+    }
+
+    fn warn(&mut self, name: &'static str, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(name, Status::Warn, message.into(), Some(hint.into()));
+    }
+
+    fn fail(&mut self, name: &'static str, message: impl Into<String>, hint: impl Into<String>) {
+        self.push(name, Status::Fail, message.into(), Some(hint.into()));
+    }
+
+    fn push(&mut self, name: &'static str, status: Status, message: String, hint: Option<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            message,
+            hint,
+        });
+    }
+}
+
+/// Checks Python, the PlatformIO installation, the Rust target of the crate in `project`
+/// and - unless `skip_toolchain` is set - the toolchain PlatformIO uses for that target.
+///
+/// Checks whose preconditions failed are reported as skipped rather than run.
+pub fn diagnose(
+    project: impl AsRef<Path>,
+    pio_path: Option<PathBuf>,
+    target: Option<String>,
+    skip_toolchain: bool,
+) -> Report {
+    let mut report = Report::default();
+
+    let (major, minor) = PYTHON_MIN_VERSION;
+    let python_ok = match python::check_python_at_least(major, minor) {
+        Ok(()) => {
+            report.pass(
+                "python",
+                format!("{} {}.{} or later found", python::PYTHON, major, minor),
+            );
+            true
+        }
+        Err(err) => {
+            report.fail(
+                "python",
+                format!("{:#}", err),
+                format!(
+                    "Install Python {}.{} or later and make sure '{}' is in your PATH",
+                    major,
+                    minor,
+                    python::PYTHON
+                ),
+            );
+            false
+        }
+    };
+
+    let pio = if python_ok {
+        match check_platformio(pio_path) {
+            Ok(info) if info.platformio_exe.exists() => {
+                report.pass(
+                    "platformio",
+                    format!(
+                        "PlatformIO Core {} in {} (Python {})",
+                        info.core_version,
+                        info.core_dir.display(),
+                        info.python_version
+                    ),
+                );
+
+                let pio = Pio::from(info.clone()).log_level(LogLevel::Quiet);
+                report.platformio = Some(info);
+
+                Some(pio)
+            }
+            Ok(info) => {
+                report.fail(
+                    "platformio",
+                    format!(
+                        "PlatformIO executable {} does not exist",
+                        info.platformio_exe.display()
+                    ),
+                    "Run 'cargo pio installpio' to reinstall PlatformIO",
+                );
+                None
+            }
+            Err(err) => {
+                report.fail(
+                    "platformio",
+                    format!("{:#}", err),
+                    "Run 'cargo pio installpio' to install PlatformIO",
+                );
+                None
+            }
+        }
+    } else {
+        report.fail(
+            "platformio",
+            "Skipped, Python is not available",
+            "Fix the Python check first",
+        );
+        None
+    };
+
+    let target = match target {
+        Some(target) => Some(target),
+        None => match cargo::Crate::new(project).get_default_target() {
+            Ok(target) => target,
+            Err(err) => {
+                report.fail(
+                    "target",
+                    format!("{:#}", err),
+                    "Fix the Cargo configuration files (.cargo/config.toml) of the project",
+                );
+                return report;
+            }
+        },
+    };
+
+    let target = if let Some(target) = target {
+        match Resolver::derive_target_conf(&target) {
+            Ok(conf) => report.pass(
+                "target",
+                format!(
+                    "{} (platform {}, MCU {}, frameworks {})",
+                    target,
+                    conf.platform,
+                    conf.mcu,
+                    conf.frameworks.join(", ")
+                ),
+            ),
+            Err(_) => report.warn(
+                "target",
+                format!("{} has no known PlatformIO platform and MCU", target),
+                "Pass the board, MCU and platform explicitly when creating or resolving the project",
+            ),
+        }
+
+        report.target = Some(target.clone());
+
+        target
+    } else {
+        report.warn(
+            "target",
+            "No build target is configured",
+            "Set 'build.target' in .cargo/config.toml or pass --target",
+        );
+        return report;
+    };
+
+    if skip_toolchain {
+        return report;
+    }
+
+    match pio {
+        Some(pio) => match check_toolchain(pio, &target) {
+            Ok(linker) => {
+                report.pass("toolchain", format!("Linker {}", linker.display()));
+                report.linker = Some(linker);
+            }
+            Err(err) => report.fail(
+                "toolchain",
+                format!("{:#}", err),
+                format!(
+                    "Run 'cargo pio printscons --target {}' to see the full PlatformIO output",
+                    target
+                ),
+            ),
+        },
+        None => report.fail(
+            "toolchain",
+            "Skipped, PlatformIO is not available",
+            "Fix the PlatformIO check first",
+        ),
+    }
+
+    report
+}
+
+fn check_platformio(pio_path: Option<PathBuf>) -> Result<PioInstallerInfo> {
+    crate::check_pio(pio_path, LogLevel::Quiet, MessageFormat::Human)
+        .context("PlatformIO is not installed or its installation is broken")
+}
+
+fn check_toolchain(pio: Pio, target: &str) -> Result<PathBuf> {
+    let resolution = Resolver::new(pio.clone())
+        .params(ResolutionParams {
+            target: Some(target.to_owned()),
+            ..Default::default()
+        })
+        .resolve(true)?;
+
+    // PlatformIO is quiet, so its output doesn't depend on the message format
+    let scons_vars =
+        crate::get_framework_scons_vars(&pio, false, true, &resolution, MessageFormat::Human)?;
+
+    if scons_vars.link.is_empty() {
+        bail!("PlatformIO did not report a linker for {}", target);
+    }
+
+    scons_vars.full_path(&scons_vars.link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures() {
+        let mut report = Report::default();
+        assert_eq!(report.failures(), 0);
+
+        report.pass("python", "Python 3.6 or later found");
+        report.warn("target", "No build target is configured", "Pass --target");
+        assert_eq!(report.failures(), 0);
+
+        report.fail("platformio", "Not installed", "Run 'cargo pio installpio'");
+        report.fail("toolchain", "Skipped", "Fix the PlatformIO check first");
+        assert_eq!(report.failures(), 2);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"][0]["status"], "pass");
+        assert!(json["checks"][0].get("hint").is_none());
+        assert_eq!(json["checks"][2]["status"], "fail");
+        assert_eq!(json["checks"][2]["hint"], "Run 'cargo pio installpio'");
+        assert!(json.get("target").is_none());
+    }
+}
@@ -1,15 +1,21 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::process::{self, Stdio};
+use std::{env, fs, io};
 
 use anyhow::{bail, Result};
 use embuild::cargo::CargoCmd;
 use embuild::pio::*;
 use embuild::*;
 use log::*;
+use message::{Message, MessageFormat};
 use structopt::StructOpt;
 use tempfile::TempDir;
 
+mod discovery;
+mod doctor;
+mod message;
+
 const PLATFORMIO_ESP32_EXCEPTION_DECODER_DIFF: &[u8] =
     include_bytes!("patches/filter_exception_decoder_esp32c3_external_conf_fix.diff");
 
@@ -26,6 +32,11 @@ struct Opt {
     /// Stay quiet, don't print any output
     #[structopt(short, long)]
     quiet: bool,
+    /// The format of the printed results
+    ///
+    /// With 'json', results are printed to stdout as one JSON object per line (see the README for the schema)
+    #[structopt(long, global = true, default_value = "human", possible_values = &["human", "json"])]
+    message_format: MessageFormat,
 
     #[structopt(subcommand)]
     cmd: Command,
@@ -45,6 +56,27 @@ enum Command {
         #[structopt(parse(from_os_str))]
         path: Option<PathBuf>,
     },
+    /// Diagnoses Python, the PlatformIO installation and the Rust target configuration
+    Doctor {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// Rust target to check. Defaults to the target configured in the Cargo configuration files
+        #[structopt(short, long)]
+        target: Option<String>,
+
+        /// Do not check the toolchain of the target
+        ///
+        /// Checking the toolchain requires PlatformIO to install the platform and framework of the target, which might take a while
+        #[structopt(long)]
+        skip_toolchain: bool,
+
+        /// Prints the report as JSON
+        ///
+        /// Equivalent to '--message-format json'
+        #[structopt(long)]
+        json: bool,
+    },
     /// Prints one or all Scons environment variables that would be used when PlatformIO builds a project
     Printscons {
         #[structopt(flatten)]
@@ -60,8 +92,9 @@ enum Command {
 
         /// PlatformIO Scons environment variable to print
         #[structopt(short = "s", long,
-                    possible_values = &["path", "incflags", "libflags", "libdirflags", "libs",
-                                        "linkflags", "link", "linkcom", "mcu", "clangargs"])]
+                    possible_values = &["path", "incflags", "defflags", "cflags", "cc", "libflags",
+                                        "libdirflags", "libs", "linkflags", "link", "linkcom", "mcu",
+                                        "clangargs"])]
         var: Option<String>,
     },
     /// Creates a new PIO->Cargo project
@@ -90,7 +123,8 @@ enum Command {
         #[structopt(required = false, allow_hyphen_values = true, last = true)]
         cargo_args: Vec<String>,
     },
-    /// Upgrades an existing Cargo library crate to a PIO->Cargo project
+    /// Upgrades an existing Cargo library crate to a PIO->Cargo project, adding only the
+    /// Cargo settings it is missing
     Upgrade {
         #[structopt(flatten)]
         pio_ini_args: PioIniArgs,
@@ -121,16 +155,130 @@ enum Command {
         /// Equivalent to executing subcommand 'exec -- run -e release'
         #[structopt(long, short)]
         release: bool,
+
+        /// PlatformIO environment to build
+        ///
+        /// Equivalent to executing subcommand 'exec -- run -e <environment>'
+        #[structopt(long, short = "e", conflicts_with = "release")]
+        environment: Option<String>,
+    },
+    /// Flashes a PIO->Cargo project to a device
+    ///
+    /// Equivalent to executing subcommand 'exec -- run -t upload -e <environment> --upload-port <port>'
+    #[structopt(visible_alias = "upload")]
+    Flash {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// Upload port. Defaults to the port detected by PlatformIO
+        #[structopt(short, long)]
+        port: Option<String>,
+
+        /// Upload protocol (e.g. esptool, esp-prog, jlink). Defaults to the protocol configured for the environment
+        #[structopt(long)]
+        upload_protocol: Option<String>,
+
+        /// Indicates release configuration
+        ///
+        /// Equivalent to '-e release'
+        #[structopt(long, short)]
+        release: bool,
+
+        /// PlatformIO environment to flash
+        ///
+        /// If not specified, the PlatformIO project default environment will be used
+        #[structopt(long, short = "e", conflicts_with = "release")]
+        environment: Option<String>,
+
+        /// Starts the PlatformIO monitor after flashing
+        #[structopt(long, short)]
+        monitor: bool,
+
+        /// Baud rate of the monitor. Defaults to the baud rate configured for the environment
+        #[structopt(short = "b", long)]
+        baud_rate: Option<u32>,
     },
     /// Executes PlatformIO in the current directory
     Exec {
         #[structopt(flatten)]
         pio_install: PioInstallation,
 
+        /// PlatformIO environment, passed as '-e <environment>' after the pass-through arguments
+        #[structopt(long, short = "e")]
+        environment: Option<String>,
+
         /// Pass-through arguments down to PlatformIO
         #[structopt(required = false, allow_hyphen_values = true, last = true)]
         pio_args: Vec<OsString>,
     },
+    /// Manages the environments of the platformio.ini file of a PlatformIO or PIO->Cargo project
+    Env {
+        #[structopt(subcommand)]
+        cmd: EnvCommand,
+    },
+    /// Lists the boards supported by PlatformIO with the Rust target derived from their MCU
+    Boards {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// Only lists boards with this MCU
+        #[structopt(short, long)]
+        mcu: Option<String>,
+
+        /// Only lists boards of this platform
+        #[structopt(short, long)]
+        platform: Option<String>,
+
+        /// Only lists boards supporting this framework
+        #[structopt(short, long)]
+        framework: Option<String>,
+
+        /// Sorts the boards by this column
+        #[structopt(long, default_value = "id", possible_values = &["id", "name", "mcu", "platform", "ram", "rom"])]
+        sort: String,
+
+        /// Only lists boards whose ID or name contains this text
+        #[structopt()]
+        query: Option<String>,
+    },
+    /// Lists the PlatformIO platforms with the Rust targets derived from the MCUs of their boards
+    Platforms {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// Only lists platforms supporting this framework
+        #[structopt(short, long)]
+        framework: Option<String>,
+
+        /// Sorts the platforms by this column
+        #[structopt(long, default_value = "name", possible_values = &["name", "title"])]
+        sort: String,
+
+        /// Only lists platforms whose name or title contains this text
+        #[structopt()]
+        query: Option<String>,
+    },
+    /// Lists the PlatformIO frameworks with the Rust targets derived from the MCUs of the boards of their platforms
+    Frameworks {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        /// Only lists frameworks supported by this platform
+        #[structopt(short, long)]
+        platform: Option<String>,
+
+        /// Only lists frameworks whose name or title contains this text
+        #[structopt()]
+        query: Option<String>,
+    },
+    /// Invokes commands of the PlatformIO library registry
+    Libs {
+        #[structopt(flatten)]
+        pio_install: PioInstallation,
+
+        #[structopt(subcommand)]
+        cmd: LibsCommand,
+    },
     /// Invokes commands specific for the ESP-IDF SDK
     Espidf {
         #[structopt(flatten)]
@@ -141,6 +289,62 @@ enum Command {
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum LibsCommand {
+    /// Searches libraries, listing them with the Rust targets derived from the MCUs of the boards of their platforms
+    Search {
+        /// Only lists libraries supporting this framework
+        #[structopt(short, long)]
+        framework: Option<String>,
+
+        /// Only lists libraries supporting this platform
+        #[structopt(short, long)]
+        platform: Option<String>,
+
+        /// Maximum number of libraries to list
+        #[structopt(short, long, default_value = "20")]
+        limit: usize,
+
+        /// Sorts the libraries by this column. Defaults to the relevance determined by the registry
+        ///
+        /// Any other order is applied to the 100 most relevant matching libraries (or more if the
+        /// limit is higher) before the limit, which needs more registry requests
+        #[structopt(long, default_value = "relevance", possible_values = &["relevance", "name", "downloads", "updated"])]
+        sort: String,
+
+        /// Search query, in the syntax of 'pio lib search'
+        #[structopt()]
+        query: Vec<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum EnvCommand {
+    /// Lists the environments with their board, platform, frameworks and Rust target
+    List,
+    /// Adds an environment for another board, MCU or Rust target
+    ///
+    /// All other options are inherited from the common [env] section
+    Add {
+        #[structopt(flatten)]
+        framework_args: PioFrameworkArgs,
+
+        /// Indicates release configuration
+        #[structopt(long, short)]
+        release: bool,
+
+        /// Name of the environment
+        #[structopt()]
+        name: String,
+    },
+    /// Removes an environment
+    Remove {
+        /// Name of the environment
+        #[structopt()]
+        name: String,
+    },
+}
+
 #[derive(Debug, StructOpt)]
 struct PioInstallation {
     /// PlatformIO installation directory (default is ~/.platformio)
@@ -190,6 +394,11 @@ struct PioIniArgs {
 
 #[derive(Debug, StructOpt)]
 enum EspidfCommand {
+    /// Reads or edits the ESP-IDF sdkconfig file non-interactively
+    Config {
+        #[structopt(subcommand)]
+        cmd: ConfigCommand,
+    },
     /// Generates or updates the ESP-IDF sdkconfig file using the ESP-IDF Menuconfig interactive system
     Menuconfig {
         /// Rust target for which the sdkconfig file will be generated or updated
@@ -244,6 +453,77 @@ enum EspidfCommand {
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    /// Prints the raw value of an option, as written in the sdkconfig file
+    Get {
+        #[structopt(flatten)]
+        sdkconfig: SdkconfigArgs,
+
+        /// The option, with or without the 'CONFIG_' prefix
+        #[structopt()]
+        key: String,
+    },
+    /// Sets an option after validating the value against the type of the option
+    Set {
+        #[structopt(flatten)]
+        sdkconfig: SdkconfigArgs,
+
+        /// Kconfig file declaring the option, used to determine its type
+        ///
+        /// If not specified, the type is derived from the current value of the option in the sdkconfig file
+        #[structopt(long, parse(from_os_str))]
+        kconfig: Option<PathBuf>,
+
+        /// The option, with or without the 'CONFIG_' prefix
+        #[structopt()]
+        key: String,
+
+        /// The new value. Strings may be passed without quotes
+        #[structopt()]
+        value: String,
+    },
+    /// Removes an option, so that its default value applies
+    Unset {
+        #[structopt(flatten)]
+        sdkconfig: SdkconfigArgs,
+
+        /// The option, with or without the 'CONFIG_' prefix
+        #[structopt()]
+        key: String,
+    },
+    /// Prints the options of the sdkconfig defaults file whose value differs in the sdkconfig file,
+    /// and the options that are only set in the sdkconfig file
+    Diff {
+        #[structopt(flatten)]
+        sdkconfig: SdkconfigArgs,
+
+        /// The sdkconfig defaults file. Defaults to 'sdkconfig.defaults'
+        #[structopt(long, parse(from_os_str))]
+        defaults: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+struct SdkconfigArgs {
+    /// The sdkconfig file
+    ///
+    /// If not specified, 'sdkconfig.<environment>' will be used if an environment is selected,
+    /// otherwise 'sdkconfig'. Only 'set' creates a missing file
+    #[structopt(long, parse(from_os_str))]
+    sdkconfig: Option<PathBuf>,
+
+    /// Indicates release configuration
+    ///
+    /// Equivalent to '-e release'
+    #[structopt(long, short)]
+    release: bool,
+
+    /// PlatformIO environment whose sdkconfig file will be used
+    #[structopt(long, short = "e")]
+    environment: Option<String>,
+}
+
 fn parse_build_std(s: &str) -> cargo::BuildStd {
     match s {
         "none" => cargo::BuildStd::None,
@@ -303,38 +583,84 @@ fn main() -> Result<()> {
     .format_timestamp(None)
     .init();
 
+    let json = opt.message_format == MessageFormat::Json;
+
     match opt.cmd {
         Command::Installpio { path } => {
-            Pio::install(path, pio_log_level, false)?;
+            if let Some(path) = &path {
+                fs::create_dir_all(path)?;
+            }
+
+            pio_installer(path.clone(), pio_log_level, opt.message_format)?.update()?;
+
+            if json {
+                Message::PioInstallation(&check_pio(path, pio_log_level, opt.message_format)?)
+                    .print()?;
+            }
+
             Ok(())
         }
         Command::Checkpio { path } => {
-            Pio::get(path, pio_log_level, false)?;
+            if json {
+                Message::PioInstallation(&check_pio(path, pio_log_level, opt.message_format)?)
+                    .print()?;
+            } else {
+                get_pio(path, pio_log_level, opt.message_format)?;
+            }
+
             Ok(())
         }
+        Command::Doctor {
+            pio_install,
+            target,
+            skip_toolchain,
+            json,
+        } => {
+            let report = doctor::diagnose(
+                env::current_dir()?,
+                pio_install.pio_path,
+                target,
+                skip_toolchain,
+            );
+
+            if json || opt.message_format == MessageFormat::Json {
+                Message::DoctorReport(&report).print()?;
+            } else {
+                report.print();
+            }
+
+            match report.failures() {
+                0 => Ok(()),
+                failures => bail!("{} check(s) failed", failures),
+            }
+        }
         Command::Printscons {
             mut framework_args,
             precise,
             var,
             release,
         } => {
-            let pio = Pio::get(
+            let pio = get_pio(
                 framework_args.pio_install.pio_path.take(),
                 pio_log_level,
-                false,
+                opt.message_format,
             )?;
 
-            let scons_vars = get_framework_scons_vars(
-                &pio,
-                release,
-                !precise,
-                &framework_args.resolve(pio.clone())?,
-            )?;
+            let resolution = framework_args.resolve(pio.clone())?;
+            if json {
+                Message::Resolution(&resolution).print()?;
+            }
+
+            let scons_vars =
+                get_framework_scons_vars(&pio, release, !precise, &resolution, opt.message_format)?;
 
             if let Some(var) = var {
                 let scons_var = match &var[..] {
                     "path" => scons_vars.path,
                     "incflags" => scons_vars.incflags,
+                    "defflags" => scons_vars.defflags,
+                    "cflags" => scons_vars.cflags,
+                    "cc" => scons_vars.cc,
                     "libflags" => scons_vars.libflags,
                     "libdirflags" => scons_vars.libdirflags,
                     "libs" => scons_vars.libs,
@@ -346,7 +672,17 @@ fn main() -> Result<()> {
                     _ => panic!(),
                 };
 
-                println!("{}", scons_var);
+                if json {
+                    Message::SconsVariable {
+                        name: &var,
+                        value: &scons_var,
+                    }
+                    .print()?;
+                } else {
+                    println!("{}", scons_var);
+                }
+            } else if json {
+                Message::SconsVariables(&scons_vars).print()?;
             } else {
                 println!("{:?}", scons_vars);
             }
@@ -356,15 +692,130 @@ fn main() -> Result<()> {
         Command::Build {
             pio_install,
             release,
-        } => Pio::get(pio_install.pio_path, pio_log_level, false)?.run_with_args(if release {
-            &["-e", "release"]
-        } else {
-            &["-e", "debug"]
-        }),
+            environment,
+        } => {
+            let environment = if let Some(environment) = environment.as_deref() {
+                environment
+            } else if release {
+                "release"
+            } else {
+                "debug"
+            };
+
+            let result = run_pio(
+                &get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+                &["-e", environment],
+                opt.message_format,
+            );
+
+            if json {
+                Message::BuildFinished {
+                    environment,
+                    success: result.is_ok(),
+                }
+                .print()?;
+            }
+
+            result
+        }
+        Command::Flash {
+            pio_install,
+            port,
+            upload_protocol,
+            release,
+            environment,
+            monitor,
+            baud_rate,
+        } => run_flash(
+            get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+            env::current_dir()?,
+            port.as_deref(),
+            upload_protocol.as_deref(),
+            if environment.is_some() {
+                environment.as_deref()
+            } else if release {
+                Some("release")
+            } else {
+                None
+            },
+            monitor,
+            baud_rate,
+            opt.message_format,
+        ),
         Command::Exec {
             pio_install,
-            pio_args: args,
-        } => Pio::get(pio_install.pio_path, pio_log_level, false)?.exec_with_args(&args),
+            environment,
+            pio_args: mut args,
+        } => {
+            if let Some(environment) = environment {
+                args.extend(["-e".into(), environment.into()]);
+            }
+
+            let pio = get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?;
+            let mut cmd = pio.cmd();
+
+            exec_pio(&pio, cmd.args(&args), opt.message_format)
+        }
+        Command::Boards {
+            pio_install,
+            mcu,
+            platform,
+            framework,
+            sort,
+            query,
+        } => discovery::list_boards(
+            &get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+            query.as_deref(),
+            mcu.as_deref(),
+            platform.as_deref(),
+            framework.as_deref(),
+            &sort,
+            opt.message_format,
+        ),
+        Command::Platforms {
+            pio_install,
+            framework,
+            sort,
+            query,
+        } => discovery::list_platforms(
+            &get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+            query.as_deref(),
+            framework.as_deref(),
+            &sort,
+            opt.message_format,
+        ),
+        Command::Frameworks {
+            pio_install,
+            platform,
+            query,
+        } => discovery::list_frameworks(
+            &get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+            query.as_deref(),
+            platform.as_deref(),
+            opt.message_format,
+        ),
+        Command::Libs {
+            pio_install,
+            cmd:
+                LibsCommand::Search {
+                    framework,
+                    platform,
+                    limit,
+                    sort,
+                    query,
+                },
+        } => discovery::search_libraries(
+            &get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
+            &query,
+            framework.as_deref(),
+            platform.as_deref(),
+            limit,
+            &sort,
+            opt.message_format,
+        ),
+        Command::Env { cmd } => {
+            run_env(env::current_dir()?, cmd, pio_log_level, opt.message_format)
+        }
         cmd @ Command::New { .. } | cmd @ Command::Init { .. } | cmd @ Command::Upgrade { .. } => {
             let (cargo_cmd, mut pio_ini_args, path, args) = match cmd {
                 Command::New {
@@ -396,15 +847,22 @@ fn main() -> Result<()> {
             };
 
             let pio_path = pio_ini_args.framework_args.pio_install.pio_path.take();
+            let resolution = pio_ini_args.framework_args.resolve(get_pio(
+                pio_path,
+                pio_log_level,
+                opt.message_format,
+            )?)?;
+
+            if json {
+                Message::Resolution(&resolution).print()?;
+            }
+
             create_project(
                 path.unwrap_or(env::current_dir()?),
                 cargo_cmd,
+                pio_ini_args.build_std,
                 args.iter(),
-                &pio_ini_args.framework_args.resolve(Pio::get(
-                    pio_path,
-                    pio_log_level,
-                    false, /*download*/
-                )?)?,
+                &resolution,
             )?;
 
             Ok(())
@@ -413,6 +871,10 @@ fn main() -> Result<()> {
             update_project(path.unwrap_or(env::current_dir()?))?;
             Ok(())
         }
+        Command::Espidf {
+            cmd: EspidfCommand::Config { cmd },
+            ..
+        } => run_esp_idf_config(env::current_dir()?, cmd, opt.message_format),
         Command::Espidf {
             pio_install,
             cmd:
@@ -422,8 +884,12 @@ fn main() -> Result<()> {
                     environment,
                 },
         } => {
+            if json {
+                bail!("The interactive menuconfig does not support '--message-format json'");
+            }
+
             run_esp_idf_menuconfig(
-                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
+                get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
                 env::current_dir()?,
                 target.as_deref(),
                 if environment.is_some() {
@@ -448,8 +914,12 @@ fn main() -> Result<()> {
                     environment,
                 },
         } => {
+            if json {
+                bail!("The interactive monitor does not support '--message-format json'");
+            }
+
             run_esp_idf_monitor(
-                Pio::get(pio_install.pio_path, pio_log_level, false /*download*/)?,
+                get_pio(pio_install.pio_path, pio_log_level, opt.message_format)?,
                 env::current_dir()?,
                 &port,
                 baud_rate.unwrap_or(115200),
@@ -468,6 +938,236 @@ fn main() -> Result<()> {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
+fn run_flash(
+    mut pio: Pio,
+    project: impl AsRef<Path>,
+    port: Option<&str>,
+    upload_protocol: Option<&str>,
+    environment: Option<&str>,
+    monitor: bool,
+    baud_rate: Option<u32>,
+    message_format: MessageFormat,
+) -> Result<()> {
+    if !check_pio_first_project(&project) {
+        bail!(
+            "Cannot flash {}: flashing is only supported for PIO->Cargo projects",
+            project.as_ref().display()
+        );
+    }
+
+    let mut args = vec!["-t".to_owned(), "upload".to_owned()];
+
+    if monitor {
+        args.extend(["-t".to_owned(), "monitor".to_owned()]);
+
+        if let Some(baud_rate) = baud_rate {
+            args.push("--project-option".to_owned());
+            args.push(format!("monitor_speed={}", baud_rate));
+        }
+
+        // The monitor is useless if its output is suppressed
+        pio = pio.log_level(LogLevel::Standard);
+    }
+
+    if let Some(environment) = environment {
+        args.extend(["-e".to_owned(), environment.to_owned()]);
+    }
+
+    if let Some(port) = port {
+        args.extend(["--upload-port".to_owned(), port.to_owned()]);
+
+        if monitor {
+            args.extend(["--monitor-port".to_owned(), port.to_owned()]);
+        }
+    }
+
+    if let Some(upload_protocol) = upload_protocol {
+        args.push("--project-option".to_owned());
+        args.push(format!("upload_protocol={}", upload_protocol));
+    }
+
+    info!(
+        "Flashing environment {} to port {}",
+        environment.unwrap_or("(default)"),
+        port.unwrap_or("(auto-detected)")
+    );
+
+    call_in_dir(project, move || run_pio(&pio, &args, message_format))
+}
+
+/// Run `pio run` with `args` and fail if PlatformIO does, unlike `Pio::run_with_args`.
+fn run_pio(pio: &Pio, args: &[impl AsRef<OsStr>], message_format: MessageFormat) -> Result<()> {
+    let mut cmd = pio.run_cmd();
+
+    exec_pio(pio, cmd.args(args), message_format)
+}
+
+/// Run the PlatformIO command `cmd` and fail if PlatformIO does, unlike `Pio::exec`.
+///
+/// With `--message-format json` the output of PlatformIO goes to stderr, so that stdout
+/// only contains JSON messages.
+fn exec_pio(pio: &Pio, cmd: &mut process::Command, message_format: MessageFormat) -> Result<()> {
+    debug!("Running PlatformIO command: {:?}", cmd);
+
+    let status = if pio.log_level == LogLevel::Quiet {
+        cmd.stdout(Stdio::null()).stderr(Stdio::null()).status()
+    } else if message_format == MessageFormat::Json {
+        cmd.stdout(Stdio::piped()).spawn().and_then(|mut child| {
+            if let Some(mut stdout) = child.stdout.take() {
+                io::copy(&mut stdout, &mut io::stderr())?;
+            }
+
+            child.wait()
+        })
+    } else {
+        cmd.status()
+    }
+    .map_err(|e| utils::CmdError::no_run(cmd, e))?;
+
+    utils::CmdError::status_into_result(status, cmd, || None)?;
+
+    Ok(())
+}
+
+fn run_env(
+    project: impl AsRef<Path>,
+    cmd: EnvCommand,
+    pio_log_level: LogLevel,
+    message_format: MessageFormat,
+) -> Result<()> {
+    if !check_pio_first_project(&project) {
+        bail!(
+            "Cannot manage the environments of {}: no platformio.ini found",
+            project.as_ref().display()
+        );
+    }
+
+    let platformio_ini_path = project.as_ref().join("platformio.ini");
+    let mut platformio_ini = ini::PlatformioIni::try_from_file(&platformio_ini_path)?;
+
+    match cmd {
+        EnvCommand::List => {
+            let default_envs = platformio_ini.default_environments();
+
+            for env in platformio_ini.environments() {
+                let option = |name| platformio_ini.get_env_option(&env, name);
+                let (board, platform, framework, rust_target) = (
+                    option("board"),
+                    option("platform"),
+                    option("framework"),
+                    option("rust_target"),
+                );
+                let default = default_envs.contains(&env);
+
+                if message_format == MessageFormat::Json {
+                    Message::Environment {
+                        name: &env,
+                        default,
+                        board: board.as_deref(),
+                        platform: platform.as_deref(),
+                        framework: framework.as_deref(),
+                        rust_target: rust_target.as_deref(),
+                    }
+                    .print()?;
+                } else {
+                    println!(
+                        "{}{}: board {}, platform {}, framework {}, rust target {}",
+                        env,
+                        if default { " (default)" } else { "" },
+                        board.as_deref().unwrap_or("-"),
+                        platform.as_deref().unwrap_or("-"),
+                        framework.as_deref().unwrap_or("-"),
+                        rust_target.as_deref().unwrap_or("-"),
+                    );
+                }
+            }
+        }
+        EnvCommand::Add {
+            mut framework_args,
+            release,
+            name,
+        } => {
+            let pio = get_pio(
+                framework_args.pio_install.pio_path.take(),
+                pio_log_level,
+                message_format,
+            )?;
+            let resolution = framework_args.resolve(pio)?;
+            if message_format == MessageFormat::Json {
+                Message::Resolution(&resolution).print()?;
+            }
+
+            // Only PIO->Cargo projects build a Rust library
+            let options = environment_options(
+                &resolution,
+                release,
+                platformio_ini.get("env", "rust_lib").is_some(),
+            );
+
+            platformio_ini.add_environment(&name, &options)?;
+            platformio_ini.write_to_file(&platformio_ini_path)?;
+
+            info!(
+                "Added environment {} for board {} to {}",
+                name,
+                resolution.board,
+                platformio_ini_path.display()
+            );
+        }
+        EnvCommand::Remove { name } => {
+            if !platformio_ini.remove_environment(&name) {
+                bail!(
+                    "Environment {} does not exist in {}",
+                    name,
+                    platformio_ini_path.display()
+                );
+            }
+
+            platformio_ini.write_to_file(&platformio_ini_path)?;
+
+            info!(
+                "Removed environment {} from {}",
+                name,
+                platformio_ini_path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the options of a new `[env:<name>]` section of `platformio.ini` for
+/// `resolution`, with a `rust_target` if the project builds a `rust_lib`.
+fn environment_options(
+    resolution: &Resolution,
+    release: bool,
+    rust_lib: bool,
+) -> Vec<(&'static str, String)> {
+    let mut options = vec![
+        ("board", resolution.board.clone()),
+        ("platform", resolution.platform.clone()),
+        ("framework", resolution.frameworks.join(", ")),
+        (
+            "build_type",
+            if release { "release" } else { "debug" }.to_owned(),
+        ),
+    ];
+
+    if rust_lib {
+        if resolution.target.is_empty() {
+            warn!(
+                "Cannot derive a Rust target for board {}, please use the --target parameter",
+                resolution.board
+            );
+        } else {
+            options.push(("rust_target", resolution.target.clone()));
+        }
+    }
+
+    options
+}
+
 fn run_esp_idf_menuconfig<'a>(
     pio: Pio,
     project: impl AsRef<Path>,
@@ -530,6 +1230,207 @@ fn run_esp_idf_menuconfig<'a>(
     }
 }
 
+fn run_esp_idf_config(
+    project: impl AsRef<Path>,
+    cmd: ConfigCommand,
+    message_format: MessageFormat,
+) -> Result<()> {
+    let project = project.as_ref();
+
+    match cmd {
+        ConfigCommand::Get { sdkconfig, key } => {
+            let path = sdkconfig.existing_path(project)?;
+            let config = kconfig::ConfigFile::try_from_file(&path)?;
+
+            match config.get(&key) {
+                Some(value) if message_format == MessageFormat::Json => {
+                    let name = key.strip_prefix("CONFIG_").unwrap_or(&key);
+
+                    Message::SdkconfigOption {
+                        key: &format!("CONFIG_{}", name),
+                        value,
+                    }
+                    .print()?;
+                }
+                Some(value) => println!("{}", value),
+                None => bail!("{} is not set in {}", key, path.display()),
+            }
+        }
+        ConfigCommand::Set {
+            sdkconfig,
+            kconfig,
+            key,
+            value,
+        } => {
+            let path = sdkconfig.path(project);
+            let mut config = if path.exists() {
+                kconfig::ConfigFile::try_from_file(&path)?
+            } else {
+                info!("Creating {}", path.display());
+
+                Default::default()
+            };
+
+            let value = sdkconfig_symbol_type(kconfig.as_deref(), &config, &key)?
+                .to_config_value(&value)?;
+
+            config.set(&key, &value);
+            config.write_to_file(&path)?;
+
+            info!("Set {} to {} in {}", key, value, path.display());
+        }
+        ConfigCommand::Unset { sdkconfig, key } => {
+            let path = sdkconfig.existing_path(project)?;
+            let mut config = kconfig::ConfigFile::try_from_file(&path)?;
+
+            if config.unset(&key) {
+                config.write_to_file(&path)?;
+
+                info!("Unset {} in {}", key, path.display());
+            } else {
+                warn!("{} is not set in {}", key, path.display());
+            }
+        }
+        ConfigCommand::Diff {
+            sdkconfig,
+            defaults,
+        } => {
+            let path = sdkconfig.existing_path(project)?;
+            let defaults_path = defaults.unwrap_or_else(|| project.join("sdkconfig.defaults"));
+
+            let config = kconfig::ConfigFile::try_from_file(&path)?;
+            let defaults = kconfig::ConfigFile::try_from_file(&defaults_path)?;
+
+            let diff = sdkconfig_diff(&config, &defaults);
+
+            if message_format == MessageFormat::Json {
+                for change in diff {
+                    Message::SdkconfigChange {
+                        key: change.key,
+                        default: change.default,
+                        value: change.value,
+                    }
+                    .print()?;
+                }
+            } else if !diff.is_empty() {
+                println!("--- {}", defaults_path.display());
+                println!("+++ {}", path.display());
+
+                for change in diff {
+                    if let Some(default) = change.default {
+                        println!("-{}={}", change.key, default);
+                    }
+                    if let Some(value) = change.value {
+                        println!("+{}={}", change.key, value);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl SdkconfigArgs {
+    fn path(&self, project: &Path) -> PathBuf {
+        if let Some(sdkconfig) = &self.sdkconfig {
+            return sdkconfig.clone();
+        }
+
+        let environment = if self.environment.is_some() {
+            self.environment.as_deref()
+        } else if self.release {
+            Some("release")
+        } else {
+            None
+        };
+
+        // The sdkconfig of another environment must never be used instead
+        environment
+            .map(|environment| project.join(format!("sdkconfig.{}", environment)))
+            .unwrap_or_else(|| project.join("sdkconfig"))
+    }
+
+    /// Get the path of the sdkconfig file, which must exist.
+    fn existing_path(&self, project: &Path) -> Result<PathBuf> {
+        let path = self.path(project);
+        if !path.is_file() {
+            bail!(
+                "{} does not exist, build the project or use 'config set' to create it",
+                path.display()
+            );
+        }
+
+        Ok(path)
+    }
+}
+
+/// Get the options of `defaults` whose value differs in `config` (`-` lines for the
+/// default and `+` lines for the value), followed by the options only set in `config`.
+/// An option whose value in the sdkconfig file differs from the defaults file, where
+/// `None` means that the option is not set in that file.
+#[derive(Debug, PartialEq, Eq)]
+struct SdkconfigChange<'a> {
+    key: &'a str,
+    default: Option<&'a str>,
+    value: Option<&'a str>,
+}
+
+fn sdkconfig_diff<'a>(
+    config: &'a kconfig::ConfigFile,
+    defaults: &'a kconfig::ConfigFile,
+) -> Vec<SdkconfigChange<'a>> {
+    let mut diff = Vec::new();
+    for (key, default) in defaults.options() {
+        let value = config.get(key);
+
+        if value != Some(default) {
+            diff.push(SdkconfigChange {
+                key,
+                default: Some(default),
+                value,
+            });
+        }
+    }
+
+    for (key, value) in config.options() {
+        if defaults.get(key).is_none() {
+            diff.push(SdkconfigChange {
+                key,
+                default: None,
+                value: Some(value),
+            });
+        }
+    }
+
+    diff
+}
+
+fn sdkconfig_symbol_type(
+    kconfig: Option<&Path>,
+    config: &kconfig::ConfigFile,
+    key: &str,
+) -> Result<kconfig::SymbolType> {
+    if let Some(kconfig) = kconfig {
+        let name = key.strip_prefix("CONFIG_").unwrap_or(key);
+
+        match kconfig::try_symbols_from_kconfig_file(kconfig)?
+            .into_iter()
+            .find(|symbol| symbol.name == name)
+        {
+            Some(symbol) => Ok(symbol.ty),
+            None => bail!("{} is not declared in {}", name, kconfig.display()),
+        }
+    } else if let Some(value) = config.get(key) {
+        Ok(kconfig::SymbolType::of_value(value))
+    } else {
+        bail!(
+            "{} is not set in the sdkconfig file, so its type is unknown. Please use the --kconfig parameter to specify the Kconfig file declaring it",
+            key
+        );
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn run_esp_idf_monitor<'a>(
     mut pio: Pio,
@@ -646,6 +1547,49 @@ fn resolve_esp_idf_target(pio: Pio, target: impl AsRef<str>) -> Result<Resolutio
         .resolve(true)
 }
 
+/// Get the PlatformIO installation at `pio_path` or the default one, like `Pio::get`.
+fn get_pio(
+    pio_path: Option<PathBuf>,
+    log_level: LogLevel,
+    message_format: MessageFormat,
+) -> Result<Pio> {
+    let info = check_pio(pio_path, log_level, message_format)?;
+
+    Ok(Pio::from(info).log_level(log_level))
+}
+
+fn check_pio(
+    pio_path: Option<PathBuf>,
+    log_level: LogLevel,
+    message_format: MessageFormat,
+) -> Result<PioInstallerInfo> {
+    pio_installer(pio_path, log_level, message_format)?.check()
+}
+
+/// With `--message-format json` the output of the installer goes to stderr, so that
+/// stdout only contains JSON messages.
+fn pio_installer(
+    pio_path: Option<PathBuf>,
+    log_level: LogLevel,
+    message_format: MessageFormat,
+) -> Result<PioInstaller> {
+    let mut pio_installer = PioInstaller::new()?;
+
+    if log_level == LogLevel::Quiet {
+        pio_installer.silent();
+    }
+
+    if message_format == MessageFormat::Json {
+        pio_installer.stdout_to_stderr();
+    }
+
+    if let Some(pio_path) = pio_path {
+        pio_installer.pio(pio_path);
+    }
+
+    Ok(pio_installer)
+}
+
 fn check_pio_first_project(project: impl AsRef<Path>) -> bool {
     let project = project.as_ref();
 
@@ -701,6 +1645,7 @@ fn get_framework_scons_vars(
     release: bool,
     quick: bool,
     resolution: &Resolution,
+    message_format: MessageFormat,
 ) -> Result<project::SconsVariables> {
     let temp_dir = TempDir::new()?;
     let project_path = temp_dir.path().join("proj");
@@ -717,7 +1662,13 @@ fn get_framework_scons_vars(
 
     builder.generate(resolution)?;
 
-    pio.build(&project_path, release)?;
+    let mut cmd = pio.run_cmd();
+    cmd.arg("-d")
+        .arg(&project_path)
+        .arg("-e")
+        .arg(if release { "release" } else { "debug" });
+
+    exec_pio(pio, &mut cmd, message_format)?;
 
     project::SconsVariables::from_dump(project_path)
 }
@@ -725,6 +1676,7 @@ fn get_framework_scons_vars(
 fn create_project<I, S>(
     project_path: impl AsRef<Path>,
     cargo_cmd: CargoCmd,
+    build_std: cargo::BuildStd,
     cargo_args: I,
     resolution: &Resolution,
 ) -> Result<PathBuf>
@@ -738,6 +1690,7 @@ where
         .enable_git_repos()
         .enable_platform_packages_patches()
         .enable_cargo(cargo_cmd)
+        .build_std(build_std)
         .cargo_options(cargo_args)
         .generate(resolution)
 }
@@ -745,3 +1698,95 @@ where
 fn update_project(project_path: impl AsRef<Path>) -> Result<PathBuf> {
     project::Builder::new(project_path).update()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdkconfig() {
+        let project = Path::new("project");
+        let args = |environment: Option<&str>, release| SdkconfigArgs {
+            sdkconfig: None,
+            release,
+            environment: environment.map(Into::into),
+        };
+
+        assert_eq!(args(None, false).path(project), project.join("sdkconfig"));
+        assert_eq!(
+            args(None, true).path(project),
+            project.join("sdkconfig.release")
+        );
+        assert_eq!(
+            args(Some("c3"), true).path(project),
+            project.join("sdkconfig.c3")
+        );
+        assert!(args(Some("missing"), false).existing_path(project).is_err());
+
+        let defaults = kconfig::ConfigFile::parse(
+            "CONFIG_FREERTOS_HZ=1000\nCONFIG_SPIRAM=y\nCONFIG_LOG_TAG=\"app\"\n",
+        );
+        let config = kconfig::ConfigFile::parse(
+            "CONFIG_FREERTOS_HZ=100\n# CONFIG_SPIRAM is not set\nCONFIG_LOG_TAG=\"app\"\nCONFIG_PARTITION_TABLE_SINGLE_APP=y\n",
+        );
+
+        let change = |key, default, value| SdkconfigChange {
+            key,
+            default,
+            value,
+        };
+        assert_eq!(
+            sdkconfig_diff(&config, &defaults),
+            [
+                change("CONFIG_FREERTOS_HZ", Some("1000"), Some("100")),
+                change("CONFIG_SPIRAM", Some("y"), Some("n")),
+                change("CONFIG_PARTITION_TABLE_SINGLE_APP", None, Some("y")),
+            ]
+        );
+        assert!(sdkconfig_diff(&defaults, &defaults).is_empty());
+    }
+
+    #[test]
+    fn env_options() {
+        let resolution = Resolution {
+            board: "esp32-c3-devkitm-1".into(),
+            mcu: "ESP32C3".into(),
+            platform: "espressif32".into(),
+            frameworks: vec!["espidf".into(), "arduino".into()],
+            target: "riscv32imc-esp-espidf".into(),
+        };
+
+        let options = environment_options(&resolution, true, true);
+        assert_eq!(
+            options,
+            [
+                ("board", "esp32-c3-devkitm-1".to_owned()),
+                ("platform", "espressif32".to_owned()),
+                ("framework", "espidf, arduino".to_owned()),
+                ("build_type", "release".to_owned()),
+                ("rust_target", "riscv32imc-esp-espidf".to_owned()),
+            ]
+        );
+
+        let mut platformio_ini = ini::PlatformioIni::parse("[env]\nrust_lib = app\n");
+        platformio_ini
+            .add_environment("c3", &environment_options(&resolution, false, false))
+            .unwrap();
+        assert_eq!(platformio_ini.environments(), ["c3"]);
+        assert_eq!(
+            platformio_ini.get_env_option("c3", "build_type").as_deref(),
+            Some("debug")
+        );
+        assert_eq!(platformio_ini.get_env_option("c3", "rust_target"), None);
+        assert_eq!(
+            platformio_ini.get_env_option("c3", "rust_lib").as_deref(),
+            Some("app")
+        );
+
+        let resolution = Resolution {
+            target: String::new(),
+            ..resolution
+        };
+        assert_eq!(environment_options(&resolution, false, true).len(), 4);
+    }
+}
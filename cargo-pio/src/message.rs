@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use embuild::pio::project::SconsVariables;
+use embuild::pio::{Board, Framework, Library, PioInstallerInfo, Platform, Resolution};
+use serde::Serialize;
+
+use crate::doctor::Report;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "human" => Self::Human,
+            "json" => Self::Json,
+            _ => bail!("Unknown message format '{}'", s),
+        })
+    }
+}
+
+/// A message printed to stdout with `--message-format json`.
+///
+/// Every message is a JSON object on its own line, whose `reason` field identifies its
+/// kind. The output of PlatformIO goes to stderr, so stdout only contains messages.
+/// The schema is documented in the README; new kinds of messages and new fields may be
+/// added, but existing fields are neither removed nor changed.
+#[derive(Serialize, Debug)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    PioInstallation(&'a PioInstallerInfo),
+    Resolution(&'a Resolution),
+    SconsVariables(&'a SconsVariables),
+    SconsVariable {
+        name: &'a str,
+        value: &'a str,
+    },
+    BuildFinished {
+        environment: &'a str,
+        success: bool,
+    },
+    DoctorReport(&'a Report),
+    SdkconfigOption {
+        key: &'a str,
+        value: &'a str,
+    },
+    SdkconfigChange {
+        key: &'a str,
+        default: Option<&'a str>,
+        value: Option<&'a str>,
+    },
+    Environment {
+        name: &'a str,
+        default: bool,
+        board: Option<&'a str>,
+        platform: Option<&'a str>,
+        framework: Option<&'a str>,
+        rust_target: Option<&'a str>,
+    },
+    Board {
+        #[serde(flatten)]
+        board: &'a Board,
+        target: Option<&'a str>,
+    },
+    Platform {
+        #[serde(flatten)]
+        platform: &'a Platform,
+        targets: &'a [&'a str],
+    },
+    Framework {
+        #[serde(flatten)]
+        framework: &'a Framework,
+        targets: &'a [&'a str],
+    },
+    Library {
+        #[serde(flatten)]
+        library: &'a Library,
+        targets: &'a [&'a str],
+    },
+}
+
+impl Message<'_> {
+    pub fn print(&self) -> Result<()> {
+        println!("{}", serde_json::to_string(self)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment() {
+        let message = Message::Environment {
+            name: "debug",
+            default: true,
+            board: Some("esp32dev"),
+            platform: Some("espressif32"),
+            framework: Some("espidf"),
+            rust_target: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"reason":"environment","name":"debug","default":true,"board":"esp32dev","platform":"espressif32","framework":"espidf","rust_target":null}"#
+        );
+    }
+}
@@ -0,0 +1,498 @@
+//! A cache of link results keyed by the hash of all link inputs.
+//!
+//! The key of a link is computed from the linker, the normalized linker arguments and the
+//! contents of all objects, archives and linker scripts they reference. Paths of inputs
+//! are replaced by their contents in the key, so that links of identical files in
+//! different (temporary) locations share an entry. Every entry stores the link outputs
+//! (the output file and the map file, if any), entries are evicted in least recently
+//! used order once the total size of the cache exceeds its maximum size.
+//!
+//! A link whose libraries or linker scripts cannot be found is not cacheable, as a
+//! change of them could not be detected.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::build::LinkerFlavor;
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::paths::{for_each_path, linker_scripts, PathKind, SearchPaths};
+
+/// The environment variable that enables the cache, its value is the cache directory.
+pub const CACHE_VAR: &str = "LDPROXY_CACHE";
+
+/// The default maximum size of the cache (1 GiB).
+pub const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// Changes whenever the computation of the key changes.
+const KEY_VERSION: &str = "ldproxy-cache-v2";
+
+const STATS_FILE: &str = "stats.json";
+const STATS_LOCK_FILE: &str = "stats.lock";
+const LAST_USED_FILE: &str = "last-used";
+
+/// How long to wait for other ldproxy instances to update the statistics.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Locks older than this were left behind by a killed ldproxy instance.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkCache {
+    dir: PathBuf,
+    max_size: u64,
+    key: String,
+}
+
+impl LinkCache {
+    /// Create the cache in `dir` for the invocation of `linker` with `args`.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        max_size: u64,
+        linker: &str,
+        search: &SearchPaths,
+        args: &[String],
+    ) -> Result<Self> {
+        Ok(Self {
+            dir: dir.into(),
+            max_size,
+            key: key(linker, search, args)?,
+        })
+    }
+
+    fn entry_dir(&self) -> PathBuf {
+        self.dir.join(&self.key)
+    }
+
+    /// Restore the link `outputs` from the cache, return whether the cache contained
+    /// them.
+    pub fn restore(&self, outputs: &[PathBuf]) -> Result<bool> {
+        let entry = self.entry_dir();
+        let hit =
+            entry.is_dir() && (0..outputs.len()).all(|i| entry.join(output_file_name(i)).is_file());
+
+        if hit {
+            for (i, output) in outputs.iter().enumerate() {
+                fs::copy(entry.join(output_file_name(i)), output).with_context(|| {
+                    anyhow!("Could not restore '{}' from link cache", output.display())
+                })?;
+            }
+            touch(&entry)?;
+        }
+
+        Ok(hit)
+    }
+
+    /// Store the link `outputs` in the cache and evict old entries if the cache is too
+    /// big.
+    pub fn store(&self, outputs: &[PathBuf]) -> Result<()> {
+        let entry = self.entry_dir();
+        let tmp = self
+            .dir
+            .join(format!("tmp-{}-{}", self.key, std::process::id()));
+
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+
+        for (i, output) in outputs.iter().enumerate() {
+            fs::copy(output, tmp.join(output_file_name(i)))
+                .with_context(|| anyhow!("Could not store '{}' in link cache", output.display()))?;
+        }
+        touch(&tmp)?;
+
+        if entry.exists() {
+            fs::remove_dir_all(&entry)?;
+        }
+        // Another ldproxy instance could have stored the same entry in the meantime.
+        if fs::rename(&tmp, &entry).is_err() {
+            fs::remove_dir_all(&tmp)?;
+        }
+
+        debug!("Stored link outputs in cache entry {}", entry.display());
+
+        self.evict()
+    }
+
+    /// Remove the least recently used entries until the cache is smaller than its
+    /// maximum size.
+    fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_entry = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.len() == 64 && n.chars().all(|c| c.is_ascii_hexdigit()))
+                .unwrap_or(false);
+
+            if is_entry && path.is_dir() {
+                let last_used = fs::metadata(path.join(LAST_USED_FILE))
+                    .and_then(|m| m.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+
+                entries.push((last_used, dir_size(&path)?, path));
+            }
+        }
+
+        // Most recently used first.
+        entries.sort_by_key(|(last_used, ..)| std::cmp::Reverse(*last_used));
+
+        let mut size = 0;
+        for (_, entry_size, path) in entries {
+            size += entry_size;
+
+            if size > self.max_size && path != self.entry_dir() {
+                debug!("Evicting link cache entry {}", path.display());
+                fs::remove_dir_all(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record a cache hit or miss in the statistics and return them.
+    ///
+    /// The statistics are locked while they are updated, as links run in parallel.
+    pub fn record(&self, hit: bool) -> Result<Stats> {
+        fs::create_dir_all(&self.dir)?;
+        let _lock = FileLock::acquire(self.dir.join(STATS_LOCK_FILE))?;
+
+        let stats_file = self.dir.join(STATS_FILE);
+
+        let mut stats = fs::read(&stats_file)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Stats>(&data).ok())
+            .unwrap_or_default();
+
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+
+        fs::write(&stats_file, serde_json::to_vec(&stats)?)?;
+
+        Ok(stats)
+    }
+}
+
+/// A lock held by creating a file, which is removed when the lock is dropped.
+struct FileLock(PathBuf);
+
+impl FileLock {
+    /// Create the lock file `path`, waiting for other ldproxy instances to remove it.
+    fn acquire(path: PathBuf) -> Result<Self> {
+        let start = Instant::now();
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+
+                    if matches!(age, Some(age) if age > STALE_LOCK_AGE) {
+                        debug!("Removing stale lock {}", path.display());
+                        let _ = fs::remove_file(&path);
+                    } else if start.elapsed() > LOCK_TIMEOUT {
+                        bail!("Timed out waiting for lock '{}'", path.display());
+                    } else {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Could not lock '{}'", path.display()))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn output_file_name(index: usize) -> String {
+    format!("output-{}", index)
+}
+
+/// Mark the cache entry `dir` as used now.
+fn touch(dir: &Path) -> Result<()> {
+    fs::write(dir.join(LAST_USED_FILE), [])?;
+    Ok(())
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        size += entry?.metadata()?.len();
+    }
+
+    Ok(size)
+}
+
+/// Compute the cache key of the invocation of `linker` with `args`.
+fn key(linker: &str, search: &SearchPaths, args: &[String]) -> Result<String> {
+    let mut inputs = Vec::new();
+    let mut error = None;
+
+    let toolchain_args = args
+        .iter()
+        .filter(|arg| arg.starts_with("-m") || arg.starts_with("--sysroot"))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut args = args.to_vec();
+    for_each_path(search.flavor, &mut args, &mut |kind, value| {
+        let path = search.cwd.join(value);
+
+        match kind {
+            PathKind::Output => Some("<output>".to_owned()),
+            PathKind::Map => Some("<map>".to_owned()),
+            PathKind::Input | PathKind::Script if path.is_file() => {
+                inputs.push(path);
+                Some("<input>".to_owned())
+            }
+            PathKind::Script => {
+                match search.find_in_lib_dirs(value) {
+                    Some(script) => inputs.push(script),
+                    None => {
+                        error.get_or_insert(anyhow!("Linker script '{}' was not found", value));
+                    }
+                }
+                None
+            }
+            PathKind::Lib => {
+                let lib = search
+                    .find_lib(value)
+                    .or_else(|| find_toolchain_lib(linker, search, &toolchain_args, value));
+                match lib {
+                    Some(lib) => inputs.push(lib),
+                    None => {
+                        error.get_or_insert(anyhow!("Library '{}' was not found", value));
+                    }
+                }
+                None
+            }
+            PathKind::LibDir if path.is_dir() => {
+                match linker_scripts(&path) {
+                    Ok(scripts) => inputs.extend(scripts),
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
+                Some("<dir>".to_owned())
+            }
+            PathKind::LibDir | PathKind::Input => None,
+        }
+    });
+
+    if let Some(err) = error {
+        return Err(err);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut update = |data: &[u8]| {
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(data);
+    };
+
+    update(KEY_VERSION.as_bytes());
+    update(search.flavor.to_string().as_bytes());
+    update(linker.as_bytes());
+
+    // A changed linker changes the output.
+    if let Ok(modified) = fs::metadata(linker).and_then(|m| m.modified()) {
+        let since_epoch = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        update(&since_epoch.as_nanos().to_le_bytes());
+    }
+
+    for arg in &args {
+        update(arg.as_bytes());
+    }
+
+    for input in inputs {
+        let mut file = File::open(&input)
+            .with_context(|| anyhow!("Could not read link input '{}'", input.display()))?;
+
+        hasher.update(file.metadata()?.len().to_le_bytes());
+        io::copy(&mut file, &mut hasher)?;
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Find the library `lib` in the search directories of the toolchain, which are only
+/// known to the gcc driver.
+///
+/// `toolchain_args` are passed to the driver, as they can select a different multilib
+/// directory.
+fn find_toolchain_lib(
+    linker: &str,
+    search: &SearchPaths,
+    toolchain_args: &[String],
+    lib: &str,
+) -> Option<PathBuf> {
+    if search.flavor != LinkerFlavor::Gcc {
+        return None;
+    }
+
+    let files = match lib.strip_prefix(':') {
+        Some(file) => vec![file.to_owned()],
+        None => vec![format!("lib{}.a", lib), format!("lib{}.so", lib)],
+    };
+
+    files.into_iter().find_map(|file| {
+        let output = Command::new(linker)
+            .current_dir(&search.cwd)
+            .args(toolchain_args)
+            .arg(format!("-print-file-name={}", file))
+            .output()
+            .ok()?;
+
+        // The driver prints the file name itself if the file was not found.
+        let path = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+        Some(path).filter(|path| path.is_absolute() && path.is_file())
+    })
+}
+
+/// Parse a size in bytes with an optional `K`, `M` or `G` suffix (powers of 1024).
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, shift) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 30),
+        _ => (size, 0),
+    };
+
+    match number.trim().parse::<u64>() {
+        Ok(number) => number
+            .checked_mul(1 << shift)
+            .ok_or_else(|| anyhow!("Cache size '{}' is too large", size)),
+        Err(_) => bail!("Invalid cache size '{}'", size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn key_changes() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.o"), "main").unwrap();
+        fs::write(dir.join("lib/libfoo.a"), "foo").unwrap();
+        fs::write(dir.join("lib/memory.x"), "MEMORY {}").unwrap();
+
+        let args = ["main.o", "-Llib", "-lfoo", "-Tmemory.x", "-o", "app.elf"].map(str::to_owned);
+        let key = |args: &[String]| {
+            let search = SearchPaths::new(LinkerFlavor::Ld, dir.to_str(), args).unwrap();
+            key("ld", &search, args).unwrap()
+        };
+
+        let original = key(&args);
+        assert_eq!(key(&args), original);
+
+        // Output paths are not part of the key.
+        let mut other_output = args.clone();
+        other_output[5] = "other.elf".to_owned();
+        assert_eq!(key(&other_output), original);
+
+        fs::write(dir.join("main.o"), "changed").unwrap();
+        let changed_object = key(&args);
+        assert_ne!(changed_object, original);
+
+        fs::write(dir.join("lib/memory.x"), "MEMORY { changed }").unwrap();
+        assert_ne!(key(&args), changed_object);
+
+        let mut missing_lib = args.clone();
+        missing_lib[2] = "-lmissing".to_owned();
+        let search = SearchPaths::new(LinkerFlavor::Ld, dir.to_str(), &missing_lib).unwrap();
+        assert!(super::key("ld", &search, &missing_lib).is_err());
+    }
+
+    #[test]
+    fn restore_and_evict() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let output = dir.join("app.elf");
+        let cache = |key: char| LinkCache {
+            dir: dir.join("cache"),
+            max_size: 25,
+            key: key.to_string().repeat(64),
+        };
+        let store = |key: char, content: &str| {
+            fs::write(&output, content).unwrap();
+            cache(key).store(std::slice::from_ref(&output)).unwrap();
+            // Make sure the entries have different last used times.
+            thread::sleep(Duration::from_millis(20));
+        };
+        let restore = |key: char| {
+            let _ = fs::remove_file(&output);
+            let hit = cache(key).restore(std::slice::from_ref(&output)).unwrap();
+            thread::sleep(Duration::from_millis(20));
+
+            hit.then(|| fs::read_to_string(&output).unwrap())
+        };
+
+        assert!(!cache('a').restore(std::slice::from_ref(&output)).unwrap());
+
+        store('a', "aaaaaaaaaa");
+        store('b', "bbbbbbbbbb");
+        assert_eq!(restore('a').as_deref(), Some("aaaaaaaaaa"));
+
+        // `b` is the least recently used entry.
+        store('c', "cccccccccc");
+        assert_eq!(restore('b'), None);
+        assert_eq!(restore('a').as_deref(), Some("aaaaaaaaaa"));
+        assert_eq!(restore('c').as_deref(), Some("cccccccccc"));
+
+        assert_eq!(
+            cache('a').record(true).unwrap(),
+            Stats { hits: 1, misses: 0 }
+        );
+        assert_eq!(
+            cache('b').record(false).unwrap(),
+            Stats { hits: 1, misses: 1 }
+        );
+        assert!(!dir.join("cache").join(STATS_LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("2K").unwrap(), 2048);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert!(parse_size("1T").is_err());
+        assert_eq!(parse_size("16777215G").unwrap(), 16777215 << 30);
+        assert!(parse_size("20000000000G").is_err());
+        assert!(parse_size("18446744073709551615K").is_err());
+    }
+}
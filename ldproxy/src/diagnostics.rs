@@ -0,0 +1,453 @@
+//! Typed diagnostics parsed from the linker output.
+//!
+//! The GNU ld, lld and msvc (link.exe, lld-link) message formats are recognized. The
+//! diagnostics can be emitted as JSON messages in the format of rustc's
+//! `--error-format=json`, which cargo and IDEs understand.
+
+use std::fmt::{self, Display};
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// The kind of a linker diagnostic.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum DiagnosticKind {
+    UndefinedReference { symbol: String },
+    MultipleDefinition { symbol: String },
+    RegionOverflow { region: String, bytes: u64 },
+    MissingLibrary { library: String },
+}
+
+impl DiagnosticKind {
+    /// The code of this kind in JSON messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UndefinedReference { .. } => "undefined-reference",
+            Self::MultipleDefinition { .. } => "multiple-definition",
+            Self::RegionOverflow { .. } => "region-overflow",
+            Self::MissingLibrary { .. } => "missing-library",
+        }
+    }
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndefinedReference { symbol } => write!(f, "undefined reference to `{}`", symbol),
+            Self::MultipleDefinition { symbol } => write!(f, "multiple definition of `{}`", symbol),
+            Self::RegionOverflow { region, bytes } => {
+                write!(f, "region `{}` overflowed by {} bytes", region, bytes)
+            }
+            Self::MissingLibrary { library } => write!(f, "cannot find library `{}`", library),
+        }
+    }
+}
+
+/// A file (and line) the linker refers to in a diagnostic.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub file: String,
+    /// The source line, only known if the inputs have debug information.
+    pub line: Option<u32>,
+}
+
+impl Location {
+    /// Parse a location like `main.c:12`, `main.o:(.text+0x12)` or
+    /// `main.c:12 (/src/main.c:12)`.
+    fn parse(location: &str) -> Option<Self> {
+        // GNU ld prefixes the location with the path of the linker.
+        let location = match location.rsplit_once(": ") {
+            Some((_, location)) => location,
+            None => location,
+        }
+        .trim();
+        // lld appends the absolute path in parentheses.
+        let location = match location.split_once(" (") {
+            Some((location, _)) if !location.is_empty() => location,
+            _ => location,
+        };
+
+        if let Some((file, line)) = location.rsplit_once(':') {
+            if let Ok(line) = line.parse() {
+                return Some(Self {
+                    file: file.to_owned(),
+                    line: Some(line),
+                });
+            }
+        }
+
+        let file = match location.find(":(") {
+            Some(i) => &location[..i],
+            None => location,
+        };
+
+        (!file.is_empty()).then(|| Self {
+            file: file.to_owned(),
+            line: None,
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}", self.file, line),
+            None => write!(f, "{}", self.file),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    #[serde(flatten)]
+    pub kind: DiagnosticKind,
+    /// All locations the diagnostic refers to, e.g. every reference to an undefined
+    /// symbol.
+    pub locations: Vec<Location>,
+    /// The linker output this diagnostic was parsed from.
+    pub rendered: String,
+}
+
+impl Diagnostic {
+    fn new(kind: DiagnosticKind, line: &str) -> Self {
+        Self {
+            kind,
+            locations: Vec::new(),
+            rendered: line.to_owned(),
+        }
+    }
+
+    /// Convert this diagnostic to a JSON message in the format of rustc's
+    /// `--error-format=json`.
+    pub fn to_json(&self) -> Value {
+        let spans = self
+            .locations
+            .iter()
+            .filter_map(|location| {
+                let line = location.line?;
+
+                Some(json!({
+                    "file_name": location.file,
+                    "byte_start": 0,
+                    "byte_end": 0,
+                    "line_start": line,
+                    "line_end": line,
+                    "column_start": 1,
+                    "column_end": 1,
+                    "is_primary": true,
+                    "text": [],
+                    "label": null,
+                    "suggested_replacement": null,
+                    "suggestion_applicability": null,
+                    "expansion": null,
+                }))
+            })
+            .collect::<Vec<_>>();
+
+        let children = self
+            .locations
+            .iter()
+            .filter(|location| location.line.is_none())
+            .map(|location| {
+                json!({
+                    "message": format!("referenced in {}", location),
+                    "code": null,
+                    "level": "note",
+                    "spans": [],
+                    "children": [],
+                    "rendered": null,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "$message_type": "diagnostic",
+            "message": self.kind.to_string(),
+            "code": {
+                "code": self.kind.code(),
+                "explanation": null,
+            },
+            "level": "error",
+            "spans": spans,
+            "children": children,
+            "rendered": format!("error: {}\n{}\n", self.kind, self.rendered),
+        })
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for location in &self.locations {
+            write!(f, "\n  --> {}", location)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Patterns {
+    undefined: Regex,
+    multiple: Regex,
+    region: Regex,
+    missing_lib: Regex,
+    lld_undefined: Regex,
+    lld_duplicate: Regex,
+    lld_location: Regex,
+    msvc_undefined: Regex,
+    msvc_multiple: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        let regex = |r| Regex::new(r).unwrap();
+
+        Self {
+            // `<location>: undefined reference to `<symbol>'`
+            undefined: regex(r"^(?:(.*?): )?undefined reference to [`'](.+)'$"),
+            // `<location>: multiple definition of `<symbol>'; <location>: first defined here`
+            multiple: regex(
+                r"^(?:(.*?): )?multiple definition of [`']([^']+)'(?:; (.*?): first defined here)?",
+            ),
+            // GNU ld: `region `<region>' overflowed by <n> bytes`,
+            // lld: `section '<section>' will not fit in region '<region>': overflowed by <n> bytes`
+            region: regex(r"region [`']([^']+)'.*overflowed by (\d+) bytes?"),
+            // GNU ld: `cannot find -l<lib>`, lld: `unable to find library -l<lib>`,
+            // msvc: `LNK1181: cannot open input file '<lib>.lib'`
+            missing_lib: regex(
+                r"(?:cannot find -l|unable to find library -l)(:?[^\s:]+)|LNK1181: cannot open input file '([^']+)'",
+            ),
+            lld_undefined: regex(r"error: undefined (?:hidden )?symbol: (.+)$"),
+            lld_duplicate: regex(r"error: duplicate symbol: (.+)$"),
+            lld_location: regex(r"^>>> (?:referenced by|defined at) (.+)$"),
+            msvc_undefined: regex(r"^(?:(.*?) : )?error LNK2019: unresolved external symbol (\S+)"),
+            msvc_multiple: regex(r"^(?:(.*?) : )?error LNK2005: (\S+) already defined"),
+        }
+    }
+}
+
+/// Parse all diagnostics from the linker output `output`.
+///
+/// Diagnostics of the same kind and symbol are merged into one with multiple locations.
+pub fn parse(output: &str) -> Vec<Diagnostic> {
+    let patterns = Patterns::new();
+    let mut result = Vec::<Diagnostic>::new();
+    // The diagnostic the lld `>>> ` location lines that follow belong to.
+    let mut lld_current: Option<usize> = None;
+
+    for line in output.lines() {
+        let line = line.trim_end();
+
+        if line.starts_with(">>> ") {
+            if let Some(i) = lld_current {
+                let diagnostic = &mut result[i];
+                if let Some(captures) = patterns.lld_location.captures(line) {
+                    diagnostic.locations.extend(Location::parse(&captures[1]));
+                }
+                diagnostic.rendered.push('\n');
+                diagnostic.rendered.push_str(line);
+            }
+            continue;
+        }
+        lld_current = None;
+
+        let (kind, locations) = if let Some(c) = patterns.lld_undefined.captures(line) {
+            let kind = DiagnosticKind::UndefinedReference {
+                symbol: c[1].to_owned(),
+            };
+            lld_current = Some(add(&mut result, Diagnostic::new(kind, line)));
+            continue;
+        } else if let Some(c) = patterns.lld_duplicate.captures(line) {
+            let kind = DiagnosticKind::MultipleDefinition {
+                symbol: c[1].to_owned(),
+            };
+            lld_current = Some(add(&mut result, Diagnostic::new(kind, line)));
+            continue;
+        } else if let Some(c) = patterns.undefined.captures(line) {
+            (
+                DiagnosticKind::UndefinedReference {
+                    symbol: c[2].to_owned(),
+                },
+                vec![c.get(1).and_then(|l| Location::parse(l.as_str()))],
+            )
+        } else if let Some(c) = patterns.multiple.captures(line) {
+            (
+                DiagnosticKind::MultipleDefinition {
+                    symbol: c[2].to_owned(),
+                },
+                vec![
+                    c.get(1).and_then(|l| Location::parse(l.as_str())),
+                    c.get(3).and_then(|l| Location::parse(l.as_str())),
+                ],
+            )
+        } else if let Some(c) = patterns.region.captures(line) {
+            (
+                DiagnosticKind::RegionOverflow {
+                    region: c[1].to_owned(),
+                    bytes: c[2].parse().unwrap_or_default(),
+                },
+                vec![],
+            )
+        } else if let Some(c) = patterns.missing_lib.captures(line) {
+            let library = c.get(1).or_else(|| c.get(2)).unwrap().as_str();
+            (
+                DiagnosticKind::MissingLibrary {
+                    library: library.to_owned(),
+                },
+                vec![],
+            )
+        } else if let Some(c) = patterns.msvc_undefined.captures(line) {
+            (
+                DiagnosticKind::UndefinedReference {
+                    symbol: c[2].to_owned(),
+                },
+                vec![c.get(1).and_then(|l| Location::parse(l.as_str()))],
+            )
+        } else if let Some(c) = patterns.msvc_multiple.captures(line) {
+            (
+                DiagnosticKind::MultipleDefinition {
+                    symbol: c[2].to_owned(),
+                },
+                vec![c.get(1).and_then(|l| Location::parse(l.as_str()))],
+            )
+        } else {
+            continue;
+        };
+
+        let mut diagnostic = Diagnostic::new(kind, line);
+        diagnostic.locations = locations.into_iter().flatten().collect();
+        add(&mut result, diagnostic);
+    }
+
+    result
+}
+
+/// Add `diagnostic` to `result` or merge it with an existing diagnostic of the same
+/// kind, return its index.
+fn add(result: &mut Vec<Diagnostic>, diagnostic: Diagnostic) -> usize {
+    if let Some(i) = result.iter().position(|d| d.kind == diagnostic.kind) {
+        let existing = &mut result[i];
+        for location in diagnostic.locations {
+            if !existing.locations.contains(&location) {
+                existing.locations.push(location);
+            }
+        }
+        existing.rendered.push('\n');
+        existing.rendered.push_str(&diagnostic.rendered);
+
+        i
+    } else {
+        result.push(diagnostic);
+        result.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_gnu() {
+        let output = "\
+/opt/xtensa/bin/ld: main.o: in function `app_main':
+/src/main.c:12: undefined reference to `missing_fn'
+/opt/xtensa/bin/ld: /src/main.c:20: undefined reference to `missing_fn'
+/opt/xtensa/bin/ld: util.o:(.text+0x10): multiple definition of `helper'; main.o:(.text+0x0): first defined here
+/opt/xtensa/bin/ld: app.elf section `.iram0.text' will not fit in region `iram0_0_seg'
+/opt/xtensa/bin/ld: region `iram0_0_seg' overflowed by 1234 bytes
+/opt/xtensa/bin/ld: cannot find -lfoo: No such file or directory
+collect2: error: ld returned 1 exit status
+";
+
+        let diagnostics = parse(output);
+        assert_eq!(diagnostics.len(), 4);
+
+        assert_eq!(
+            diagnostics[0].kind,
+            DiagnosticKind::UndefinedReference {
+                symbol: "missing_fn".into()
+            }
+        );
+        assert_eq!(
+            diagnostics[0].locations,
+            vec![
+                Location {
+                    file: "/src/main.c".into(),
+                    line: Some(12)
+                },
+                Location {
+                    file: "/src/main.c".into(),
+                    line: Some(20)
+                },
+            ]
+        );
+
+        assert_eq!(
+            diagnostics[1].kind,
+            DiagnosticKind::MultipleDefinition {
+                symbol: "helper".into()
+            }
+        );
+        assert_eq!(diagnostics[1].locations.len(), 2);
+        assert_eq!(diagnostics[1].locations[1].file, "main.o");
+
+        assert_eq!(
+            diagnostics[2].kind,
+            DiagnosticKind::RegionOverflow {
+                region: "iram0_0_seg".into(),
+                bytes: 1234
+            }
+        );
+        assert_eq!(
+            diagnostics[3].kind,
+            DiagnosticKind::MissingLibrary {
+                library: "foo".into()
+            }
+        );
+
+        let json = diagnostics[0].to_json();
+        assert_eq!(json["code"]["code"], "undefined-reference");
+        assert_eq!(json["spans"][1]["line_start"], 20);
+    }
+
+    #[test]
+    fn parse_lld() {
+        let output = "\
+ld.lld: error: undefined symbol: missing_fn
+>>> referenced by main.c:3 (/src/main.c:3)
+>>>               main.o:(app_main)
+ld.lld: error: duplicate symbol: helper
+>>> defined at util.c:1
+>>>            util.o:(helper)
+>>> defined at main.c:5
+ld.lld: error: section '.text' will not fit in region 'flash': overflowed by 16 bytes
+ld.lld: error: unable to find library -lbar
+";
+
+        let diagnostics = parse(output);
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(
+            diagnostics[0].locations,
+            vec![Location {
+                file: "main.c".into(),
+                line: Some(3)
+            }]
+        );
+        assert_eq!(diagnostics[1].locations.len(), 2);
+        assert_eq!(
+            diagnostics[2].kind,
+            DiagnosticKind::RegionOverflow {
+                region: "flash".into(),
+                bytes: 16
+            }
+        );
+        assert_eq!(
+            diagnostics[3].kind,
+            DiagnosticKind::MissingLibrary {
+                library: "bar".into()
+            }
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Running the actual linker.
+
+use std::io::{self, Read, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Context, Result};
+
+/// The result of a linker invocation.
+#[derive(Clone, Debug)]
+pub struct LinkerOutput {
+    pub status: ExitStatus,
+    /// The stdout of the linker, decoded lossily.
+    pub stdout: String,
+    /// The stderr of the linker, decoded lossily.
+    pub stderr: String,
+}
+
+/// Run `cmd`, pass its stdout and stderr through as they are written and capture them.
+pub fn run(cmd: &mut Command) -> Result<LinkerOutput> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| anyhow!("Could not run linker {:?}", cmd.get_program()))?;
+
+    let stdout = tee(child.stdout.take().unwrap(), io::stdout());
+    let stderr = tee(child.stderr.take().unwrap(), io::stderr());
+
+    let status = child.wait()?;
+
+    let join = |handle: JoinHandle<io::Result<Vec<u8>>>| -> Result<String> {
+        let output = handle
+            .join()
+            .map_err(|_| anyhow!("Forwarding the linker output panicked"))??;
+
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    };
+
+    Ok(LinkerOutput {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
+
+/// Copy everything from `from` to `to` as soon as it is available, and return all of
+/// it once `from` is closed.
+fn tee(
+    mut from: impl Read + Send + 'static,
+    mut to: impl Write + Send + 'static,
+) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut buf = [0; 8192];
+
+        loop {
+            let len = match from.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            to.write_all(&buf[..len])?;
+            to.flush()?;
+            captured.extend_from_slice(&buf[..len]);
+        }
+
+        Ok(captured)
+    })
+}
+
+/// Get the exit code ldproxy should exit with for the linker exit `status`.
+///
+/// A linker killed by a signal results in `128 + <signal>`, like in a shell.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+
+    1
+}
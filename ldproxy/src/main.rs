@@ -1,13 +1,25 @@
-use std::collections::HashMap;
-use std::env;
-use std::path::Path;
-use std::process::Command;
-use std::vec::Vec;
-
-use anyhow::{bail, Result};
-use embuild::build;
-use embuild::cli::{ParseFrom, UnixCommandArgs};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use cache::LinkCache;
+use embuild::build::{self, LinkerFlavor};
+use linker::LinkerOutput;
 use log::*;
+use memory_report::MemoryReport;
+use options::Options;
+use paths::{PathKind, SearchPaths};
+
+mod cache;
+mod diagnostics;
+mod linker;
+mod memory_report;
+mod options;
+mod paths;
+mod reproducer;
+mod response_file;
+mod rewrite;
 
 fn main() -> Result<()> {
     env_logger::Builder::from_env(
@@ -26,128 +38,270 @@ fn main() -> Result<()> {
 
     debug!("Raw link arguments: {:?}", env::args());
 
-    let mut args = args()?;
+    let raw_args = env::args().skip(1).collect::<Vec<_>>();
+    let mut args = args(&raw_args)?;
 
     debug!("Link arguments: {:?}", args);
 
-    let [linker, remove_duplicate_libs, cwd] = [
-        &build::LDPROXY_LINKER_ARG,
-        &build::LDPROXY_DEDUP_LIBS_ARG,
-        &build::LDPROXY_WORKING_DIRECTORY_ARG,
-    ]
-    .parse_from(&mut args);
-
-    let linker = linker
-        .ok()
-        .and_then(|v| v.into_iter().last())
-        .unwrap_or_else(|| {
-            panic!(
-                "Cannot locate argument '{}'",
-                build::LDPROXY_LINKER_ARG.format(Some("<linker>"))
-            )
-        });
+    let options = Options::parse(&mut args)?;
+    let Options {
+        ref linker,
+        flavor,
+        ref cwd,
+        ..
+    } = options;
 
     debug!("Actual linker executable: {}", linker);
+    debug!("Linker flavor: {}", flavor);
 
-    let cwd = cwd.ok().and_then(|v| v.into_iter().last());
-    let remove_duplicate_libs = remove_duplicate_libs.is_ok();
+    for rules_file in &options.rewrite_rules {
+        let rules = rewrite::load(rules_file)?;
 
-    let args = if remove_duplicate_libs {
+        debug!("Applying {} rewrite rules from {}", rules.len(), rules_file);
+
+        args = rewrite::apply(&rules, args);
+    }
+
+    let mut args = if options.group_libs {
+        debug!("Grouping of repeated libs requested");
+
+        flavor.group_libs(args)
+    } else if options.dedup_libs {
         debug!("Duplicate libs removal requested");
 
-        let mut libs = HashMap::<String, usize>::new();
+        flavor.dedup_libs(args)
+    } else {
+        args
+    };
+
+    let map_file = if options.memory_report.is_some() {
+        map_file(flavor, &mut args)
+    } else {
+        None
+    };
+
+    let reproducer_dir = options
+        .reproducer
+        .clone()
+        .or_else(|| env::var(reproducer::REPRODUCER_VAR).ok());
+    let write_reproducer = || -> Result<()> {
+        if let Some(dir) = &reproducer_dir {
+            let dir = reproducer::write(dir, linker, flavor, cwd.as_deref(), &args, &raw_args)
+                .context("Could not write link reproducer")?;
+
+            info!("Link reproducer written to {}", dir.display());
+        }
+
+        Ok(())
+    };
 
-        for arg in &args {
-            if arg.starts_with("-l") {
-                *libs.entry(arg.clone()).or_default() += 1;
+    let cache = options
+        .cache
+        .clone()
+        .or_else(|| env::var(cache::CACHE_VAR).ok())
+        .and_then(|dir| match link_cache(dir, &options, &args) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!("Link cache disabled: {:#}", err);
+                None
             }
+        });
+
+    let cached = match &cache {
+        Some((cache, outputs)) => {
+            let hit = cache.restore(outputs).unwrap_or_else(|err| {
+                warn!("{:#}", err);
+                false
+            });
+
+            match cache.record(hit) {
+                Ok(stats) => info!(
+                    "Link cache {} ({} hits, {} misses)",
+                    if hit { "hit" } else { "miss" },
+                    stats.hits,
+                    stats.misses
+                ),
+                Err(err) => warn!("Could not update link cache statistics: {:#}", err),
+            }
+
+            hit
         }
+        None => false,
+    };
+
+    if !cached {
+        let mut cmd = Command::new(linker);
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.args(&args);
 
-        debug!("Libs occurances: {:?}", libs);
+        debug!("Calling actual linker: {:?}", cmd);
 
-        let mut deduped_args = Vec::new();
+        let LinkerOutput {
+            status,
+            stdout,
+            stderr,
+        } = linker::run(&mut cmd)?;
 
-        for arg in args {
-            if libs.contains_key(&arg) {
-                *libs.get_mut(&arg).unwrap() -= 1;
+        debug!("==============Linker stdout:\n{}\n==============", stdout);
+        debug!("==============Linker stderr:\n{}\n==============", stderr);
 
-                if libs[&arg] == 0 {
-                    libs.remove(&arg);
-                }
+        if !status.success() {
+            if let Err(err) = write_reproducer() {
+                error!("{:#}", err);
             }
 
-            if !libs.contains_key(&arg) {
-                deduped_args.push(arg);
+            if options.diagnostics || options.diagnostics_file.is_some() {
+                emit_diagnostics(&options, &args, &stdout, &stderr)?;
             }
-        }
 
-        deduped_args
-    } else {
-        args
-    };
+            // The linker output has been passed through already.
+            error!("Linker {} failed: {}", linker, status);
+            process::exit(linker::exit_code(status));
+        }
 
-    let mut cmd = Command::new(&linker);
-    if let Some(cwd) = cwd {
-        cmd.current_dir(cwd);
+        if let Some((cache, outputs)) = &cache {
+            if let Err(err) = cache.store(outputs) {
+                warn!("{:#}", err);
+            }
+        }
     }
-    cmd.args(&args);
 
-    debug!("Calling actual linker: {:?}", cmd);
+    if let (Some(format), Some(map_file)) = (options.memory_report, map_file) {
+        let cwd = Path::new(cwd.as_deref().unwrap_or("."));
+        let output =
+            paths::find_path(flavor, &args, PathKind::Output).unwrap_or_else(|| "a.out".to_owned());
 
-    let output = cmd.output()?;
-    let stdout = String::from_utf8(output.stdout)?;
-    let stderr = String::from_utf8(output.stderr)?;
+        let report = MemoryReport::new(cwd.join(map_file), cwd.join(output))
+            .context("Could not create memory report")?;
+        let report = report.format(format)?;
 
-    debug!("==============Linker stdout:\n{}\n==============", stdout);
-    debug!("==============Linker stderr:\n{}\n==============", stderr);
-
-    if !output.status.success() {
-        bail!(
-            "Linker {} failed: {}\nSTDERR OUTPUT:\n{}",
-            linker,
-            output.status,
-            stderr
-        );
+        if let Some(file) = &options.memory_report_file {
+            fs::write(file, report)
+                .with_context(|| anyhow!("Could not write memory report to '{}'", file))?;
+        } else {
+            info!("{}", report);
+        }
     }
 
     if env::var("LDPROXY_LINK_FAIL").is_ok() {
+        write_reproducer()?;
+
         bail!("Failure requested");
     }
 
     Ok(())
 }
 
-/// Get all arguments
+/// Parse the diagnostics from the linker output and write them as JSON messages to the
+/// diagnostics file.
 ///
-/// **Currently only supports gcc-like arguments**
-///
-/// FIXME: handle other linker flavors (https://doc.rust-lang.org/rustc/codegen-options/index.html#linker-flavor)
-fn args() -> Result<Vec<String>> {
-    let mut result = Vec::new();
-
-    for arg in env::args().skip(1) {
-        // Rustc could invoke use with response file arguments, so we could get arguments
-        // like: `@<link-args-file>` (as per `@file` section of
-        // https://gcc.gnu.org/onlinedocs/gcc-11.2.0/gcc/Overall-Options.html)
-        //
-        // Deal with that
-        if let Some(rsp_file_str) = arg.strip_prefix('@') {
-            let rsp_file = Path::new(rsp_file_str);
-            // get all arguments from the response file if it exists
-            if rsp_file.exists() {
-                let contents = std::fs::read_to_string(rsp_file)?;
-                debug!("Contents of {}: {}", rsp_file_str, contents);
-
-                result.extend(UnixCommandArgs::new(&contents));
-            }
-            // otherwise just add the argument as normal
-            else {
-                result.push(arg);
-            }
-        } else {
-            result.push(arg);
+/// Rustc captures everything the linker prints, so the messages are written to
+/// `<output>.diagnostics.json` next to the output file if no diagnostics file is given.
+fn emit_diagnostics(options: &Options, args: &[String], stdout: &str, stderr: &str) -> Result<()> {
+    // The msvc linker prints its errors to stdout.
+    let diagnostics = diagnostics::parse(&format!("{}\n{}", stdout, stderr));
+
+    let mut messages = String::new();
+    for diagnostic in &diagnostics {
+        messages.push_str(&diagnostic.to_json().to_string());
+        messages.push('\n');
+    }
+
+    let file = match &options.diagnostics_file {
+        Some(file) => PathBuf::from(file),
+        None => {
+            let output = paths::find_path(options.flavor, args, PathKind::Output)
+                .unwrap_or_else(|| "a.out".to_owned());
+
+            Path::new(options.cwd.as_deref().unwrap_or("."))
+                .join(output)
+                .with_extension("diagnostics.json")
         }
+    };
+
+    fs::write(&file, messages)
+        .with_context(|| anyhow!("Could not write linker diagnostics to '{}'", file.display()))?;
+
+    info!(
+        "{} linker diagnostics written to {}",
+        diagnostics.len(),
+        file.display()
+    );
+
+    Ok(())
+}
+
+/// Create the link cache in `dir` for the link with `args` and get the paths of the
+/// link outputs.
+fn link_cache(
+    dir: String,
+    options: &Options,
+    args: &[String],
+) -> Result<(LinkCache, Vec<PathBuf>)> {
+    let flavor = options.flavor;
+    let cwd = Path::new(options.cwd.as_deref().unwrap_or("."));
+
+    let output = paths::find_path(flavor, args, PathKind::Output)
+        .or_else(|| (!flavor.is_msvc()).then(|| "a.out".to_owned()))
+        .ok_or_else(|| anyhow!("The output file of the link is unknown"))?;
+
+    let outputs = std::iter::once(output)
+        .chain(paths::find_path(flavor, args, PathKind::Map))
+        .map(|file| cwd.join(file))
+        .collect();
+
+    let search = SearchPaths::new(flavor, options.cwd.as_deref(), args)?;
+    let cache = LinkCache::new(dir, options.cache_max_size, &options.linker, &search, args)?;
+
+    Ok((cache, outputs))
+}
+
+/// Get the map file the linker writes or add the arguments to write one next to the
+/// output file.
+fn map_file(flavor: LinkerFlavor, args: &mut Vec<String>) -> Option<String> {
+    if let Some(map_file) = paths::find_path(flavor, args, PathKind::Map) {
+        return Some(map_file);
+    }
+
+    if flavor.is_msvc() {
+        warn!("Memory reports are not supported for the msvc linker flavor");
+        return None;
     }
 
-    Ok(result)
+    let output =
+        paths::find_path(flavor, args, PathKind::Output).unwrap_or_else(|| "a.out".to_owned());
+    let map_file = Path::new(&output)
+        .with_extension("map")
+        .to_string_lossy()
+        .into_owned();
+
+    debug!("Requesting map file {}", map_file);
+
+    args.push(if flavor == LinkerFlavor::Gcc {
+        format!("-Wl,-Map={}", map_file)
+    } else {
+        format!("-Map={}", map_file)
+    });
+
+    Some(map_file)
+}
+
+/// Get all arguments
+///
+/// Response files (`@<file>`), also nested ones, are expanded according to the linker
+/// flavor given by `--ldproxy-linker-flavor` or detected from `--ldproxy-linker`, if
+/// these are not part of a response file themselves. Otherwise response files are
+/// parsed as gcc-like.
+fn args(raw_args: &[String]) -> Result<Vec<String>> {
+    let mut ldproxy_args = raw_args.to_vec();
+    let flavor = options::linker_flavor(
+        options::last(&build::LDPROXY_LINKER_FLAVOR_ARG, &mut ldproxy_args),
+        options::last(&build::LDPROXY_LINKER_ARG, &mut ldproxy_args).as_ref(),
+    )?;
+
+    // Rustc could invoke use with response file arguments, so we could get arguments
+    // like: `@<link-args-file>`, which could refer to other response files.
+    response_file::expand(flavor, raw_args.iter().cloned())
 }
@@ -0,0 +1,534 @@
+//! Memory usage reports from the linker map file and the linked ELF file.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::Serialize;
+use xmas_elf::program::Type;
+use xmas_elf::sections::{SectionHeader, ShType, SHF_ALLOC};
+use xmas_elf::ElfFile;
+
+/// How many of the biggest contributing archives are included in the report.
+const MAX_ARCHIVES: usize = 10;
+
+/// The format in which the memory report is emitted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" | "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unsupported memory report format '{}'", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MemoryReport {
+    /// The memory regions from the linker script and how much of them is used.
+    pub regions: Vec<RegionUsage>,
+    /// All allocated sections of the linked file.
+    pub sections: Vec<SectionUsage>,
+    /// The archives (or object files) with the biggest contribution to the allocated
+    /// sections, sorted by size.
+    pub archives: Vec<ArchiveUsage>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RegionUsage {
+    pub name: String,
+    pub origin: u64,
+    pub length: u64,
+    pub used: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SectionUsage {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    /// The memory region containing this section, if any.
+    pub region: Option<String>,
+    /// The load address (LMA) of this section if it differs from its address, e.g. for
+    /// initialized data that is copied from flash at startup.
+    pub load_address: Option<u64>,
+    /// The memory region containing the load address, if any.
+    pub load_region: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ArchiveUsage {
+    pub name: String,
+    pub size: u64,
+}
+
+/// An input section as listed in the map file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct InputSection {
+    address: u64,
+    size: u64,
+    file: String,
+}
+
+impl MemoryReport {
+    /// Create a memory report from the map file `map_file` and the linked ELF file
+    /// `elf_file`.
+    pub fn new(map_file: impl AsRef<Path>, elf_file: impl AsRef<Path>) -> Result<Self> {
+        let map_file = map_file.as_ref();
+        let map = fs::read_to_string(map_file)
+            .with_context(|| anyhow!("Could not read map file '{}'", map_file.display()))?;
+
+        let elf_data = fs::read(elf_file)?;
+        let elf = ElfFile::new(&elf_data).map_err(Error::msg)?;
+
+        let sections = elf
+            .section_iter()
+            .filter(|header| {
+                header.flags() & SHF_ALLOC != 0
+                    && header.size() > 0
+                    && header.get_type() != Ok(ShType::Null)
+            })
+            .map(|header| {
+                Ok(SectionUsage {
+                    name: header.get_name(&elf).map_err(Error::msg)?.to_owned(),
+                    address: header.address(),
+                    size: header.size(),
+                    region: None,
+                    load_address: load_address(&elf, &header)
+                        .filter(|&lma| lma != header.address()),
+                    load_region: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self::from_parts(
+            parse_memory_regions(&map),
+            sections,
+            parse_input_sections(&map),
+        ))
+    }
+
+    fn from_parts(
+        mut regions: Vec<RegionUsage>,
+        mut sections: Vec<SectionUsage>,
+        input_sections: Vec<InputSection>,
+    ) -> Self {
+        for section in &mut sections {
+            section.region = use_region(&mut regions, section.address, section.size);
+            // Sections loaded from a different address occupy memory at both addresses.
+            section.load_region = section
+                .load_address
+                .and_then(|lma| use_region(&mut regions, lma, section.size));
+        }
+
+        let mut archives = HashMap::<String, u64>::new();
+        for input in input_sections {
+            let allocated = sections
+                .iter()
+                .any(|s| input.address >= s.address && input.address - s.address < s.size);
+
+            if allocated {
+                *archives.entry(archive_name(&input.file)).or_default() += input.size;
+            }
+        }
+
+        let mut archives = archives
+            .into_iter()
+            .map(|(name, size)| ArchiveUsage { name, size })
+            .collect::<Vec<_>>();
+        archives.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        archives.truncate(MAX_ARCHIVES);
+
+        Self {
+            regions,
+            sections,
+            archives,
+        }
+    }
+
+    /// Format this report in `format`.
+    pub fn format(&self, format: ReportFormat) -> Result<String> {
+        Ok(match format {
+            ReportFormat::Text => self.to_string(),
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+}
+
+impl Display for MemoryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Memory region usage:")?;
+        if self.regions.is_empty() {
+            writeln!(f, "  (no memory regions defined)")?;
+        }
+        for region in &self.regions {
+            let percent = if region.length > 0 {
+                region.used as f64 * 100.0 / region.length as f64
+            } else {
+                0.0
+            };
+
+            writeln!(
+                f,
+                "  {:<24} {:>10} / {:>10} bytes ({:5.1}%)",
+                region.name, region.used, region.length, percent
+            )?;
+        }
+
+        writeln!(f, "Section sizes:")?;
+        for section in &self.sections {
+            write!(
+                f,
+                "  {:<24} 0x{:08x} {:>10} {}",
+                section.name,
+                section.address,
+                section.size,
+                section.region.as_deref().unwrap_or("")
+            )?;
+
+            if let Some(lma) = section.load_address {
+                write!(
+                    f,
+                    " (loaded from 0x{:08x} {})",
+                    lma,
+                    section.load_region.as_deref().unwrap_or("")
+                )?;
+            }
+            writeln!(f)?;
+        }
+
+        write!(f, "Biggest contributors:")?;
+        for archive in &self.archives {
+            write!(f, "\n  {:<40} {:>10}", archive.name, archive.size)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Add `size` to the usage of the region containing `address` and return its name.
+fn use_region(regions: &mut [RegionUsage], address: u64, size: u64) -> Option<String> {
+    let region = regions
+        .iter_mut()
+        .find(|r| address >= r.origin && address - r.origin < r.length)?;
+    region.used += size;
+
+    Some(region.name.clone())
+}
+
+/// Get the load address of the section `header` from the loadable segment containing
+/// it, if any.
+fn load_address(elf: &ElfFile, header: &SectionHeader) -> Option<u64> {
+    // Sections without contents (like `.bss`) are not loaded.
+    if header.get_type() == Ok(ShType::NoBits) {
+        return None;
+    }
+
+    elf.program_iter()
+        .filter(|ph| ph.get_type() == Ok(Type::Load))
+        .find(|ph| {
+            header.offset() >= ph.offset()
+                && header.offset() + header.size() <= ph.offset() + ph.file_size()
+        })
+        .map(|ph| ph.physical_addr() + (header.offset() - ph.offset()))
+}
+
+/// Get the name of the archive (or object file) of the map file's input file `file`.
+fn archive_name(file: &str) -> String {
+    // Archive members are listed as `<archive>(<member>)`.
+    let file = match file.find('(') {
+        Some(i) if file.ends_with(')') => &file[..i],
+        _ => file,
+    };
+
+    Path::new(file)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.to_owned())
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Parse the `Memory Configuration` of a GNU ld map file.
+fn parse_memory_regions(map: &str) -> Vec<RegionUsage> {
+    map.lines()
+        .skip_while(|l| l.trim() != "Memory Configuration")
+        .skip(1)
+        .take_while(|l| !l.starts_with("Linker script and memory map"))
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let name = fields.next()?;
+            let origin = parse_hex(fields.next()?)?;
+            let length = parse_hex(fields.next()?)?;
+
+            if name == "*default*" {
+                return None;
+            }
+
+            Some(RegionUsage {
+                name: name.to_owned(),
+                origin,
+                length,
+                used: 0,
+            })
+        })
+        .collect()
+}
+
+/// Parse all input sections of a GNU ld or lld map file.
+fn parse_input_sections(map: &str) -> Vec<InputSection> {
+    let mut lines = map.lines();
+
+    let is_lld = lines
+        .next()
+        .map(|l| l.split_whitespace().next() == Some("VMA"))
+        .unwrap_or(false);
+
+    if is_lld {
+        // `VMA LMA Size Align Out In Symbol`, the input column is `<file>:(<section>)`.
+        return lines
+            .filter_map(|l| {
+                let mut fields = l.split_whitespace();
+                let address = parse_hex(fields.next()?)?;
+                let _lma = fields.next()?;
+                let size = parse_hex(fields.next()?)?;
+                let _align = fields.next()?;
+                let input = fields.collect::<Vec<_>>().join(" ");
+                let (file, _) = input.split_once(":(")?;
+
+                Some(InputSection {
+                    address,
+                    size,
+                    file: file.to_owned(),
+                })
+            })
+            .filter(|s| s.size > 0)
+            .collect();
+    }
+
+    let mut result = Vec::new();
+    let mut pending_name = false;
+
+    for line in map
+        .lines()
+        .skip_while(|l| !l.starts_with("Linker script and memory map"))
+    {
+        // Input sections are indented by one space, long section names are followed by a
+        // line with the remaining fields.
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let is_input = line.starts_with(' ') && !line.starts_with("  ");
+
+        let fields = match fields.as_slice() {
+            [name] if is_input && name.starts_with('.') => {
+                pending_name = true;
+                continue;
+            }
+            [name, rest @ ..] if is_input && (name.starts_with('.') || *name == "COMMON") => rest,
+            rest if pending_name && line.starts_with("  ") => rest,
+            _ => {
+                pending_name = false;
+                continue;
+            }
+        };
+        pending_name = false;
+
+        if let [address, size, file @ ..] = fields {
+            if let (Some(address), Some(size), false) =
+                (parse_hex(address), parse_hex(size), file.is_empty())
+            {
+                if size > 0 {
+                    result.push(InputSection {
+                        address,
+                        size,
+                        file: file.join(" "),
+                    });
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GNU_MAP: &str = "\
+Archive member included to satisfy reference by file (symbol)
+
+Memory Configuration
+
+Name             Origin             Length             Attributes
+iram0_0_seg      0x0000000040080000 0x0000000000020000 xr
+dram0_0_seg      0x000000003ffb0000 0x000000000002c200 rw
+*default*        0x0000000000000000 0xffffffffffffffff
+
+Linker script and memory map
+
+.iram0.text     0x0000000040080000      0x130
+ *(.iram1 .iram1.*)
+ .iram1.0       0x0000000040080000      0x100 /build/libfreertos.a(port.c.obj)
+ .iram1.a_very_long_section_name_that_wraps
+                0x0000000040080100       0x30 /build/main.o
+                0x0000000040080100                some_symbol
+ *fill*         0x0000000040080130        0x0
+.dram0.data     0x000000003ffb0000       0x40
+ .data          0x000000003ffb0000       0x40 /build/libfreertos.a(tasks.c.obj)
+.debug_info     0x0000000000000000      0x500
+ .debug_info    0x0000000000000000      0x500 /build/main.o
+";
+
+    #[test]
+    fn parse_gnu_map() {
+        let regions = parse_memory_regions(GNU_MAP);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].name, "iram0_0_seg");
+        assert_eq!(regions[1].length, 0x2c200);
+
+        let inputs = parse_input_sections(GNU_MAP);
+        assert_eq!(inputs.len(), 4);
+        assert_eq!(inputs[1].file, "/build/main.o");
+        assert_eq!(inputs[1].size, 0x30);
+
+        let sections = vec![
+            SectionUsage {
+                name: ".iram0.text".into(),
+                address: 0x40080000,
+                size: 0x130,
+                region: None,
+                load_address: None,
+                load_region: None,
+            },
+            SectionUsage {
+                name: ".dram0.data".into(),
+                address: 0x3ffb0000,
+                size: 0x40,
+                region: None,
+                load_address: None,
+                load_region: None,
+            },
+        ];
+
+        let report = MemoryReport::from_parts(regions, sections, inputs);
+        assert_eq!(report.regions[0].used, 0x130);
+        assert_eq!(report.regions[1].used, 0x40);
+        assert_eq!(report.sections[1].region.as_deref(), Some("dram0_0_seg"));
+        assert_eq!(
+            report.archives,
+            vec![
+                ArchiveUsage {
+                    name: "libfreertos.a".into(),
+                    size: 0x140
+                },
+                ArchiveUsage {
+                    name: "main.o".into(),
+                    size: 0x30
+                },
+            ]
+        );
+    }
+
+    const LLD_MAP: &str = "\
+             VMA              LMA     Size Align Out     In      Symbol
+        42000020         42000020      130     4 .flash.text
+        42000020         42000020      100     4         /build/libfreertos.a(port.c.obj):(.text.vPortYield)
+        42000020         42000020        0     1                 vPortYield
+        42000120         42000120       30     4         /build/main.o:(.text.main)
+        3fc80000         42000150       40    16 .dram0.data
+        3fc80000         42000150       40     4         /build/libfreertos.a(tasks.c.obj):(.data)
+        3fc80040         3fc80040       20    16 .dram0.bss
+        3fc80040         3fc80040       20     4         /build/main.o:(.bss)
+               0                0      500     1 .debug_info
+               0                0      500     1         /build/main.o:(.debug_info)
+";
+
+    #[test]
+    fn parse_lld_map() {
+        // lld map files have no memory configuration.
+        assert!(parse_memory_regions(LLD_MAP).is_empty());
+
+        let inputs = parse_input_sections(LLD_MAP);
+        assert_eq!(
+            inputs[..3],
+            [
+                InputSection {
+                    address: 0x42000020,
+                    size: 0x100,
+                    file: "/build/libfreertos.a(port.c.obj)".into(),
+                },
+                InputSection {
+                    address: 0x42000120,
+                    size: 0x30,
+                    file: "/build/main.o".into(),
+                },
+                InputSection {
+                    address: 0x3fc80000,
+                    size: 0x40,
+                    file: "/build/libfreertos.a(tasks.c.obj)".into(),
+                },
+            ]
+        );
+        assert_eq!(inputs.len(), 5);
+
+        let region = |name: &str, origin, length| RegionUsage {
+            name: name.into(),
+            origin,
+            length,
+            used: 0,
+        };
+        let section = |name: &str, address, size, load_address| SectionUsage {
+            name: name.into(),
+            address,
+            size,
+            region: None,
+            load_address,
+            load_region: None,
+        };
+
+        let report = MemoryReport::from_parts(
+            vec![
+                region("irom_seg", 0x42000000, 0x400000),
+                region("dram0_0_seg", 0x3fc80000, 0x50000),
+            ],
+            vec![
+                section(".flash.text", 0x42000020, 0x130, None),
+                section(".dram0.data", 0x3fc80000, 0x40, Some(0x42000150)),
+                section(".dram0.bss", 0x3fc80040, 0x20, None),
+            ],
+            inputs,
+        );
+
+        // The initializers of `.dram0.data` are stored in flash.
+        assert_eq!(report.regions[0].used, 0x130 + 0x40);
+        assert_eq!(report.regions[1].used, 0x40 + 0x20);
+        assert_eq!(report.sections[1].region.as_deref(), Some("dram0_0_seg"));
+        assert_eq!(report.sections[1].load_region.as_deref(), Some("irom_seg"));
+        assert_eq!(report.sections[2].load_region, None);
+        assert_eq!(
+            report.archives,
+            vec![
+                ArchiveUsage {
+                    name: "libfreertos.a".into(),
+                    size: 0x140
+                },
+                ArchiveUsage {
+                    name: "main.o".into(),
+                    size: 0x50
+                },
+            ]
+        );
+    }
+}
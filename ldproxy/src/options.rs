@@ -0,0 +1,100 @@
+//! The `--ldproxy-*` arguments.
+
+use anyhow::{anyhow, Context, Result};
+use embuild::build::{self, LinkerFlavor};
+use embuild::cli::{ArgDef, ParseFrom};
+
+use crate::cache;
+use crate::memory_report::ReportFormat;
+
+/// All options of ldproxy, which are given as `--ldproxy-*` arguments.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The path to the actual linker.
+    pub linker: String,
+    pub flavor: LinkerFlavor,
+    /// The working directory used when invoking the linker.
+    pub cwd: Option<String>,
+    pub dedup_libs: bool,
+    pub group_libs: bool,
+    /// TOML files with rules to rewrite the linker arguments.
+    pub rewrite_rules: Vec<String>,
+    /// The directory in which a reproducer of a failed link is written.
+    pub reproducer: Option<String>,
+    pub memory_report: Option<ReportFormat>,
+    pub memory_report_file: Option<String>,
+    /// Write the diagnostics of a failed link as JSON messages next to the output file.
+    pub diagnostics: bool,
+    /// Write the diagnostics of a failed link to this file instead.
+    pub diagnostics_file: Option<String>,
+    /// The directory of the link cache.
+    pub cache: Option<String>,
+    /// The maximum size of the link cache in bytes.
+    pub cache_max_size: u64,
+}
+
+impl Options {
+    /// Parse all options from `args` and remove them.
+    pub fn parse(args: &mut Vec<String>) -> Result<Self> {
+        let linker = last(&build::LDPROXY_LINKER_ARG, args).ok_or_else(|| {
+            anyhow!(
+                "Cannot locate argument '{}'",
+                build::LDPROXY_LINKER_ARG.format(Some("<linker>"))
+            )
+        })?;
+        let flavor = linker_flavor(last(&build::LDPROXY_LINKER_FLAVOR_ARG, args), Some(&linker))?;
+
+        let memory_report = build::LDPROXY_MEMORY_REPORT_ARG
+            .parse_from(args)
+            .ok()
+            .map(|v| {
+                v.into_iter()
+                    .last()
+                    .unwrap_or_default()
+                    .parse::<ReportFormat>()
+            })
+            .transpose()?;
+
+        let cache_max_size = last(&build::LDPROXY_CACHE_MAX_SIZE_ARG, args)
+            .map(|size| cache::parse_size(&size))
+            .transpose()?
+            .unwrap_or(cache::DEFAULT_MAX_SIZE);
+
+        Ok(Self {
+            linker,
+            flavor,
+            cwd: last(&build::LDPROXY_WORKING_DIRECTORY_ARG, args),
+            dedup_libs: build::LDPROXY_DEDUP_LIBS_ARG.parse_from(args).is_ok(),
+            group_libs: build::LDPROXY_GROUP_LIBS_ARG.parse_from(args).is_ok(),
+            rewrite_rules: build::LDPROXY_REWRITE_RULES_ARG
+                .parse_from(args)
+                .unwrap_or_default(),
+            reproducer: last(&build::LDPROXY_REPRODUCER_ARG, args),
+            memory_report,
+            memory_report_file: last(&build::LDPROXY_MEMORY_REPORT_FILE_ARG, args),
+            diagnostics: build::LDPROXY_DIAGNOSTICS_ARG.parse_from(args).is_ok(),
+            diagnostics_file: last(&build::LDPROXY_DIAGNOSTICS_FILE_ARG, args),
+            cache: last(&build::LDPROXY_CACHE_ARG, args),
+            cache_max_size,
+        })
+    }
+}
+
+/// Parse `def` from `args`, remove it and return its last value.
+pub fn last(def: &ArgDef, args: &mut Vec<String>) -> Option<String> {
+    def.parse_from(args)
+        .ok()
+        .and_then(|v: Vec<String>| v.into_iter().last())
+}
+
+/// Get the linker flavor from the `--ldproxy-linker-flavor` argument or detect it from
+/// the `linker` executable, defaults to [`LinkerFlavor::Gcc`].
+pub fn linker_flavor(flavor: Option<String>, linker: Option<&String>) -> Result<LinkerFlavor> {
+    if let Some(flavor) = flavor {
+        flavor
+            .parse()
+            .with_context(|| anyhow!("Unsupported linker flavor '{}'", flavor))
+    } else {
+        Ok(linker.and_then(LinkerFlavor::detect).unwrap_or_default())
+    }
+}
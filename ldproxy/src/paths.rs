@@ -0,0 +1,280 @@
+//! Paths referenced by linker arguments.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use embuild::build::LinkerFlavor;
+
+const LINKER_SCRIPT_EXTENSIONS: &[&str] = &["ld", "lds", "x"];
+
+/// What a path in the linker arguments refers to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PathKind {
+    LibDir,
+    Lib,
+    Script,
+    Output,
+    Map,
+    Input,
+}
+
+/// Gcc-like options that take a path as the next argument (`<option> <value>`).
+const GNU_SEPARATE_OPTIONS: &[(&str, PathKind)] = &[
+    ("-L", PathKind::LibDir),
+    ("-l", PathKind::Lib),
+    ("-T", PathKind::Script),
+    ("-o", PathKind::Output),
+    ("-Map", PathKind::Map),
+];
+
+/// Gcc-like options that take a path in the same argument (`<option><value>`).
+///
+/// Longer options must come before their prefixes.
+const GNU_JOINED_OPTIONS: &[(&str, PathKind)] = &[
+    ("--library-path=", PathKind::LibDir),
+    ("--library=", PathKind::Lib),
+    ("--script=", PathKind::Script),
+    ("--output=", PathKind::Output),
+    ("--Map=", PathKind::Map),
+    ("-Map=", PathKind::Map),
+    ("-L", PathKind::LibDir),
+    ("-l", PathKind::Lib),
+    ("-T", PathKind::Script),
+    ("-o", PathKind::Output),
+];
+
+/// Gcc-like options that would be mistaken for a joined option in
+/// [`GNU_JOINED_OPTIONS`], but take an address as `<option>=<value>` or
+/// `<option> <value>`.
+const GNU_ADDRESS_OPTIONS: &[&str] = &[
+    "-Tbss",
+    "-Tdata",
+    "-Ttext",
+    "-Ttext-segment",
+    "-Trodata-segment",
+    "-Tldata-segment",
+];
+
+/// MSVC options that take a path as `<option><value>` (matched case-insensitively).
+const MSVC_OPTIONS: &[(&str, PathKind)] = &[
+    ("/libpath:", PathKind::LibDir),
+    ("-libpath:", PathKind::LibDir),
+    ("/defaultlib:", PathKind::Lib),
+    ("-defaultlib:", PathKind::Lib),
+    ("/out:", PathKind::Output),
+    ("-out:", PathKind::Output),
+    ("/map:", PathKind::Map),
+    ("-map:", PathKind::Map),
+];
+
+/// Get the last path of `kind` in `args`.
+pub fn find_path(flavor: LinkerFlavor, args: &[String], kind: PathKind) -> Option<String> {
+    let mut result = None;
+    for_each_path(flavor, &mut args.to_vec(), &mut |k, value| {
+        if k == kind {
+            result = Some(value.to_owned());
+        }
+        None
+    });
+
+    result
+}
+
+/// Resolves the files referenced by linker arguments.
+#[derive(Clone, Debug)]
+pub struct SearchPaths {
+    pub flavor: LinkerFlavor,
+    /// The working directory of the linker.
+    pub cwd: PathBuf,
+    /// Absolute paths of all library search directories.
+    pub lib_dirs: Vec<PathBuf>,
+}
+
+impl SearchPaths {
+    /// Create the search paths for the linker `args` that are used in the working
+    /// directory `cwd` (or the current directory if [`None`]).
+    pub fn new(flavor: LinkerFlavor, cwd: Option<&str>, args: &[String]) -> Result<Self> {
+        let cwd = match cwd {
+            Some(cwd) => PathBuf::from(cwd),
+            None => std::env::current_dir()?,
+        };
+
+        let mut lib_dirs = Vec::new();
+        for_each_path(flavor, &mut args.to_vec(), &mut |kind, value| {
+            if kind == PathKind::LibDir {
+                lib_dirs.push(cwd.join(value));
+            }
+            None
+        });
+
+        Ok(Self {
+            flavor,
+            cwd,
+            lib_dirs,
+        })
+    }
+
+    /// Find the file of library `lib` in the library search directories.
+    pub fn find_lib(&self, lib: &str) -> Option<PathBuf> {
+        let candidates = if self.flavor.is_msvc() {
+            if Path::new(lib).extension().is_some() {
+                vec![lib.to_owned()]
+            } else {
+                vec![format!("{}.lib", lib)]
+            }
+        } else if let Some(file) = lib.strip_prefix(':') {
+            vec![file.to_owned()]
+        } else {
+            vec![format!("lib{}.a", lib), format!("lib{}.so", lib)]
+        };
+
+        candidates.iter().find_map(|c| {
+            let path = Path::new(c);
+            if path.is_absolute() {
+                Some(path.to_owned()).filter(|p| p.is_file())
+            } else if self.flavor.is_msvc() && self.cwd.join(path).is_file() {
+                Some(self.cwd.join(path))
+            } else {
+                self.find_in_lib_dirs(c)
+            }
+        })
+    }
+
+    pub fn find_in_lib_dirs(&self, file: &str) -> Option<PathBuf> {
+        self.lib_dirs
+            .iter()
+            .map(|dir| dir.join(file))
+            .find(|path| path.is_file())
+    }
+}
+
+/// Get all linker scripts in the directory `dir`.
+pub fn linker_scripts(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?.path();
+        let is_script = entry
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| LINKER_SCRIPT_EXTENSIONS.contains(&e))
+            .unwrap_or(false);
+
+        if is_script && entry.is_file() {
+            result.push(entry);
+        }
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+/// Call `f` for every path in `args` and replace the path with the result of `f`, if
+/// any.
+pub fn for_each_path(
+    flavor: LinkerFlavor,
+    args: &mut [String],
+    f: &mut dyn FnMut(PathKind, &str) -> Option<String>,
+) {
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+
+        if flavor.is_msvc() {
+            let lower = arg.to_ascii_lowercase();
+
+            if let Some((prefix, kind)) = MSVC_OPTIONS.iter().find(|(p, _)| lower.starts_with(p)) {
+                let value = arg[prefix.len()..].trim_matches('"');
+                if let Some(new) = f(*kind, value) {
+                    args[i] = format!("{}{}", &arg[..prefix.len()], new);
+                }
+            } else if !arg.starts_with('/') && !arg.starts_with('-') {
+                if let Some(new) = f(PathKind::Input, arg) {
+                    args[i] = new;
+                }
+            }
+        } else if let Some(wl_args) = arg
+            .strip_prefix("-Wl,")
+            .filter(|_| flavor == LinkerFlavor::Gcc)
+        {
+            let mut wl_args = wl_args.split(',').map(str::to_owned).collect::<Vec<_>>();
+            for_each_path(LinkerFlavor::Ld, &mut wl_args, f);
+            args[i] = format!("-Wl,{}", wl_args.join(","));
+        } else if let Some(option) = GNU_ADDRESS_OPTIONS.iter().find(|o| {
+            matches!(arg.strip_prefix(*o), Some(value) if value.is_empty() || value.starts_with('='))
+        }) {
+            if arg == option {
+                i += 1;
+            }
+        } else if let Some((_, kind)) = GNU_SEPARATE_OPTIONS.iter().find(|(p, _)| arg == *p) {
+            if let Some(value) = args.get(i + 1) {
+                if let Some(new) = f(*kind, value) {
+                    args[i + 1] = new;
+                }
+            }
+            i += 1;
+        } else if let Some((prefix, kind)) =
+            GNU_JOINED_OPTIONS.iter().find(|(p, _)| arg.starts_with(p))
+        {
+            if let Some(new) = f(*kind, &arg[prefix.len()..]) {
+                args[i] = format!("{}{}", prefix, new);
+            }
+        } else if !arg.starts_with('-') {
+            if let Some(new) = f(PathKind::Input, arg) {
+                args[i] = new;
+            }
+        }
+
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let mut args = [
+            "main.o",
+            "-Ttext=0x1000",
+            "-Tdata",
+            "0x2000",
+            "-Tmemory.x",
+            "-Wl,-Map=app.map,--gc-sections",
+            "-o",
+            "app.elf",
+        ]
+        .map(str::to_owned);
+
+        let mut paths = Vec::new();
+        for_each_path(LinkerFlavor::Gcc, &mut args, &mut |kind, value| {
+            paths.push((kind, value.to_owned()));
+            Some(format!("new/{}", value))
+        });
+
+        assert_eq!(
+            paths,
+            [
+                (PathKind::Input, "main.o".to_owned()),
+                (PathKind::Script, "memory.x".to_owned()),
+                (PathKind::Map, "app.map".to_owned()),
+                (PathKind::Output, "app.elf".to_owned()),
+            ]
+        );
+        assert_eq!(
+            args,
+            [
+                "new/main.o",
+                "-Ttext=0x1000",
+                "-Tdata",
+                "0x2000",
+                "-Tnew/memory.x",
+                "-Wl,-Map=new/app.map,--gc-sections",
+                "-o",
+                "new/app.elf",
+            ]
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! Self-contained link reproducers.
+//!
+//! A reproducer is a directory containing the final linker arguments, a copy of every
+//! object, archive and linker script they reference and a shell script that reruns the
+//! real linker with these copies.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use embuild::build::LinkerFlavor;
+use embuild::cli;
+use log::*;
+
+use crate::paths::{find_path, for_each_path, linker_scripts, PathKind, SearchPaths};
+
+/// The environment variable that enables the reproducer mode, its value is the
+/// directory in which reproducers of failed links are written.
+pub const REPRODUCER_VAR: &str = "LDPROXY_REPRODUCER";
+
+const FILES_DIR: &str = "files";
+const OUT_DIR: &str = "out";
+const ARGS_FILE: &str = "args.txt";
+const ORIGINAL_ARGS_FILE: &str = "original-args.txt";
+const SCRIPT_FILE: &str = "link.sh";
+
+/// Write a reproducer for the invocation of `linker` with `args` into a subdirectory of
+/// `dir` and return the path of that subdirectory.
+///
+/// `raw_args` are the arguments as ldproxy received them, before expanding response
+/// files, and are only saved for reference.
+pub fn write(
+    dir: impl AsRef<Path>,
+    linker: &str,
+    flavor: LinkerFlavor,
+    cwd: Option<&str>,
+    args: &[String],
+    raw_args: &[String],
+) -> Result<PathBuf> {
+    let mut reproducer = Reproducer {
+        root: PathBuf::new(),
+        search: SearchPaths::new(flavor, cwd, args)?,
+        copied: HashSet::new(),
+    };
+    let name = reproducer.output_name(args);
+    reproducer.root = dir.as_ref().join(name);
+
+    if reproducer.root.exists() {
+        fs::remove_dir_all(&reproducer.root).with_context(|| {
+            format!(
+                "Could not remove old reproducer '{}'",
+                reproducer.root.display()
+            )
+        })?;
+    }
+    fs::create_dir_all(reproducer.root.join(FILES_DIR))?;
+    fs::create_dir_all(reproducer.root.join(OUT_DIR))?;
+
+    let args = reproducer.rewrite_args(flavor, args)?;
+
+    let root = &reproducer.root;
+    fs::write(
+        root.join(ARGS_FILE),
+        flavor.format_response_file(args.iter().map(String::as_str)),
+    )?;
+    fs::write(
+        root.join(ORIGINAL_ARGS_FILE),
+        cli::join_unix_args(raw_args.iter().map(String::as_str)),
+    )?;
+
+    let script_path = root.join(SCRIPT_FILE);
+    fs::write(
+        &script_path,
+        format!(
+            "#!/bin/sh\n\
+             # Reruns the link captured by ldproxy with the files of this directory.\n\
+             cd \"$(dirname \"$0\")\" || exit 1\n\
+             exec {} @{} \"$@\"\n",
+            cli::quote_unix_arg(linker),
+            ARGS_FILE
+        ),
+    )?;
+    make_executable(&script_path)?;
+
+    Ok(reproducer.root)
+}
+
+struct Reproducer {
+    root: PathBuf,
+    search: SearchPaths,
+    /// Absolute paths of all files already copied.
+    copied: HashSet<PathBuf>,
+}
+
+impl Reproducer {
+    /// Get the name of the reproducer from the output file of the link.
+    fn output_name(&self, args: &[String]) -> String {
+        find_path(self.search.flavor, args, PathKind::Output)
+            .and_then(|output| {
+                Path::new(&output)
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+            })
+            .unwrap_or_else(|| "link".to_owned())
+    }
+
+    /// Copy all files referenced by `args` and return the arguments changed to refer to
+    /// the copies.
+    fn rewrite_args(&mut self, flavor: LinkerFlavor, args: &[String]) -> Result<Vec<String>> {
+        let mut error = None;
+        let mut rewrite = |kind, value: &str| match self.rewrite_path(kind, value) {
+            Ok(result) => result,
+            Err(err) => {
+                error.get_or_insert(err);
+                None
+            }
+        };
+
+        let mut args = args.to_vec();
+        for_each_path(flavor, &mut args, &mut rewrite);
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(args),
+        }
+    }
+
+    /// Copy the file(s) `value` of `kind` refers to and return the path that should
+    /// replace `value`, if any.
+    fn rewrite_path(&mut self, kind: PathKind, value: &str) -> Result<Option<String>> {
+        let path = self.search.cwd.join(value);
+
+        Ok(match kind {
+            PathKind::Output | PathKind::Map => Path::new(value)
+                .file_name()
+                .map(|name| Path::new(OUT_DIR).join(name).to_string_lossy().into_owned()),
+            PathKind::LibDir if path.is_dir() => {
+                // Linker scripts can include other scripts from the search directories,
+                // so copy all of them.
+                for script in linker_scripts(&path)? {
+                    self.copy(&script)?;
+                }
+
+                let copy = self.mirror(&path);
+                fs::create_dir_all(self.root.join(&copy))?;
+                Some(copy.to_string_lossy().into_owned())
+            }
+            PathKind::Lib => {
+                if let Some(lib) = self.search.find_lib(value) {
+                    let copy = self.copy(&lib)?;
+
+                    // Absolute paths (`-l:/path/libfoo.a`) must point to the copy.
+                    if Path::new(value.trim_start_matches(':')).is_absolute() {
+                        let copy = copy.to_string_lossy().into_owned();
+                        Some(if value.starts_with(':') {
+                            format!(":{}", copy)
+                        } else {
+                            copy
+                        })
+                    } else {
+                        None
+                    }
+                } else {
+                    warn!("Could not find library '{}' for the reproducer", value);
+                    None
+                }
+            }
+            PathKind::Script | PathKind::Input if path.is_file() => {
+                Some(self.copy(&path)?.to_string_lossy().into_owned())
+            }
+            PathKind::Script => {
+                // The script is found in one of the search directories which are
+                // copied already.
+                if let Some(script) = self.search.find_in_lib_dirs(value) {
+                    self.copy(&script)?;
+                } else {
+                    warn!(
+                        "Could not find linker script '{}' for the reproducer",
+                        value
+                    );
+                }
+                None
+            }
+            PathKind::LibDir | PathKind::Input => None,
+        })
+    }
+
+    /// Copy `file` into the reproducer and return the path of the copy relative to
+    /// the reproducer root.
+    fn copy(&mut self, file: &Path) -> Result<PathBuf> {
+        let copy = self.mirror(file);
+
+        if self.copied.insert(file.to_owned()) {
+            let dest = self.root.join(&copy);
+            fs::create_dir_all(dest.parent().unwrap())?;
+            fs::copy(file, &dest).with_context(|| {
+                format!(
+                    "Could not copy '{}' to '{}'",
+                    file.display(),
+                    dest.display()
+                )
+            })?;
+        }
+
+        Ok(copy)
+    }
+
+    /// Get the path relative to the reproducer root that mirrors the absolute `path`.
+    fn mirror(&self, path: &Path) -> PathBuf {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+
+        let mut result = PathBuf::from(FILES_DIR);
+        for component in path.components() {
+            match component {
+                Component::Prefix(prefix) => result.push(
+                    prefix
+                        .as_os_str()
+                        .to_string_lossy()
+                        .chars()
+                        .filter(char::is_ascii_alphanumeric)
+                        .collect::<String>(),
+                ),
+                Component::Normal(c) => result.push(c),
+                Component::ParentDir => {
+                    result.pop();
+                }
+                Component::RootDir | Component::CurDir => {}
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(file: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(file)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(file, permissions)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_file: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn reproducer() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().to_owned();
+        let project = dir.join("project");
+        fs::create_dir_all(project.join("lib")).unwrap();
+        fs::write(project.join("main.o"), "main").unwrap();
+        fs::write(project.join("sections.ld"), "INCLUDE memory.x").unwrap();
+        fs::write(project.join("lib/libfoo.a"), "foo").unwrap();
+        fs::write(project.join("lib/memory.x"), "MEMORY {}").unwrap();
+        fs::write(project.join("lib/other.ld"), "other").unwrap();
+
+        let args = [
+            "main.o",
+            "-Llib",
+            "-lfoo",
+            "-Tsections.ld",
+            "-T",
+            "memory.x",
+            "-o",
+            "build/app.elf",
+        ]
+        .map(str::to_owned);
+        let raw_args = ["@link.rsp".to_owned()];
+
+        let root = write(
+            dir.join("reproducers"),
+            "/opt/tool chain/ld",
+            LinkerFlavor::Ld,
+            Some(project.to_str().unwrap()),
+            &args,
+            &raw_args,
+        )
+        .unwrap();
+        assert_eq!(root, dir.join("reproducers").join("app"));
+
+        let copied = fs::read_to_string(root.join(ARGS_FILE)).unwrap();
+        let copied = LinkerFlavor::Ld.parse_response_file(&copied);
+        assert_eq!(copied.len(), args.len());
+
+        let file = |arg: &str| {
+            assert!(Path::new(arg).starts_with(FILES_DIR), "{}", arg);
+            root.join(arg)
+        };
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+
+        assert_eq!(read(file(&copied[0])), "main");
+        let lib_dir = file(copied[1].strip_prefix("-L").unwrap());
+        assert_eq!(read(lib_dir.join("libfoo.a")), "foo");
+        assert_eq!(read(lib_dir.join("memory.x")), "MEMORY {}");
+        assert_eq!(read(lib_dir.join("other.ld")), "other");
+        assert_eq!(copied[2], "-lfoo");
+        assert_eq!(
+            read(file(copied[3].strip_prefix("-T").unwrap())),
+            "INCLUDE memory.x"
+        );
+        assert_eq!(copied[4..6], ["-T", "memory.x"]);
+        assert_eq!(
+            copied[6..],
+            [
+                "-o".to_owned(),
+                Path::new(OUT_DIR).join("app.elf").display().to_string()
+            ]
+        );
+        assert!(root.join(OUT_DIR).is_dir());
+
+        assert_eq!(read(root.join(ORIGINAL_ARGS_FILE)), "@link.rsp");
+        assert!(
+            read(root.join(SCRIPT_FILE)).contains(r#"exec "/opt/tool chain/ld" @args.txt "$@""#)
+        );
+    }
+}
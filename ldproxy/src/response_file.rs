@@ -0,0 +1,97 @@
+//! Expansion of response files (`@<file>`).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use embuild::build::LinkerFlavor;
+use log::*;
+
+/// How deep response files can be nested.
+const MAX_DEPTH: usize = 32;
+
+/// Replace every `@<file>` argument in `args` with the arguments in `<file>` parsed for
+/// `flavor`, including `@<file>` arguments in response files.
+///
+/// Arguments of response files that don't exist are kept as they are, like gcc does (see
+/// the `@file` section of
+/// <https://gcc.gnu.org/onlinedocs/gcc-11.2.0/gcc/Overall-Options.html>).
+pub fn expand(flavor: LinkerFlavor, args: impl IntoIterator<Item = String>) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+    expand_into(flavor, args, &mut Vec::new(), &mut result)?;
+
+    Ok(result)
+}
+
+fn expand_into(
+    flavor: LinkerFlavor,
+    args: impl IntoIterator<Item = String>,
+    stack: &mut Vec<PathBuf>,
+    result: &mut Vec<String>,
+) -> Result<()> {
+    for arg in args {
+        let rsp_file = match arg.strip_prefix('@') {
+            Some(rsp_file) if Path::new(rsp_file).is_file() => Path::new(rsp_file),
+            _ => {
+                result.push(arg);
+                continue;
+            }
+        };
+
+        let canonical = rsp_file
+            .canonicalize()
+            .unwrap_or_else(|_| rsp_file.to_owned());
+        if stack.contains(&canonical) {
+            bail!("Response file '{}' includes itself", rsp_file.display());
+        }
+        if stack.len() >= MAX_DEPTH {
+            bail!(
+                "Response files are nested deeper than {} levels at '{}'",
+                MAX_DEPTH,
+                rsp_file.display()
+            );
+        }
+
+        let contents = fs::read_to_string(rsp_file)
+            .with_context(|| anyhow!("Could not read response file '{}'", rsp_file.display()))?;
+        debug!("Contents of {}: {}", rsp_file.display(), contents);
+
+        stack.push(canonical);
+        expand_into(flavor, flavor.parse_response_file(&contents), stack, result)?;
+        stack.pop();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn nested() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+
+        let inner = dir.join("inner.rsp");
+        let outer = dir.join("outer.rsp");
+        let cycle = dir.join("cycle.rsp");
+        fs::write(&inner, "-lc 'a b.o'").unwrap();
+        fs::write(&outer, format!("main.o @{} -o out", inner.display())).unwrap();
+        fs::write(&cycle, format!("@{}", cycle.display())).unwrap();
+
+        let args = expand(
+            LinkerFlavor::Gcc,
+            vec![format!("@{}", outer.display()), "@missing.rsp".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(
+            args,
+            ["main.o", "-lc", "a b.o", "-o", "out", "@missing.rsp"]
+        );
+
+        assert!(expand(LinkerFlavor::Gcc, vec![format!("@{}", cycle.display())]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Rules that rewrite the linker arguments, loaded from TOML files.
+//!
+//! A rules file contains a list of `[[rule]]` tables, which are applied in order:
+//!
+//! ```toml
+//! [[rule]]
+//! action = "remove"
+//! match = "-Wl,--as-needed"
+//!
+//! [[rule]]
+//! action = "replace"
+//! regex = "^-Wl,-z,(.*)$"
+//! args = ["-z", "$1"]
+//!
+//! [[rule]]
+//! action = "insert-before"
+//! match = "-lc"
+//! args = ["-lm"]
+//! ```
+//!
+//! Every rule matches arguments either exactly with `match` or with the regular
+//! expression `regex`. The `args` of `replace` and `insert-before` rules can refer to
+//! capture groups of `regex` (`$1`, `${name}`).
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Action {
+    /// Remove the matching argument.
+    Remove,
+    /// Replace the matching argument with `args`.
+    Replace,
+    /// Insert `args` before the matching argument.
+    InsertBefore,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDef {
+    action: Action,
+    #[serde(rename = "match")]
+    exact: Option<String>,
+    regex: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleDef>,
+}
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// A single rewrite rule.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    action: Action,
+    matcher: Matcher,
+    args: Vec<String>,
+}
+
+impl Rule {
+    fn try_from_def(def: RuleDef) -> Result<Self> {
+        let matcher = match (def.exact, def.regex) {
+            (Some(exact), None) => Matcher::Exact(exact),
+            (None, Some(regex)) => Matcher::Regex(
+                Regex::new(&regex).with_context(|| anyhow!("Invalid regex '{}'", regex))?,
+            ),
+            _ => bail!("Rule must have exactly one of `match` or `regex`"),
+        };
+
+        if def.action == Action::InsertBefore && def.args.is_empty() {
+            bail!("Rule with action `insert-before` must have `args`");
+        }
+
+        Ok(Self {
+            action: def.action,
+            matcher,
+            args: def.args,
+        })
+    }
+
+    /// Get the arguments this rule produces for `arg` if it matches.
+    fn expand(&self, arg: &str) -> Option<Vec<String>> {
+        match &self.matcher {
+            Matcher::Exact(exact) if exact == arg => Some(self.args.clone()),
+            Matcher::Regex(regex) => regex.captures(arg).map(|captures| {
+                self.args
+                    .iter()
+                    .map(|template| {
+                        let mut result = String::new();
+                        captures.expand(template, &mut result);
+                        result
+                    })
+                    .collect()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Apply this rule to all `args`.
+    fn apply(&self, args: Vec<String>) -> Vec<String> {
+        let mut result = Vec::with_capacity(args.len());
+
+        for arg in args {
+            match (self.action, self.expand(&arg)) {
+                (Action::Remove, Some(_)) => {}
+                (Action::Replace, Some(new_args)) => result.extend(new_args),
+                (Action::InsertBefore, Some(new_args)) => {
+                    result.extend(new_args);
+                    result.push(arg);
+                }
+                (_, None) => result.push(arg),
+            }
+        }
+
+        result
+    }
+}
+
+/// Load all rules from the TOML file `path`.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<Rule>> {
+    let path = path.as_ref();
+
+    let contents = fs::read_to_string(path)
+        .with_context(|| anyhow!("Could not read rewrite rules '{}'", path.display()))?;
+    let file: RulesFile = toml::from_str(&contents)
+        .with_context(|| anyhow!("Could not parse rewrite rules '{}'", path.display()))?;
+
+    file.rule
+        .into_iter()
+        .enumerate()
+        .map(|(i, def)| {
+            Rule::try_from_def(def)
+                .with_context(|| anyhow!("Invalid rule #{} in '{}'", i + 1, path.display()))
+        })
+        .collect()
+}
+
+/// Apply all `rules` in order to `args`.
+pub fn apply<'a>(rules: impl IntoIterator<Item = &'a Rule>, args: Vec<String>) -> Vec<String> {
+    rules.into_iter().fold(args, |args, rule| rule.apply(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_rules() {
+        let file: RulesFile = toml::from_str(
+            r#"
+            [[rule]]
+            action = "remove"
+            match = "-Wl,--as-needed"
+
+            [[rule]]
+            action = "replace"
+            regex = "^-Wl,-z,(.*)$"
+            args = ["-z", "$1"]
+
+            [[rule]]
+            action = "replace"
+            match = "-nodefaultlibs"
+
+            [[rule]]
+            action = "insert-before"
+            match = "-lc"
+            args = ["-lm"]
+            "#,
+        )
+        .unwrap();
+        let rules = file
+            .rule
+            .into_iter()
+            .map(Rule::try_from_def)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let args = [
+            "main.o",
+            "-Wl,--as-needed",
+            "-Wl,-z,noexecstack",
+            "-nodefaultlibs",
+            "-lc",
+        ]
+        .iter()
+        .map(|&s| s.to_owned())
+        .collect();
+
+        assert_eq!(
+            apply(&rules, args),
+            ["main.o", "-z", "noexecstack", "-lm", "-lc"]
+        );
+    }
+
+    #[test]
+    fn invalid_rule() {
+        let def = RuleDef {
+            action: Action::Remove,
+            exact: Some("-a".into()),
+            regex: Some("-b".into()),
+            args: vec![],
+        };
+
+        assert!(Rule::try_from_def(def).is_err());
+    }
+}
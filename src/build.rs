@@ -4,9 +4,24 @@ use std::path::{Path, PathBuf};
 use std::{env, vec};
 
 use crate::cargo::{self, add_link_arg, print_warning, set_metadata, track_file};
-use crate::cli::{self, Arg, ArgDef};
+use crate::cli::{self, Arg, ArgDef, ArgOpts};
 use crate::utils::OsStrExt;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+
+mod compile_commands;
+mod encoding;
+mod flavor;
+mod metadata;
+mod options;
+#[cfg(feature = "glob")]
+mod tracker;
+
+pub use compile_commands::*;
+pub use flavor::*;
+pub use metadata::*;
+pub use options::*;
+#[cfg(feature = "glob")]
+pub use tracker::*;
 
 const VAR_C_INCLUDE_ARGS: &str = "EMBUILD_C_INCLUDE_ARGS";
 const VAR_LINK_ARGS: &str = "EMBUILD_LINK_ARGS";
@@ -18,20 +33,46 @@ pub const LDPROXY_NAME: &str = "ldproxy";
 
 pub const LDPROXY_LINKER_ARG: ArgDef = Arg::option("ldproxy-linker").long();
 pub const LDPROXY_DEDUP_LIBS_ARG: ArgDef = Arg::flag("ldproxy-dedup-libs").long();
+pub const LDPROXY_GROUP_LIBS_ARG: ArgDef = Arg::flag("ldproxy-group-libs").long();
 pub const LDPROXY_WORKING_DIRECTORY_ARG: ArgDef = Arg::option("ldproxy-cwd").long();
-
+pub const LDPROXY_LINKER_FLAVOR_ARG: ArgDef = Arg::option("ldproxy-linker-flavor").long();
+pub const LDPROXY_REWRITE_RULES_ARG: ArgDef = Arg::option("ldproxy-rewrite-rules").long();
+pub const LDPROXY_REPRODUCER_ARG: ArgDef = Arg::option("ldproxy-reproducer").long();
+pub const LDPROXY_MEMORY_REPORT_ARG: ArgDef = Arg::option("ldproxy-memory-report").with_opts(
+    ArgOpts::DOUBLE_HYPHEN
+        .union(ArgOpts::VALUE_SEP_EQUALS)
+        .union(ArgOpts::VALUE_OPTIONAL),
+);
+pub const LDPROXY_MEMORY_REPORT_FILE_ARG: ArgDef = Arg::option("ldproxy-memory-report-file").long();
+pub const LDPROXY_DIAGNOSTICS_ARG: ArgDef = Arg::flag("ldproxy-diagnostics").long();
+pub const LDPROXY_DIAGNOSTICS_FILE_ARG: ArgDef = Arg::option("ldproxy-diagnostics-file").long();
+pub const LDPROXY_CACHE_ARG: ArgDef = Arg::option("ldproxy-cache").long();
+pub const LDPROXY_CACHE_MAX_SIZE_ARG: ArgDef = Arg::option("ldproxy-cache-max-size").long();
+
+/// Get all `<name>=<value>` pairs from the values of the environment variables starting
+/// with `<env_var_prefix>_`.
+///
+/// Fails if any of these values doesn't contain a `=`, everything after the first `=` is
+/// the value.
+#[deprecated(note = "use `OptionsLoader` to load typed options")]
 pub fn env_options_iter(
     env_var_prefix: impl AsRef<str>,
 ) -> Result<impl Iterator<Item = (String, String)>> {
     let env_var_prefix = env_var_prefix.as_ref().to_owned() + "_";
 
-    Ok(env::vars()
-        .filter(move |(key, _)| key.starts_with(&env_var_prefix))
-        .map(|(_, value)| {
-            let split = value.split('=').collect::<vec::Vec<_>>();
+    let options = env::vars()
+        .filter(|(key, _)| key.starts_with(&env_var_prefix))
+        .map(|(key, value)| match value.split_once('=') {
+            Some((name, value)) => Ok((name.trim().to_owned(), value.trim().to_owned())),
+            None => bail!(
+                "Invalid value '{}' of environment variable {}, expected `<name>=<value>`",
+                value,
+                key
+            ),
+        })
+        .collect::<Result<vec::Vec<_>>>()?;
 
-            (split[0].trim().to_owned(), split[1].trim().to_owned())
-        }))
+    Ok(options.into_iter())
 }
 
 #[cfg(feature = "glob")]
@@ -120,17 +161,68 @@ pub struct CInclArgs {
 
 impl CInclArgs {
     pub fn try_from_env(lib_name: impl AsRef<str>) -> Result<Self> {
-        Ok(Self {
-            args: env::var(format!(
-                "DEP_{}_{}",
-                lib_name.as_ref().to_uppercase(),
-                VAR_C_INCLUDE_ARGS,
-            ))?,
-        })
+        let value = env::var(format!(
+            "DEP_{}_{}",
+            lib_name.as_ref().to_uppercase(),
+            VAR_C_INCLUDE_ARGS,
+        ))?;
+
+        match encoding::decode(&value, |v| vec![v.to_owned()])?.as_slice() {
+            [args] => Ok(Self { args: args.clone() }),
+            _ => bail!("Invalid propagated C include args '{}'", value),
+        }
     }
 
     pub fn propagate(&self) {
-        set_metadata(VAR_C_INCLUDE_ARGS, self.args.as_str());
+        set_metadata(VAR_C_INCLUDE_ARGS, encoding::encode([self.args.as_str()]));
+    }
+}
+
+/// When the linker arguments passed to `ldproxy` are written to a response file
+/// (`@<file>`) instead of passing them on the command line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResponseFilePolicy {
+    /// Always pass the arguments on the command line.
+    Never,
+    /// Always use a response file.
+    Always,
+    /// Use a response file if the arguments, formatted for the linker flavor, are longer
+    /// than the given number of bytes.
+    Threshold(usize),
+}
+
+impl ResponseFilePolicy {
+    /// The threshold of the default policy on non-Windows hosts and for unknown linker
+    /// flavors.
+    pub const DEFAULT_THRESHOLD: usize = 32 * 1024;
+
+    /// The default policy for a linker of `flavor`.
+    ///
+    /// On Windows always use a response file to circumvent the command-line length
+    /// limitation, unless the flavor is unknown and the response file could be in the
+    /// wrong format. Otherwise use [`Self::DEFAULT_THRESHOLD`].
+    pub fn default_for(flavor: Option<LinkerFlavor>) -> Self {
+        if cfg!(windows) && flavor.is_some() {
+            Self::Always
+        } else {
+            Self::Threshold(Self::DEFAULT_THRESHOLD)
+        }
+    }
+
+    /// Whether arguments of `len` bytes should be written to a response file.
+    pub fn applies(self, len: usize) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            Self::Threshold(threshold) => len > threshold,
+        }
+    }
+}
+
+impl Default for ResponseFilePolicy {
+    /// The default policy for an unknown linker flavor, see [`Self::default_for`].
+    fn default() -> Self {
+        Self::default_for(None)
     }
 }
 
@@ -146,6 +238,16 @@ pub struct LinkArgsBuilder {
     /// The working directory that should be set when linking.
     pub(crate) working_directory: Option<PathBuf>,
     pub(crate) dedup_libs: bool,
+    /// Whether repeated libraries should be collapsed into groups (see
+    /// [`LinkerFlavor::group_libs`]), takes precedence over `dedup_libs`.
+    pub(crate) group_libs: bool,
+    /// TOML files with rules that rewrite the linker arguments in `ldproxy`.
+    pub(crate) rewrite_rules: Vec<PathBuf>,
+    /// The flavor of the linker, detected from [`linker`](Self::linker) if not set.
+    pub(crate) linker_flavor: Option<LinkerFlavor>,
+    /// When the arguments for `ldproxy` are written to a response file, depends on the
+    /// linker flavor if not set (see [`ResponseFilePolicy::default_for`]).
+    pub(crate) response_file: Option<ResponseFilePolicy>,
 }
 
 impl LinkArgsBuilder {
@@ -169,7 +271,36 @@ impl LinkArgsBuilder {
         self
     }
 
+    pub fn group_libs(mut self, group: bool) -> Self {
+        self.group_libs = group;
+        self
+    }
+
+    /// Add a TOML file with rules that `ldproxy` uses to rewrite the linker arguments.
+    pub fn rewrite_rules(mut self, rules_file: impl Into<PathBuf>) -> Self {
+        self.rewrite_rules.push(rules_file.into());
+        self
+    }
+
+    pub fn linker_flavor(mut self, flavor: LinkerFlavor) -> Self {
+        self.linker_flavor = Some(flavor);
+        self
+    }
+
+    pub fn response_file(mut self, policy: ResponseFilePolicy) -> Self {
+        self.response_file = Some(policy);
+        self
+    }
+
+    /// Get the explicitly set linker flavor or try to detect it from the linker path.
+    pub fn get_linker_flavor(&self) -> Option<LinkerFlavor> {
+        self.linker_flavor
+            .or_else(|| self.linker.as_ref().and_then(LinkerFlavor::detect))
+    }
+
     pub fn build(self) -> Result<LinkArgs> {
+        let flavor = self.get_linker_flavor();
+
         let args: Vec<_> = self
             .libdirflags
             .into_iter()
@@ -202,7 +333,9 @@ impl LinkArgsBuilder {
                 result.extend(LDPROXY_LINKER_ARG.format(Some(linker.try_to_str()?)));
             }
 
-            if self.dedup_libs {
+            if self.group_libs {
+                result.extend(LDPROXY_GROUP_LIBS_ARG.format(None));
+            } else if self.dedup_libs {
                 result.extend(LDPROXY_DEDUP_LIBS_ARG.format(None));
             }
 
@@ -210,35 +343,35 @@ impl LinkArgsBuilder {
                 result.extend(LDPROXY_WORKING_DIRECTORY_ARG.format(Some(cwd.try_to_str()?)))
             }
 
-            // If `windows && gcc` we always use reponse files to circumvent the command-line
-            // length limitation.
-            // TODO: implement other linkers
-            if cfg!(windows) {
-                // TODO: add way to detect linker flavor
-                let is_gcc = self
-                    .linker
-                    .and_then(|l| {
-                        l.file_stem()
-                            .and_then(OsStr::to_str)
-                            .map(|s| s.ends_with("gcc"))
-                    })
-                    .unwrap_or(false);
-
-                if is_gcc {
-                    let link_args_file = cargo::out_dir().join(LINK_ARGS_FILE_NAME);
-                    let args = cli::join_unix_args(args.iter().map(|s| s.as_str()));
-
-                    std::fs::write(&link_args_file, args).with_context(|| {
-                        anyhow!(
-                            "could not write link args to file '{}'",
-                            link_args_file.display()
-                        )
-                    })?;
-
-                    result.push(format!("@{}", link_args_file.try_to_str()?));
-                } else {
-                    result.extend(args);
-                }
+            for rules_file in &self.rewrite_rules {
+                result.extend(LDPROXY_REWRITE_RULES_ARG.format(Some(rules_file.try_to_str()?)));
+            }
+
+            if let Some(flavor) = flavor {
+                result.extend(LDPROXY_LINKER_FLAVOR_ARG.format(Some(flavor.into())));
+            }
+
+            // `ldproxy` parses the response file with the same flavor (and also falls
+            // back to gcc if it is unknown).
+            let rsp_args = flavor
+                .unwrap_or_default()
+                .format_response_file(args.iter().map(|s| s.as_str()));
+
+            let policy = self
+                .response_file
+                .unwrap_or_else(|| ResponseFilePolicy::default_for(flavor));
+
+            if policy.applies(rsp_args.len()) {
+                let link_args_file = cargo::out_dir().join(LINK_ARGS_FILE_NAME);
+
+                std::fs::write(&link_args_file, rsp_args).with_context(|| {
+                    anyhow!(
+                        "could not write link args to file '{}'",
+                        link_args_file.display()
+                    )
+                })?;
+
+                result.push(format!("@{}", link_args_file.try_to_str()?));
             } else {
                 result.extend(args);
             }
@@ -264,9 +397,8 @@ impl LinkArgs {
     /// dependency's `links` property value, which is specified in its package manifest
     /// (`Cargo.toml`).
     pub fn try_from_env(lib_name: impl Display) -> Result<Self> {
-        let args =
-            cli::UnixCommandArgs::new(&env::var(format!("DEP_{}_{}", lib_name, VAR_LINK_ARGS))?)
-                .collect();
+        let value = env::var(format!("DEP_{}_{}", lib_name, VAR_LINK_ARGS))?;
+        let args = encoding::decode(&value, |v| cli::UnixCommandArgs::new(v).collect())?;
 
         Ok(Self { args })
     }
@@ -286,10 +418,9 @@ impl LinkArgs {
     /// [`LinkArgs::output_propagated`] in their build script with the value of this
     /// crate's `links` property (specified in `Cargo.toml`).
     pub fn propagate(&self) {
-        // TODO: maybe more efficient escape machanism
         set_metadata(
             VAR_LINK_ARGS,
-            cli::join_unix_args(self.args.iter().map(|s| s.as_str())),
+            encoding::encode(self.args.iter().map(|s| s.as_str())),
         );
     }
 
@@ -312,15 +443,14 @@ pub struct CfgArgs {
 
 impl CfgArgs {
     /// Load options from `lib_name` which have been propagated using [`propagate`](CfgArgs::propagate).
+    /// Options propagated in the legacy `:`-separated format are read as well.
     ///
     /// `lib_name` doesn't refer to a crate, library or package name, it refers to a
     /// dependency's `links` property value, which is specified in its package manifest
     /// (`Cargo.toml`).
     pub fn try_from_env(lib_name: impl Display) -> Result<Self> {
-        let args = env::var(format!("DEP_{}_{}", lib_name, VAR_CFG_ARGS))?
-            .split(':') // TODO: Un-escape
-            .map(Into::into)
-            .collect();
+        let value = env::var(format!("DEP_{}_{}", lib_name, VAR_CFG_ARGS))?;
+        let args = encoding::decode(&value, |v| v.split(':').map(Into::into).collect())?;
 
         Ok(Self { args })
     }
@@ -353,6 +483,15 @@ impl CfgArgs {
         }
     }
 
+    /// Declare all configuration options as expected cfgs with `rustc-check-cfg`, so
+    /// that rustc doesn't warn about them.
+    ///
+    /// Use [`kconfig::CheckCfg`](crate::kconfig::CheckCfg) to also declare all options
+    /// that are not set but are known from the `Kconfig` sources.
+    pub fn output_check_cfg(&self) -> Result<()> {
+        crate::kconfig::CheckCfg::new().cfg_args(self).emit()
+    }
+
     /// Propagate all configuration options to all dependents of this crate.
     ///
     /// ### **Important**
@@ -361,7 +500,10 @@ impl CfgArgs {
     /// [`CfgArgs::output_propagated`] in their build script with the value of this
     /// crate's `links` property (specified in `Cargo.toml`).
     pub fn propagate(&self) {
-        cargo::set_metadata(VAR_CFG_ARGS, self.args.join(":")); // TODO: Escape
+        cargo::set_metadata(
+            VAR_CFG_ARGS,
+            encoding::encode(self.args.iter().map(|s| s.as_str())),
+        );
     }
 
     /// Add options from `lib_name` which have been propagated using [`propagate`](CfgArgs::propagate).
@@ -373,3 +515,28 @@ impl CfgArgs {
         Self::try_from_env(lib_name).map(|args| args.output())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_file_policy() {
+        assert!(!ResponseFilePolicy::Never.applies(usize::MAX));
+        assert!(ResponseFilePolicy::Always.applies(0));
+        assert!(!ResponseFilePolicy::Threshold(10).applies(10));
+        assert!(ResponseFilePolicy::Threshold(10).applies(11));
+
+        let threshold = ResponseFilePolicy::Threshold(ResponseFilePolicy::DEFAULT_THRESHOLD);
+        assert_eq!(ResponseFilePolicy::default_for(None), threshold);
+        assert_eq!(ResponseFilePolicy::default(), threshold);
+        assert_eq!(
+            ResponseFilePolicy::default_for(Some(LinkerFlavor::Gcc)),
+            if cfg!(windows) {
+                ResponseFilePolicy::Always
+            } else {
+                threshold
+            }
+        );
+    }
+}
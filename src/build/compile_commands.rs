@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::CInclArgs;
+use crate::cli::NativeCommandArgs;
+
+pub const COMPILE_COMMANDS_FILE_NAME: &str = "compile_commands.json";
+
+/// An entry of a [JSON compilation
+/// database](https://clang.llvm.org/docs/JSONCompilationDatabase.html).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompileCommand {
+    /// The working directory of the compilation.
+    pub directory: PathBuf,
+    /// The source file of the compilation.
+    pub file: PathBuf,
+    /// The compile command, starting with the compiler executable.
+    pub arguments: Vec<String>,
+}
+
+/// A JSON compilation database (`compile_commands.json`) which can be used by editors
+/// and tools like clangd.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CompileCommands {
+    pub commands: Vec<CompileCommand>,
+}
+
+impl CompileCommands {
+    /// Add all commands of `other`, replacing the commands of this database for the
+    /// same files.
+    pub fn merge(&mut self, other: CompileCommands) {
+        self.commands
+            .retain(|c| !other.commands.iter().any(|o| o.file == c.file));
+        self.commands.extend(other.commands);
+    }
+
+    /// Read a compilation database from `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let data = fs::read(path)
+            .with_context(|| anyhow!("Could not read compilation database '{}'", path.display()))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write this compilation database to `path`.
+    ///
+    /// If `path` is a directory, the database is written to a `compile_commands.json`
+    /// file in that directory.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+        let path = if path.is_dir() {
+            path.join(COMPILE_COMMANDS_FILE_NAME)
+        } else {
+            path.to_owned()
+        };
+
+        fs::write(&path, serde_json::to_string_pretty(self)?).with_context(|| {
+            anyhow!("Could not write compilation database '{}'", path.display())
+        })?;
+
+        Ok(path)
+    }
+}
+
+/// A builder for the [`CompileCommands`] of sources compiled with the same arguments.
+///
+/// Builders can be created from [`CInclArgs`], cmake `CompileGroup`s and
+/// `SconsVariables`.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct CompileCommandsBuilder {
+    /// The compiler arguments, without the compiler and the source file.
+    pub(crate) args: Vec<String>,
+    /// The path to the compiler, `cc` if not set.
+    pub(crate) compiler: Option<PathBuf>,
+    /// The working directory of the compilation, the current directory if not set.
+    pub(crate) directory: Option<PathBuf>,
+    pub(crate) sources: Vec<PathBuf>,
+    /// Headers that bindgen generates bindings for.
+    pub(crate) bindgen_headers: Vec<PathBuf>,
+}
+
+impl CompileCommandsBuilder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn compiler(mut self, compiler: impl Into<PathBuf>) -> Self {
+        self.compiler = Some(compiler.into());
+        self
+    }
+
+    pub fn directory(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.directory = Some(dir.into());
+        self
+    }
+
+    /// Add compiler arguments.
+    pub fn args<S>(mut self, args: impl IntoIterator<Item = S>) -> Self
+    where
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add source files compiled with the arguments of this builder.
+    pub fn sources<P>(mut self, sources: impl IntoIterator<Item = P>) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.sources.extend(sources.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a header wrapper that bindgen generates bindings for.
+    ///
+    /// The header is compiled as a C header with the `__bindgen` define, like bindgen
+    /// does.
+    pub fn bindgen_header(mut self, header: impl Into<PathBuf>) -> Self {
+        self.bindgen_headers.push(header.into());
+        self
+    }
+
+    pub fn build(self) -> Result<CompileCommands> {
+        let directory = match self.directory {
+            Some(dir) => dir,
+            None => env::current_dir()?,
+        };
+        let compiler = self
+            .compiler
+            .map(|c| c.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "cc".to_owned());
+
+        let command = |file: &Path, extra_args: &[&str]| {
+            let file = directory.join(file);
+
+            let arguments = std::iter::once(compiler.clone())
+                .chain(self.args.iter().cloned())
+                .chain(extra_args.iter().map(|&a| a.to_owned()))
+                .chain(["-c".to_owned(), file.to_string_lossy().into_owned()])
+                .collect();
+
+            CompileCommand {
+                directory: directory.clone(),
+                file,
+                arguments,
+            }
+        };
+
+        let commands = self
+            .sources
+            .iter()
+            .map(|source| command(source, &[]))
+            .chain(
+                self.bindgen_headers
+                    .iter()
+                    .map(|header| command(header, &["-D__bindgen", "-x", "c-header"])),
+            )
+            .collect();
+
+        Ok(CompileCommands { commands })
+    }
+}
+
+impl From<&CInclArgs> for CompileCommandsBuilder {
+    fn from(args: &CInclArgs) -> Self {
+        Self::new().args(NativeCommandArgs::new(&args.args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_c_incl_args() {
+        let incl_args = CInclArgs {
+            args: "-DFOO=1 \"-I/opt/esp idf/include\"".to_owned(),
+        };
+
+        let commands = CompileCommandsBuilder::from(&incl_args)
+            .compiler("/opt/bin/gcc")
+            .directory("/src")
+            .sources(["main.c"])
+            .bindgen_header("/out/bindings.h")
+            .build()
+            .unwrap();
+
+        assert_eq!(commands.commands.len(), 2);
+        assert_eq!(commands.commands[0].file, Path::new("/src/main.c"));
+        assert_eq!(
+            commands.commands[0].arguments,
+            [
+                "/opt/bin/gcc",
+                "-DFOO=1",
+                "-I/opt/esp idf/include",
+                "-c",
+                "/src/main.c"
+            ]
+        );
+        assert_eq!(commands.commands[1].file, Path::new("/out/bindings.h"));
+        assert!(commands.commands[1]
+            .arguments
+            .contains(&"c-header".to_owned()));
+
+        let mut merged = commands.clone();
+        merged.merge(commands);
+        assert_eq!(merged.commands.len(), 2);
+    }
+}
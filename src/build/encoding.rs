@@ -0,0 +1,123 @@
+//! The encoding of argument lists propagated as cargo metadata.
+//!
+//! Metadata values are passed to the build scripts of dependents as `DEP_*` environment
+//! variables, and cannot contain newlines. The versioned encoding starts with
+//! [`V1_PREFIX`], which cannot start a value of the legacy formats: `%` is always quoted
+//! by [`cli::join_unix_args`](crate::cli::join_unix_args) and cannot start a `cfg`
+//! option or a C compiler argument. Every argument is then percent-encoded and
+//! terminated by `:`, so that any list of strings, including empty strings and strings
+//! containing `:`, newlines or other control characters, survives the round trip.
+
+use anyhow::{bail, Result};
+
+/// The prefix of values in the version 1 encoding.
+pub const V1_PREFIX: &str = "%1:";
+
+/// Encode `args` in the latest version of the encoding.
+pub fn encode<'a>(args: impl IntoIterator<Item = &'a str>) -> String {
+    let mut result = V1_PREFIX.to_owned();
+
+    for arg in args {
+        for c in arg.chars() {
+            if c == '%' || c == ':' || c.is_ascii_control() {
+                result.push_str(&format!("%{:02X}", c as u8));
+            } else {
+                result.push(c);
+            }
+        }
+        result.push(':');
+    }
+
+    result
+}
+
+/// Decode `value`, which is either in a versioned encoding or in the legacy format
+/// which is parsed by `legacy`.
+pub fn decode(value: &str, legacy: impl FnOnce(&str) -> Vec<String>) -> Result<Vec<String>> {
+    let encoded = match value.strip_prefix(V1_PREFIX) {
+        Some(encoded) => encoded,
+        None if value.starts_with('%') => {
+            bail!("Unsupported encoding of propagated metadata '{}'", value)
+        }
+        None => return Ok(legacy(value)),
+    };
+
+    let mut args = encoded.split(':').collect::<Vec<_>>();
+    if args.pop() != Some("") {
+        bail!("Propagated metadata '{}' is truncated", value);
+    }
+
+    args.into_iter().map(|arg| unescape(arg, value)).collect()
+}
+
+fn unescape(arg: &str, value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(arg.len());
+    let mut rest = arg.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            match byte {
+                Some(byte) => bytes.push(byte),
+                None => bail!("Invalid escape sequence in propagated metadata '{}'", value),
+            }
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(arg) => Ok(arg),
+        Err(_) => bail!("Propagated metadata '{}' is not valid UTF-8", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::quickcheck;
+
+    use super::*;
+
+    fn no_legacy(_: &str) -> Vec<String> {
+        panic!("Decoded as legacy format")
+    }
+
+    #[test]
+    fn encode_special() {
+        let args = ["", "a:b", "100%", "line\nbreak", "ü:\u{0}"];
+        let encoded = encode(args.iter().copied());
+
+        assert_eq!(encoded, "%1::a%3Ab:100%25:line%0Abreak:ü%3A%00:");
+        assert!(!encoded.contains('\n'));
+        assert_eq!(decode(&encoded, no_legacy).unwrap(), args);
+        assert_eq!(decode("%1:", no_legacy).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn decode_invalid() {
+        assert!(decode("%1:a", no_legacy).is_err());
+        assert!(decode("%1:a%2:", no_legacy).is_err());
+        assert!(decode("%1:%FF:", no_legacy).is_err());
+        assert!(decode("%2:a:", no_legacy).is_err());
+    }
+
+    #[test]
+    fn decode_legacy() {
+        let args = decode("a:b", |v| v.split(':').map(Into::into).collect()).unwrap();
+        assert_eq!(args, ["a", "b"]);
+    }
+
+    quickcheck! {
+        fn roundtrip(args: Vec<String>) -> bool {
+            let encoded = encode(args.iter().map(String::as_str));
+
+            !encoded.contains('\n') && decode(&encoded, no_legacy).unwrap() == args
+        }
+    }
+}
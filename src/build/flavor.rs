@@ -0,0 +1,400 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use strum::{Display, EnumString, IntoStaticStr};
+
+use crate::cli::{self, UnixCommandArgs, WindowsCommandArgs};
+
+/// The flavor of a linker, which determines how its arguments are parsed and formatted.
+///
+/// See <https://doc.rust-lang.org/rustc/codegen-options/index.html#linker-flavor>.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, EnumString, Display, IntoStaticStr)]
+#[strum(ascii_case_insensitive)]
+pub enum LinkerFlavor {
+    /// A gcc-like compiler driver (`gcc`, `clang`, `cc`) that forwards to the linker.
+    #[strum(serialize = "gcc")]
+    Gcc,
+    /// The GNU linker (`ld`, `ld.bfd`, `ld.gold`).
+    #[strum(serialize = "ld")]
+    Ld,
+    /// The LLVM linker in its GNU-compatible mode (`ld.lld`).
+    #[strum(serialize = "ld.lld")]
+    Lld,
+    /// The MSVC linker (`link.exe`) or the LLVM linker in its MSVC-compatible mode
+    /// (`lld-link`).
+    #[strum(to_string = "msvc", serialize = "lld-link")]
+    Msvc,
+}
+
+impl Default for LinkerFlavor {
+    fn default() -> Self {
+        Self::Gcc
+    }
+}
+
+impl LinkerFlavor {
+    /// Try to detect the flavor of the linker executable `linker` from its file name.
+    pub fn detect(linker: impl AsRef<Path>) -> Option<Self> {
+        // Also split on `\` so that windows paths are handled on all hosts.
+        let file_name = linker
+            .as_ref()
+            .to_str()?
+            .rsplit(&['/', '\\'][..])
+            .next()?
+            .to_ascii_lowercase();
+        let name = file_name.strip_suffix(".exe").unwrap_or(&file_name);
+        // Strip the version of versioned drivers (ex. `gcc-12`, `clang-15`).
+        let name = match name.rsplit_once('-') {
+            Some((name, version))
+                if !version.is_empty()
+                    && version.starts_with(|c: char| c.is_ascii_digit())
+                    && version.chars().all(|c| c.is_ascii_digit() || c == '.') =>
+            {
+                name
+            }
+            _ => name,
+        };
+
+        // Cross toolchains prefix the tool name with the target triple
+        // (ex. `xtensa-esp32-elf-gcc`), so only look at the last component.
+        let tool = name.rsplit('-').next().unwrap_or(name);
+
+        if name.ends_with("lld-link") || tool == "link" {
+            Some(Self::Msvc)
+        } else if tool == "ld.lld" || tool == "lld" {
+            Some(Self::Lld)
+        } else if tool == "ld" || tool.starts_with("ld.") {
+            Some(Self::Ld)
+        } else if ["gcc", "g++", "cc", "c++", "clang", "clang++"]
+            .iter()
+            .any(|d| tool == *d || tool.starts_with(&format!("{}.", d)))
+        {
+            Some(Self::Gcc)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this flavor uses the MSVC command-line conventions.
+    pub fn is_msvc(self) -> bool {
+        self == Self::Msvc
+    }
+
+    /// Split the contents of a response file (`@<file>`) into arguments.
+    pub fn parse_response_file(self, contents: &str) -> Vec<String> {
+        if self.is_msvc() {
+            contents.lines().flat_map(WindowsCommandArgs::new).collect()
+        } else {
+            UnixCommandArgs::new(contents).collect()
+        }
+    }
+
+    /// Format `args` as the contents of a response file (`@<file>`) this flavor
+    /// understands.
+    pub fn format_response_file<'a>(self, args: impl IntoIterator<Item = &'a str>) -> String {
+        if self.is_msvc() {
+            cli::join_windows_args(args)
+        } else {
+            cli::join_unix_args(args)
+        }
+    }
+
+    /// If `arg` adds a library to the link, get a key that uniquely identifies that
+    /// library for this flavor.
+    ///
+    /// The gcc-like flavors recognize `-l<name>` and `--library=<name>`, the MSVC flavor
+    /// recognizes `/DEFAULTLIB:<name>` and `<path>.lib` arguments.
+    pub fn lib_key(self, arg: &str) -> Option<String> {
+        if self.is_msvc() {
+            let lower = arg.to_ascii_lowercase();
+
+            let lib = if let Some(lib) = ["/defaultlib:", "-defaultlib:"]
+                .iter()
+                .find_map(|p| lower.strip_prefix(p))
+            {
+                lib.trim_matches('"')
+            } else if lower.ends_with(".lib") && !is_msvc_option(&lower) {
+                lower.as_str()
+            } else {
+                return None;
+            };
+
+            // `link.exe` appends `.lib` to library names without an extension.
+            Some(if Path::new(lib).extension().is_some() {
+                lib.to_owned()
+            } else {
+                format!("{}.lib", lib)
+            })
+        } else {
+            arg.strip_prefix("--library=")
+                .or_else(|| arg.strip_prefix("-l"))
+                .filter(|lib| !lib.is_empty())
+                .map(str::to_owned)
+        }
+    }
+
+    /// Remove duplicate library arguments from `args` according to the rules of this
+    /// flavor.
+    ///
+    /// The gcc-like flavors search libraries in command-line order, so only the last
+    /// occurrence of a library is kept to retain the dependencies of all earlier
+    /// arguments. The MSVC flavor searches all libraries regardless of their order, so
+    /// only the first occurrence is kept.
+    pub fn dedup_libs(self, args: Vec<String>) -> Vec<String> {
+        let mut libs = HashMap::<String, usize>::new();
+
+        for arg in &args {
+            if let Some(key) = self.lib_key(arg) {
+                *libs.entry(key).or_default() += 1;
+            }
+        }
+
+        let mut seen = HashMap::<String, usize>::new();
+
+        args.into_iter()
+            .filter(|arg| match self.lib_key(arg) {
+                Some(key) => {
+                    let count = libs[&key];
+                    let occurrence = seen.entry(key).or_default();
+                    *occurrence += 1;
+
+                    if self.is_msvc() {
+                        *occurrence == 1
+                    } else {
+                        *occurrence == count
+                    }
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Remove duplicate library arguments from `args` while keeping the semantics of
+    /// circular dependencies between static libraries.
+    ///
+    /// For the gcc-like flavors every range of arguments from the first to the last
+    /// occurrence of a repeated library (merged with all overlapping ranges) is collapsed
+    /// into a single `--start-group ... --end-group`, in which each library occurs only
+    /// once. The linker searches the libraries of a group repeatedly until no new
+    /// undefined references are created, so no repetition is necessary. Ranges that
+    /// already contain a group are left unchanged because groups cannot be nested.
+    ///
+    /// The MSVC flavor doesn't need groups, so this is the same as
+    /// [`dedup_libs`](Self::dedup_libs).
+    pub fn group_libs(self, args: Vec<String>) -> Vec<String> {
+        if self.is_msvc() {
+            return self.dedup_libs(args);
+        }
+
+        let mut spans = HashMap::<String, (usize, usize)>::new();
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(key) = self.lib_key(arg) {
+                spans.entry(key).or_insert((i, i)).1 = i;
+            }
+        }
+
+        let mut spans = spans
+            .into_values()
+            .filter(|(first, last)| first != last)
+            .collect::<Vec<_>>();
+        spans.sort_unstable();
+
+        let mut regions: Vec<(usize, usize)> = Vec::new();
+        for (first, last) in spans {
+            match regions.last_mut() {
+                Some(region) if first <= region.1 => region.1 = region.1.max(last),
+                _ => regions.push((first, last)),
+            }
+        }
+
+        let (start_group, end_group) = if self == Self::Gcc {
+            ("-Wl,--start-group", "-Wl,--end-group")
+        } else {
+            ("--start-group", "--end-group")
+        };
+        let is_group = |arg: &str| {
+            arg.contains("--start-group")
+                || arg.contains("--end-group")
+                || matches!(arg, "-(" | "-)" | "-Wl,-(" | "-Wl,-)")
+        };
+
+        let mut result = Vec::with_capacity(args.len() + regions.len() * 2);
+        let mut regions = regions.into_iter().peekable();
+        let mut libs = HashSet::new();
+        let mut in_group = false;
+
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(&(first, last)) = regions.peek() {
+                if i == first && !args[first..=last].iter().any(|a| is_group(a)) {
+                    result.push(start_group.to_owned());
+                    libs.clear();
+                    in_group = true;
+                }
+
+                let keep = !in_group || self.lib_key(arg).map_or(true, |key| libs.insert(key));
+                if keep {
+                    result.push(arg.clone());
+                }
+
+                if i == last {
+                    if in_group {
+                        result.push(end_group.to_owned());
+                    }
+                    in_group = false;
+                    regions.next();
+                }
+            } else {
+                result.push(arg.clone());
+            }
+        }
+
+        result
+    }
+}
+
+/// Whether `arg` is an MSVC-style option (ex. `/out:file` or `-debug`) rather than a
+/// path.
+fn is_msvc_option(arg: &str) -> bool {
+    let name = match arg.strip_prefix('/').or_else(|| arg.strip_prefix('-')) {
+        Some(name) => name,
+        None => return false,
+    };
+    let name = name.split(':').next().unwrap_or_default();
+
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(
+            LinkerFlavor::detect("/opt/xtensa-esp32-elf-gcc"),
+            Some(LinkerFlavor::Gcc)
+        );
+        assert_eq!(
+            LinkerFlavor::detect("riscv32-esp-elf-ld.exe"),
+            Some(LinkerFlavor::Ld)
+        );
+        assert_eq!(LinkerFlavor::detect("ld.lld"), Some(LinkerFlavor::Lld));
+        assert_eq!(LinkerFlavor::detect("lld-link"), Some(LinkerFlavor::Msvc));
+        assert_eq!(
+            LinkerFlavor::detect(r"C:\VS\bin\LINK.EXE"),
+            Some(LinkerFlavor::Msvc)
+        );
+        assert_eq!(LinkerFlavor::detect("gcc-12"), Some(LinkerFlavor::Gcc));
+        assert_eq!(
+            LinkerFlavor::detect("/opt/xtensa-esp32-elf-gcc-8.4.0"),
+            Some(LinkerFlavor::Gcc)
+        );
+        assert_eq!(
+            LinkerFlavor::detect("clang-15.exe"),
+            Some(LinkerFlavor::Gcc)
+        );
+        assert_eq!(LinkerFlavor::detect("ld.lld-15"), Some(LinkerFlavor::Lld));
+        assert_eq!(LinkerFlavor::detect("ldproxy"), None);
+        assert_eq!(LinkerFlavor::detect("12"), None);
+        assert_eq!("lld-link".parse(), Ok(LinkerFlavor::Msvc));
+        assert_eq!(LinkerFlavor::Lld.to_string(), "ld.lld");
+    }
+
+    #[test]
+    fn dedup_gcc() {
+        let deduped = LinkerFlavor::Gcc.dedup_libs(args(&[
+            "-lfoo",
+            "a.o",
+            "--library=bar",
+            "--library=foo",
+            "-lbar",
+            "-L",
+        ]));
+
+        assert_eq!(deduped, args(&["a.o", "--library=foo", "-lbar", "-L"]));
+    }
+
+    #[test]
+    fn group_gcc() {
+        let grouped = LinkerFlavor::Gcc.group_libs(args(&[
+            "main.o",
+            "-la",
+            "-lb",
+            "-la",
+            "-lc",
+            "-lb",
+            "-ld",
+            "-le",
+            "-Wl,--start-group",
+            "-le",
+            "-Wl,--end-group",
+        ]));
+
+        assert_eq!(
+            grouped,
+            args(&[
+                "main.o",
+                "-Wl,--start-group",
+                "-la",
+                "-lb",
+                "-lc",
+                "-Wl,--end-group",
+                "-ld",
+                "-le",
+                "-Wl,--start-group",
+                "-le",
+                "-Wl,--end-group",
+            ])
+        );
+    }
+
+    #[test]
+    fn dedup_msvc() {
+        let deduped = LinkerFlavor::Msvc.dedup_libs(args(&[
+            "/DEFAULTLIB:kernel32",
+            "/OUT:app.lib",
+            "Kernel32.lib",
+            r"C:\libs\foo.lib",
+            "-defaultlib:msvcrt.lib",
+            r"c:\LIBS\foo.lib",
+        ]));
+
+        assert_eq!(
+            deduped,
+            args(&[
+                "/DEFAULTLIB:kernel32",
+                "/OUT:app.lib",
+                r"C:\libs\foo.lib",
+                "-defaultlib:msvcrt.lib",
+            ])
+        );
+    }
+
+    #[test]
+    fn response_file_quoting() {
+        let args = ["main.o", "C:\\my libs\\", "-DNAME=\"a b\"", "it's"];
+
+        assert_eq!(
+            LinkerFlavor::Gcc.format_response_file(args),
+            r#"main.o "C:\\my libs\\" "-DNAME=\"a b\"" "it's""#
+        );
+        assert_eq!(
+            LinkerFlavor::Msvc.format_response_file(args),
+            r#"main.o "C:\my libs\\" "-DNAME=\"a b\"" it's"#
+        );
+
+        for flavor in [
+            LinkerFlavor::Gcc,
+            LinkerFlavor::Ld,
+            LinkerFlavor::Lld,
+            LinkerFlavor::Msvc,
+        ] {
+            let contents = flavor.format_response_file(args);
+            assert_eq!(flavor.parse_response_file(&contents), args, "{}", flavor);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cargo::{self, set_metadata};
+use crate::utils::OsStrExt;
+
+const METADATA_DIR: &str = "embuild-metadata";
+const VAR_METADATA_PREFIX: &str = "EMBUILD_METADATA_";
+
+/// A typed value that a crate's build script propagates to the build scripts of its
+/// dependents.
+///
+/// The value is serialized as JSON to a file in `OUT_DIR`, whose path is passed to the
+/// dependents as the cargo metadata `EMBUILD_METADATA_<NAME>`. Like all cargo metadata
+/// it is only available to the direct dependents of a crate with a `links` property.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct EspIdfInfo {
+///     path: PathBuf,
+///     mcu: String,
+/// }
+///
+/// impl PropagatedMetadata for EspIdfInfo {
+///     const NAME: &'static str = "ESP_IDF_INFO";
+/// }
+///
+/// // In the build script of the crate with `links = "esp_idf"`:
+/// info.propagate()?;
+///
+/// // In the build script of a dependent:
+/// let info = EspIdfInfo::try_from_env("ESP_IDF")?;
+/// ```
+pub trait PropagatedMetadata: Serialize + DeserializeOwned {
+    /// The name of the metadata, which may only contain ASCII alphanumeric characters
+    /// and `_`.
+    const NAME: &'static str;
+
+    /// Propagate this value to all dependents of this crate.
+    ///
+    /// ### **Important**
+    /// Calling this method in a dependency doesn't do anything on itself. All dependents
+    /// that want to use this value must call [`try_from_env`](Self::try_from_env) in
+    /// their build script with the value of this crate's `links` property (specified in
+    /// `Cargo.toml`).
+    fn propagate(&self) -> Result<()> {
+        let file = self.write_to_out_dir(cargo::out_dir())?;
+
+        set_metadata(
+            format!("{}{}", VAR_METADATA_PREFIX, Self::NAME),
+            file.as_os_str().try_to_str()?,
+        );
+
+        Ok(())
+    }
+
+    /// Write this value to its file in `out_dir` and return the path of the file.
+    fn write_to_out_dir(&self, out_dir: impl AsRef<Path>) -> Result<PathBuf> {
+        check_name(Self::NAME)?;
+
+        let dir = out_dir.as_ref().join(METADATA_DIR);
+        fs::create_dir_all(&dir)?;
+
+        let file = dir.join(format!("{}.json", Self::NAME.to_ascii_lowercase()));
+        fs::write(&file, serde_json::to_vec(self)?).with_context(|| {
+            anyhow!(
+                "Could not write metadata '{}' to '{}'",
+                Self::NAME,
+                file.display()
+            )
+        })?;
+
+        Ok(file)
+    }
+
+    /// Load the value from `lib_name` which has been propagated using
+    /// [`propagate`](Self::propagate).
+    ///
+    /// `lib_name` doesn't refer to a crate, library or package name, it refers to a
+    /// dependency's `links` property value, which is specified in its package manifest
+    /// (`Cargo.toml`).
+    fn try_from_env(lib_name: impl Display) -> Result<Self> {
+        check_name(Self::NAME)?;
+
+        let var = format!(
+            "DEP_{}_{}{}",
+            lib_name.to_string().to_uppercase(),
+            VAR_METADATA_PREFIX,
+            Self::NAME.to_uppercase()
+        );
+        let file =
+            env::var_os(&var).ok_or_else(|| anyhow!("Environment variable {} not set", var))?;
+
+        Self::try_from_file(file)
+    }
+
+    /// Load the value from the `file` written by [`propagate`](Self::propagate).
+    fn try_from_file(file: impl AsRef<Path>) -> Result<Self> {
+        let file = file.as_ref();
+
+        let data = fs::read(file).with_context(|| {
+            anyhow!(
+                "Could not read metadata '{}' from '{}'",
+                Self::NAME,
+                file.display()
+            )
+        })?;
+
+        serde_json::from_slice(&data)
+            .with_context(|| anyhow!("Could not parse metadata '{}'", Self::NAME))
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Invalid metadata name '{}'", name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Info {
+        path: PathBuf,
+        flags: Vec<String>,
+    }
+
+    impl PropagatedMetadata for Info {
+        const NAME: &'static str = "TEST_INFO";
+    }
+
+    #[test]
+    fn roundtrip() {
+        let temp = TempDir::new().unwrap();
+        let out_dir = temp.path();
+
+        let info = Info {
+            path: "/opt/esp-idf".into(),
+            flags: vec!["a:b".into(), "line\nbreak".into()],
+        };
+        let file = info.write_to_out_dir(out_dir).unwrap();
+
+        assert_eq!(file, out_dir.join(METADATA_DIR).join("test_info.json"));
+        assert_eq!(Info::try_from_file(&file).unwrap(), info);
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::value::{Error as DeError, MapDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::cargo::{print_warning, track_env_var, track_file, CargoConfigLoader, EnvConfigValue};
+
+/// A loader of the options of a build script into a typed struct.
+///
+/// Every field of the options struct `T` is read from (in order of precedence):
+/// 1. the environment variable `<PREFIX>_<FIELD>`;
+/// 2. the `[env]` table of the cargo configuration of the crate's directory (see
+///    [`CargoConfig`](crate::cargo::CargoConfig)), whose values only override the
+///    environment if they are set with `force = true`, like cargo does;
+/// 3. the `[package.metadata.<key>]` table of the crate's `Cargo.toml`, if
+///    [`package_metadata`](Self::package_metadata) is set.
+///
+/// Fields that are not set anywhere get their `#[serde(default)]`. Values from
+/// environment variables are parsed according to the type of the field: booleans
+/// accept `true`/`false`, `1`/`0`, `yes`/`no` and `on`/`off`, lists are separated by
+/// `,`.
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct BuildOptions {
+///     #[serde(default)]
+///     version: Option<String>,
+///     #[serde(default)]
+///     components: Vec<String>,
+/// }
+///
+/// // Reads `ESP_IDF_VERSION`, `ESP_IDF_COMPONENTS` and `[package.metadata.esp-idf]`.
+/// let options: BuildOptions = OptionsLoader::new("ESP_IDF")
+///     .package_metadata("esp-idf")
+///     .load()?;
+/// ```
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct OptionsLoader {
+    env_prefix: String,
+    manifest_dir: Option<PathBuf>,
+    package_metadata: Option<String>,
+    cargo_config: bool,
+    cargo_home: Option<PathBuf>,
+    track: bool,
+}
+
+impl OptionsLoader {
+    /// Create a loader of the options with environment variables prefixed by
+    /// `<env_prefix>_`.
+    ///
+    /// The crate's directory is taken from `CARGO_MANIFEST_DIR`.
+    pub fn new(env_prefix: impl Into<String>) -> Self {
+        Self {
+            env_prefix: env_prefix.into(),
+            manifest_dir: env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from),
+            package_metadata: None,
+            cargo_config: true,
+            cargo_home: None,
+            track: true,
+        }
+    }
+
+    /// Set the directory of the crate containing `Cargo.toml`.
+    pub fn manifest_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.manifest_dir = Some(dir.into());
+        self
+    }
+
+    /// Read options from `[package.metadata.<key>]` of the crate's `Cargo.toml`.
+    pub fn package_metadata(mut self, key: impl Into<String>) -> Self {
+        self.package_metadata = Some(key.into());
+        self
+    }
+
+    /// Whether options are read from the `[env]` table of the cargo configuration
+    /// files, `true` by default.
+    pub fn cargo_config(mut self, value: bool) -> Self {
+        self.cargo_config = value;
+        self
+    }
+
+    /// Set the cargo home directory whose configuration is read, `$CARGO_HOME` or
+    /// `~/.cargo` by default.
+    pub fn cargo_home(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cargo_home = Some(dir.into());
+        self
+    }
+
+    /// Whether the build script should be rerun when any of the option's environment
+    /// variables or files change, `true` by default.
+    pub fn track(mut self, value: bool) -> Self {
+        self.track = value;
+        self
+    }
+
+    /// Get the name of the environment variable of `field`.
+    pub fn env_var(&self, field: &str) -> String {
+        format!(
+            "{}_{}",
+            self.env_prefix,
+            field.replace('-', "_").to_uppercase()
+        )
+    }
+
+    /// Load the options.
+    pub fn load<T: DeserializeOwned>(&self) -> Result<T> {
+        let mut vars = BTreeMap::new();
+        for &field in field_names::<T>()? {
+            let var = self.env_var(field);
+            if self.track {
+                track_env_var(&var);
+            }
+
+            if let Some(value) = env::var_os(&var) {
+                let value = value
+                    .into_string()
+                    .map_err(|_| anyhow!("Environment variable {} is not valid UTF-8", var))?;
+
+                vars.insert(var, value);
+            }
+        }
+
+        self.load_with_vars(vars)
+    }
+
+    /// Load the options with the environment variables `vars` instead of the
+    /// environment of this process.
+    fn load_with_vars<T: DeserializeOwned>(&self, vars: BTreeMap<String, String>) -> Result<T> {
+        let fields = field_names::<T>()?;
+
+        let mut entries = Vec::<(&'static str, Entry)>::new();
+        let mut add = |field: &'static str, entry: Entry| {
+            if !entries.iter().any(|(f, _)| *f == field) {
+                entries.push((field, entry));
+            }
+        };
+
+        let config_env = if self.cargo_config {
+            self.cargo_config_env()?
+        } else {
+            Default::default()
+        };
+        let config_entry = |field: &str, force: bool| {
+            config_env
+                .get(&self.env_var(field))
+                .filter(|value| value.force == force)
+                .map(|value| Entry {
+                    value: RawValue::Str(value.value.clone()),
+                    source: Source::CargoConfig,
+                })
+        };
+
+        // Like cargo, forced `[env]` values override the environment, all others don't.
+        for &field in fields {
+            if let Some(entry) = config_entry(field, true) {
+                add(field, entry);
+            }
+        }
+
+        for &field in fields {
+            let var = self.env_var(field);
+            if let Some(value) = vars.get(&var) {
+                add(
+                    field,
+                    Entry {
+                        value: RawValue::Str(value.clone()),
+                        source: Source::Env(var),
+                    },
+                );
+            }
+        }
+
+        for &field in fields {
+            if let Some(entry) = config_entry(field, false) {
+                add(field, entry);
+            }
+        }
+
+        if let Some(key) = &self.package_metadata {
+            if let Some((file, table)) = self.package_metadata_table(key)? {
+                for (name, value) in table {
+                    match fields.iter().find(|f| normalize(f) == normalize(&name)) {
+                        Some(field) => add(
+                            field,
+                            Entry {
+                                value: RawValue::Toml(value),
+                                source: Source::Metadata(file.clone(), key.clone()),
+                            },
+                        ),
+                        None => print_warning(format!(
+                            "Unknown option `{}` in `[package.metadata.{}]` of '{}'",
+                            name,
+                            key,
+                            file.display()
+                        )),
+                    }
+                }
+            }
+        }
+
+        T::deserialize(MapDeserializer::new(entries.into_iter()))
+            .map_err(|err| anyhow!("Invalid build options ({}_*): {}", self.env_prefix, err))
+    }
+
+    /// Get the merged `[env]` table of the cargo configuration of the crate's directory.
+    fn cargo_config_env(&self) -> Result<BTreeMap<String, EnvConfigValue>> {
+        let dir = match &self.manifest_dir {
+            Some(dir) => dir.clone(),
+            None => env::current_dir()?,
+        };
+
+        // Only `[env]` is used, which the `CARGO_*` environment variables don't affect.
+        let mut loader = CargoConfigLoader::new(dir).env(false);
+        if let Some(cargo_home) = &self.cargo_home {
+            loader = loader.cargo_home(cargo_home);
+        }
+
+        let config = loader.load()?;
+        if self.track {
+            for file in &config.files {
+                track_file(file);
+            }
+        }
+
+        Ok(config.env)
+    }
+
+    /// Get the `[package.metadata.<key>]` table of the crate's `Cargo.toml`.
+    fn package_metadata_table(&self, key: &str) -> Result<Option<(PathBuf, toml::value::Table)>> {
+        let manifest = match &self.manifest_dir {
+            Some(dir) => dir.join("Cargo.toml"),
+            None => return Ok(None),
+        };
+
+        if self.track {
+            track_file(&manifest);
+        }
+
+        let value = read_toml(&manifest)?;
+        let table = value
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(|m| m.get(key));
+
+        match table {
+            Some(toml::Value::Table(table)) => Ok(Some((manifest, table.clone()))),
+            Some(_) => Err(anyhow!(
+                "`[package.metadata.{}]` of '{}' is not a table",
+                key,
+                manifest.display()
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+fn read_toml(file: &Path) -> Result<toml::Value> {
+    fs::read_to_string(file)
+        .with_context(|| anyhow!("Could not read '{}'", file.display()))?
+        .parse::<toml::Value>()
+        .with_context(|| anyhow!("Could not parse '{}'", file.display()))
+}
+
+/// Get the field names of the struct `T`.
+fn field_names<T: DeserializeOwned>() -> Result<&'static [&'static str]> {
+    struct FieldNames<'a>(&'a mut Option<&'static [&'static str]>);
+
+    impl<'de, 'a> de::Deserializer<'de> for FieldNames<'a> {
+        type Error = DeError;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, DeError> {
+            Err(de::Error::custom("options must be a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, DeError> {
+            *self.0 = Some(fields);
+            Err(de::Error::custom("field names collected"))
+        }
+
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map
+            enum identifier ignored_any
+        }
+    }
+
+    let mut fields = None;
+    let _ = T::deserialize(FieldNames(&mut fields));
+
+    fields.ok_or_else(|| anyhow!("Build options must be a struct with named fields"))
+}
+
+/// Where the value of an option comes from.
+#[derive(Clone, Debug)]
+enum Source {
+    Env(String),
+    CargoConfig,
+    Metadata(PathBuf, String),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Env(var) => write!(f, "environment variable {}", var),
+            Self::CargoConfig => write!(f, "`[env]` of the cargo configuration"),
+            Self::Metadata(file, key) => {
+                write!(f, "`[package.metadata.{}]` of '{}'", key, file.display())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum RawValue {
+    /// A string that is parsed according to the type of the field.
+    Str(String),
+    Toml(toml::Value),
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    value: RawValue,
+    source: Source,
+}
+
+impl Entry {
+    fn error(&self, err: impl Display) -> DeError {
+        de::Error::custom(format_args!("{} (from {})", err, self.source))
+    }
+
+    fn parse<T: std::str::FromStr>(&self, s: &str, what: &str) -> Result<T, DeError>
+    where
+        T::Err: Display,
+    {
+        s.trim()
+            .parse()
+            .map_err(|err| self.error(format_args!("invalid {} '{}': {}", what, s, err)))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for Entry {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Implement a `deserialize_*` method that delegates to the TOML value, or handles a
+/// string with `$str`.
+macro_rules! deserialize {
+    ($method:ident, |$self:ident, $s:ident, $visitor:ident| $str:expr) => {
+        fn $method<V: Visitor<'de>>($self, $visitor: V) -> Result<V::Value, DeError> {
+            match &$self.value {
+                RawValue::Toml(value) => de::Deserializer::$method(value.clone(), $visitor)
+                    .map_err(|err| $self.error(err)),
+                RawValue::Str($s) => $str,
+            }
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Entry {
+    type Error = DeError;
+
+    deserialize!(deserialize_any, |self, s, visitor| visitor.visit_str(s));
+    deserialize!(deserialize_bool, |self, s, visitor| {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => visitor.visit_bool(true),
+            "false" | "0" | "no" | "off" => visitor.visit_bool(false),
+            _ => Err(self.error(format_args!("invalid boolean '{}'", s))),
+        }
+    });
+    deserialize!(deserialize_i8, |self, s, visitor| visitor
+        .visit_i64(self.parse(s, "integer")?));
+    deserialize!(deserialize_i16, |self, s, visitor| visitor
+        .visit_i64(self.parse(s, "integer")?));
+    deserialize!(deserialize_i32, |self, s, visitor| visitor
+        .visit_i64(self.parse(s, "integer")?));
+    deserialize!(deserialize_i64, |self, s, visitor| visitor
+        .visit_i64(self.parse(s, "integer")?));
+    deserialize!(deserialize_u8, |self, s, visitor| visitor
+        .visit_u64(self.parse(s, "integer")?));
+    deserialize!(deserialize_u16, |self, s, visitor| visitor
+        .visit_u64(self.parse(s, "integer")?));
+    deserialize!(deserialize_u32, |self, s, visitor| visitor
+        .visit_u64(self.parse(s, "integer")?));
+    deserialize!(deserialize_u64, |self, s, visitor| visitor
+        .visit_u64(self.parse(s, "integer")?));
+    deserialize!(deserialize_f32, |self, s, visitor| visitor
+        .visit_f64(self.parse(s, "number")?));
+    deserialize!(deserialize_f64, |self, s, visitor| visitor
+        .visit_f64(self.parse(s, "number")?));
+    deserialize!(deserialize_char, |self, s, visitor| visitor.visit_str(s));
+    deserialize!(deserialize_str, |self, s, visitor| visitor.visit_str(s));
+    deserialize!(deserialize_string, |self, s, visitor| visitor.visit_str(s));
+    deserialize!(deserialize_bytes, |self, s, visitor| visitor
+        .visit_bytes(s.as_bytes()));
+    deserialize!(deserialize_byte_buf, |self, s, visitor| visitor
+        .visit_bytes(s.as_bytes()));
+    deserialize!(deserialize_identifier, |self, s, visitor| visitor
+        .visit_str(s));
+    deserialize!(deserialize_ignored_any, |self, _s, visitor| visitor
+        .visit_unit());
+    deserialize!(deserialize_unit, |self, _s, visitor| visitor.visit_unit());
+    deserialize!(deserialize_seq, |self, s, visitor| {
+        let items = s
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Entry {
+                value: RawValue::Str(item.to_owned()),
+                source: self.source.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        visitor.visit_seq(de::value::SeqDeserializer::new(items.into_iter()))
+    });
+    deserialize!(deserialize_map, |self, _s, visitor| Err(
+        self.error("expected a table, found a string")
+    ));
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match &self.value {
+            RawValue::Toml(value) => value
+                .clone()
+                .deserialize_enum(name, variants, visitor)
+                .map_err(|err| self.error(err)),
+            RawValue::Str(s) => {
+                let s: de::value::StrDeserializer<DeError> = s.trim().into_deserializer();
+                s.deserialize_enum(name, variants, visitor)
+                    .map_err(|err| self.error(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Release,
+        Debug,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Options {
+        version: String,
+        #[serde(default)]
+        components: Vec<String>,
+        #[serde(default)]
+        verbose: bool,
+        jobs: Option<u32>,
+        mode: Mode,
+        #[serde(default)]
+        define: Option<String>,
+    }
+
+    #[test]
+    fn load() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("crate");
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"
+            [package.metadata.opt-test]
+            version = "v4.4"
+            jobs = 2
+            mode = "debug"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join(".cargo").join("config.toml"),
+            r#"
+            [env]
+            OPT_TEST_JOBS = { value = "8", force = true }
+            OPT_TEST_VERSION = "v5.0"
+            OPT_TEST_MODE = { value = "release", force = false }
+            "#,
+        )
+        .unwrap();
+
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let mut env = vars(&[
+            ("OPT_TEST_COMPONENTS", "wifi, bt"),
+            ("OPT_TEST_VERBOSE", "yes"),
+            ("OPT_TEST_DEFINE", "URL=http://example.com:80"),
+            ("OPT_TEST_JOBS", "4"),
+        ]);
+
+        let loader = OptionsLoader::new("OPT_TEST")
+            .manifest_dir(&dir)
+            .cargo_home(temp.path().join("cargo-home"))
+            .package_metadata("opt-test")
+            .track(false);
+
+        let options: Options = loader.load_with_vars(env.clone()).unwrap();
+        assert_eq!(options.version, "v5.0");
+        assert_eq!(options.components, ["wifi", "bt"]);
+        assert!(options.verbose);
+        assert_eq!(options.jobs, Some(8));
+        assert_eq!(options.mode, Mode::Release);
+        assert_eq!(options.define.as_deref(), Some("URL=http://example.com:80"));
+
+        env.insert("OPT_TEST_VERSION".into(), "v4.3".into());
+        env.insert("OPT_TEST_MODE".into(), "debug".into());
+        let options: Options = loader.load_with_vars(env.clone()).unwrap();
+        assert_eq!(options.version, "v4.3");
+        assert_eq!(options.mode, Mode::Debug);
+
+        let options: Options = loader
+            .clone()
+            .cargo_config(false)
+            .load_with_vars(vars(&[]))
+            .unwrap();
+        assert_eq!(options.version, "v4.4");
+        assert_eq!(options.jobs, Some(2));
+
+        env.insert("OPT_TEST_MODE".into(), "fast".into());
+        let err = loader
+            .load_with_vars::<Options>(env.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("OPT_TEST_MODE"), "{}", err);
+
+        env.insert("OPT_TEST_MODE".into(), "debug".into());
+        env.insert("OPT_TEST_VERBOSE".into(), "maybe".into());
+        let err = loader
+            .load_with_vars::<Options>(env)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("invalid boolean 'maybe'"), "{}", err);
+    }
+}
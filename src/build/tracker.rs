@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::globs_iter;
+use crate::cargo::{self, track_file};
+
+const MANIFEST_DIR: &str = "embuild-sources";
+
+/// Tracks source files matched by globs and detects which of them actually changed
+/// since the last successful build.
+///
+/// Cargo reruns the build script whenever the modification time of a tracked file
+/// changes. The tracker records the content hashes of all files in a manifest in
+/// `OUT_DIR`, so that expensive steps (like running cmake or platformio) can be
+/// skipped if no file changed its contents.
+///
+/// ```ignore
+/// let sources = SourceTracker::new("components")
+///     .globs("components", &["**/*.c", "**/*.h"])
+///     .update()?;
+///
+/// if !sources.changes.is_empty() {
+///     sources.copy_to(&project_dir)?;
+///     run_expensive_build()?;
+/// }
+///
+/// // Only record the new state if the build succeeded.
+/// sources.commit()?;
+/// ```
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct SourceTracker {
+    name: String,
+    globs: Vec<(PathBuf, Vec<String>)>,
+    manifest_dir: Option<PathBuf>,
+    track: bool,
+}
+
+impl SourceTracker {
+    /// Create a tracker whose manifest is stored as `<name>.json`.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            globs: Vec::new(),
+            manifest_dir: None,
+            track: true,
+        }
+    }
+
+    /// Track all files in `base` that match any of `globs`.
+    pub fn globs(mut self, base: impl Into<PathBuf>, globs: &[impl AsRef<str>]) -> Self {
+        self.globs.push((
+            base.into(),
+            globs.iter().map(|g| g.as_ref().to_owned()).collect(),
+        ));
+        self
+    }
+
+    /// Set the directory of the manifest, `$OUT_DIR/embuild-sources` by default.
+    pub fn manifest_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.manifest_dir = Some(dir.into());
+        self
+    }
+
+    /// Whether cargo should rerun the build script if any of the tracked files or
+    /// directories change, `true` by default.
+    pub fn track(mut self, value: bool) -> Self {
+        self.track = value;
+        self
+    }
+
+    /// Find all tracked files and compare them to the manifest of the last commit.
+    pub fn update(self) -> Result<TrackedSources> {
+        let manifest_dir = self
+            .manifest_dir
+            .unwrap_or_else(|| cargo::out_dir().join(MANIFEST_DIR));
+        let manifest_path = manifest_dir.join(format!("{}.json", self.name));
+
+        let old = if manifest_path.exists() {
+            let data = fs::read(&manifest_path)?;
+            // A corrupted manifest just means that everything changed.
+            serde_json::from_slice::<Manifest>(&data).unwrap_or_default()
+        } else {
+            Manifest::default()
+        };
+
+        let mut files = Vec::new();
+        let mut manifest = Manifest::default();
+
+        for (base, globs) in &self.globs {
+            if self.track {
+                // Tracking the directory makes cargo notice added files.
+                track_file(base);
+            }
+
+            for (source, dest) in globs_iter(base, globs)? {
+                if !source.is_file() {
+                    continue;
+                }
+
+                if self.track {
+                    track_file(&source);
+                }
+
+                manifest.files.insert(
+                    source.clone(),
+                    ManifestEntry {
+                        hash: hash_file(&source)?,
+                        dest: dest.clone(),
+                    },
+                );
+                files.push((source, dest));
+            }
+        }
+
+        let mut changes = SourceChanges::default();
+        for (file, entry) in &manifest.files {
+            match old.files.get(file) {
+                None => changes.added.push(file.clone()),
+                Some(old_entry) if old_entry.hash != entry.hash => {
+                    changes.modified.push(file.clone())
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut removed_dests = Vec::new();
+        for (file, entry) in old.files {
+            if !manifest.files.contains_key(&file) {
+                changes.removed.push(file);
+
+                if !files.iter().any(|(_, dest)| *dest == entry.dest) {
+                    removed_dests.push(entry.dest);
+                }
+            }
+        }
+
+        Ok(TrackedSources {
+            files,
+            changes,
+            removed_dests,
+            manifest,
+            manifest_path,
+        })
+    }
+}
+
+/// The files found by a [`SourceTracker`] and how they changed.
+#[derive(Clone, Debug)]
+pub struct TrackedSources {
+    /// All tracked files with their paths relative to the base directory of their glob.
+    pub files: Vec<(PathBuf, PathBuf)>,
+    /// The changes since the last [`commit`](Self::commit).
+    pub changes: SourceChanges,
+    /// The relative paths of the removed files that no other file is copied to.
+    removed_dests: Vec<PathBuf>,
+    manifest: Manifest,
+    manifest_path: PathBuf,
+}
+
+impl TrackedSources {
+    /// Copy all files into `dest_dir`, at their path relative to the base directory of
+    /// their glob, and delete the copies of removed files.
+    ///
+    /// Files whose copy has the same contents are not written, so their modification
+    /// time doesn't change.
+    pub fn copy_to(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+
+        for dest in &self.removed_dests {
+            let dest = dest_dir.join(dest);
+            if dest.is_file() {
+                fs::remove_file(&dest)
+                    .with_context(|| anyhow!("Could not remove '{}'", dest.display()))?;
+            }
+        }
+
+        for (source, dest) in &self.files {
+            let dest = dest_dir.join(dest);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            crate::fs::copy_file_if_different(source, &dest).with_context(|| {
+                anyhow!(
+                    "Could not copy '{}' to '{}'",
+                    source.display(),
+                    dest.display()
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the current contents of all files as the state the next
+    /// [`SourceTracker::update`] compares to.
+    pub fn commit(&self) -> Result<()> {
+        if let Some(dir) = self.manifest_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(
+            &self.manifest_path,
+            serde_json::to_vec_pretty(&self.manifest)?,
+        )
+        .with_context(|| {
+            anyhow!(
+                "Could not write source manifest '{}'",
+                self.manifest_path.display()
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+/// The files that changed since the last commit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceChanges {
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl SourceChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+
+    /// All added, modified and removed files.
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.added.iter().chain(&self.modified).chain(&self.removed)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    files: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// The hex-encoded SHA-256 hash of the file.
+    hash: String,
+    /// The path of the file relative to the base directory of its glob.
+    dest: PathBuf,
+}
+
+fn hash_file(file: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    File::open(file)
+        .and_then(|mut file| io::copy(&mut file, &mut hasher))
+        .with_context(|| anyhow!("Could not read '{}'", file.display()))?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let src = dir.join("src");
+        let out = dir.join("out");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.c"), "a").unwrap();
+        fs::write(src.join("sub").join("b.c"), "b").unwrap();
+        fs::write(src.join("c.c"), "c").unwrap();
+        fs::write(src.join("ignored.txt"), "").unwrap();
+
+        let tracker = SourceTracker::new("test")
+            .globs(&src, &["**/*.c"])
+            .manifest_dir(&out)
+            .track(false);
+
+        let sources = tracker.clone().update().unwrap();
+        assert_eq!(sources.files.len(), 3);
+        assert_eq!(sources.changes.added.len(), 3);
+        sources.copy_to(dir.join("copy")).unwrap();
+        sources.commit().unwrap();
+
+        // Touching a file without changing it is not a change.
+        fs::write(src.join("a.c"), "a").unwrap();
+        let sources = tracker.clone().update().unwrap();
+        assert!(sources.changes.is_empty());
+
+        fs::write(src.join("sub").join("b.c"), "b2").unwrap();
+        fs::remove_file(src.join("c.c")).unwrap();
+        let sources = tracker.clone().update().unwrap();
+        assert_eq!(sources.changes.modified, [src.join("sub").join("b.c")]);
+        assert_eq!(sources.changes.removed, [src.join("c.c")]);
+
+        // Without a commit the changes are reported again.
+        let sources = tracker.update().unwrap();
+        assert_eq!(sources.changes.iter().count(), 2);
+
+        sources.copy_to(dir.join("copy")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("copy").join("sub").join("b.c")).unwrap(),
+            "b2"
+        );
+        assert!(dir.join("copy").join("a.c").is_file());
+        assert!(!dir.join("copy").join("c.c").exists());
+
+        let err = hash_file(&src.join("c.c")).unwrap_err();
+        assert!(format!("{:#}", err).contains("c.c"), "{:#}", err);
+    }
+}
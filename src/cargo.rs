@@ -1,16 +1,24 @@
 use std::ffi::OsStr;
-use std::fmt::{Display, Write};
+use std::fmt::Display;
 use std::path::{Path, PathBuf};
 use std::{env, fs};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 #[cfg(feature = "manifest")]
-use cargo_toml::{Manifest, Product};
+use cargo_toml::Manifest;
 use log::*;
 
 use crate::utils::{OsStrExt, PathExt};
 use crate::{cargo, cmd};
 
+mod artifact;
+mod config;
+mod output;
+
+pub use artifact::*;
+pub use config::*;
+pub use output::*;
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum CargoCmd {
     New(BuildStd),
@@ -51,35 +59,61 @@ impl Crate {
         Ok(())
     }
 
-    /// Set the library type to `lib_type` and return its name.
+    /// Add the crate types `lib_type` to the library and return its name.
+    ///
+    /// Existing crate types are kept and the formatting and comments of `Cargo.toml` are
+    /// preserved.
     #[cfg(feature = "manifest")]
-    pub(crate) fn set_library_type(
+    pub(crate) fn add_library_types(
         &self,
         lib_type: impl IntoIterator<Item = impl Into<String>>,
     ) -> Result<String> {
-        let mut cargo_toml = self.load_manifest()?;
+        let name = self.get_lib_name(&self.load_manifest()?);
         let lib_type: Vec<_> = lib_type.into_iter().map(Into::into).collect();
 
-        let name = self.get_lib_name(&cargo_toml);
         debug!(
-            "Setting Cargo library crate {} to type {:?}",
-            name, &lib_type
+            "Adding types {:?} to Cargo library crate {}",
+            &lib_type, name
         );
 
-        cargo_toml.lib = Some(Product {
-            crate_type: Some(lib_type),
-            ..if let Some(p) = cargo_toml.lib.take() {
-                p
+        self.edit_toml(self.0.join("Cargo.toml"), |doc| {
+            let lib = table_mut(doc.as_table_mut(), "lib")?;
+            let key = if lib.contains_key("crate_type") {
+                "crate_type"
             } else {
-                Default::default()
+                "crate-type"
+            };
+
+            let crate_type = lib
+                .entry(key)
+                .or_insert_with(|| toml_edit::value(toml_edit::Array::new()))
+                .as_array_mut()
+                .ok_or_else(|| anyhow!("`lib.{}` is not an array", key))?;
+
+            for ty in lib_type {
+                if !crate_type.iter().any(|t| t.as_str() == Some(&ty)) {
+                    crate_type.push(ty);
+                }
             }
-        });
 
-        self.save_manifest(&cargo_toml)?;
+            Ok(())
+        })?;
 
         Ok(name)
     }
 
+    /// Check that this crate is a library crate.
+    #[cfg(feature = "manifest")]
+    pub(crate) fn check_library(&self) -> Result<()> {
+        debug!("Checking Cargo.toml in {}", self.0.display());
+
+        if self.load_manifest()?.lib.is_none() {
+            anyhow::bail!("Not a library crate");
+        }
+
+        Ok(())
+    }
+
     /// Check that the library is a `staticlib` and return its name.
     #[cfg(feature = "manifest")]
     pub(crate) fn check_staticlib(&self) -> Result<String> {
@@ -103,49 +137,118 @@ impl Crate {
         }
     }
 
-    /// Create a `config.toml` in `.cargo` with a `[target]` and `[unstable]` section.
+    /// Create or update the `config.toml` in `.cargo` with the `build.target` and the
+    /// `[unstable]` build-std settings.
+    ///
+    /// The formatting, comments and all other settings of an existing file are preserved.
     pub fn create_config_toml(
         &self,
         target: Option<impl AsRef<str>>,
         build_std: BuildStd,
     ) -> Result<()> {
-        let cargo_config_toml_path = self.0.join(".cargo").join("config.toml");
+        let path = self.config_toml_path();
+
+        debug!("Updating Cargo config {}", path.display());
+
+        self.edit_toml(path, |doc| {
+            set_config(
+                doc,
+                target.as_ref().map(AsRef::as_ref),
+                build_std,
+                None,
+                true,
+            )
+        })
+    }
+
+    /// Complete the `config.toml` in `.cargo` of an existing crate with the
+    /// `build.target`, the `[unstable]` build-std settings and the `linker` of
+    /// `[target.<target>]`.
+    ///
+    /// Unlike [`create_config_toml`](Self::create_config_toml), only settings that are
+    /// missing are added, existing settings are never changed.
+    pub fn complete_config_toml(
+        &self,
+        target: &str,
+        build_std: BuildStd,
+        linker: impl AsRef<str>,
+    ) -> Result<()> {
+        let path = self.config_toml_path();
+
+        debug!("Completing Cargo config {}", path.display());
+
+        self.edit_toml(path, |doc| {
+            set_config(
+                doc,
+                Some(target),
+                build_std,
+                Some((target, linker.as_ref())),
+                false,
+            )
+        })
+    }
+
+    /// Set the `linker` of `[target.<target>]` in the `config.toml` in `.cargo`, for
+    /// example to `ldproxy`.
+    ///
+    /// The formatting, comments and all other settings of an existing file are preserved.
+    pub fn set_target_linker(&self, target: &str, linker: impl AsRef<str>) -> Result<()> {
+        let path = self.config_toml_path();
 
         debug!(
-            "Creating a Cargo config {}",
-            cargo_config_toml_path.display()
+            "Setting linker of target {} to {} in Cargo config {}",
+            target,
+            linker.as_ref(),
+            path.display()
         );
 
-        let mut data = String::new();
+        self.edit_toml(path, |doc| {
+            set_config(
+                doc,
+                None,
+                BuildStd::None,
+                Some((target, linker.as_ref())),
+                true,
+            )
+        })
+    }
 
-        if let Some(target) = target {
-            write!(
-                &mut data,
-                r#"[build]
-target = "{}"
-"#,
-                target.as_ref()
-            )?;
-        }
+    /// Get the path to the existing cargo config of this crate, or to the `config.toml`
+    /// in `.cargo` if there is none.
+    fn config_toml_path(&self) -> PathBuf {
+        let dir = self.0.join(".cargo");
+        let legacy = dir.join("config");
 
-        if build_std != BuildStd::None {
-            write!(
-                &mut data,
-                r#"
-[unstable]
-build-std = ["{}", "panic_abort"]
-build-std-features = ["panic_immediate_abort"]
-"#,
-                if build_std == BuildStd::Std {
-                    "std"
-                } else {
-                    "core"
-                }
-            )?;
+        if !dir.join("config.toml").exists() && legacy.is_file() {
+            legacy
+        } else {
+            dir.join("config.toml")
         }
+    }
+
+    /// Edit the TOML file at `path` (which is created if it doesn't exist) while
+    /// preserving its formatting.
+    fn edit_toml(
+        &self,
+        path: PathBuf,
+        f: impl FnOnce(&mut toml_edit::Document) -> Result<()>,
+    ) -> Result<()> {
+        let mut doc = if path.exists() {
+            fs::read_to_string(&path)
+                .with_context(|| anyhow!("Could not read '{}'", path.display()))?
+                .parse::<toml_edit::Document>()
+                .with_context(|| anyhow!("Could not parse '{}'", path.display()))?
+        } else {
+            Default::default()
+        };
+
+        f(&mut doc).with_context(|| anyhow!("Could not edit '{}'", path.display()))?;
 
-        fs::create_dir_all(cargo_config_toml_path.parent().unwrap())?;
-        fs::write(cargo_config_toml_path, data)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, doc.to_string())
+            .with_context(|| anyhow!("Could not write '{}'", path.display()))?;
 
         Ok(())
     }
@@ -186,10 +289,13 @@ build-std-features = ["panic_immediate_abort"]
         })
     }
 
+    #[deprecated(note = "use `Crate::load_config` to get the merged cargo configuration")]
     pub fn find_config_toml(&self) -> Result<Option<toml::Value>> {
+        #[allow(deprecated)]
         self.scan_config_toml(Some)
     }
 
+    #[deprecated(note = "use `Crate::load_config` to get the merged cargo configuration")]
     pub fn scan_config_toml<F, Q>(&self, f: F) -> Result<Option<Q>>
     where
         F: Fn(toml::Value) -> Option<Q>,
@@ -235,60 +341,151 @@ build-std-features = ["panic_immediate_abort"]
             .replace('-', "_")
     }
 
+    /// Get the `cargo metadata` of this crate's workspace.
+    pub fn metadata(&self) -> Result<CargoMetadata> {
+        CargoMetadata::load(&self.0)
+    }
+
+    /// Get the path to an artifact that is produced when building this crate with
+    /// `profile` (`dev`, `release` or a custom profile) for `target`.
+    ///
+    /// The path is based on the target directory reported by `cargo metadata`, so it
+    /// respects `CARGO_TARGET_DIR`, `build.target-dir` and workspaces. If this crate is
+    /// the root of a virtual workspace, all workspace members are searched for the
+    /// artifact.
+    pub fn get_artifact_path(
+        &self,
+        kind: ArtifactKind,
+        name: Option<&str>,
+        target: Option<&str>,
+        profile: &str,
+    ) -> Result<PathBuf> {
+        let metadata = self.metadata()?;
+        let artifact = metadata.find_target(metadata.package(&self.0), kind, name)?;
+
+        Ok(metadata.artifact_path(kind, &artifact.name, target, profile))
+    }
+
     /// Get the path to a binary that is produced when building this crate.
-    #[cfg(feature = "manifest")]
     pub fn get_binary_path<'a>(
         &self,
         release: bool,
         target: Option<&'a str>,
         binary: Option<&'a str>,
     ) -> Result<PathBuf> {
-        let bin_products = self.load_manifest()?.bin;
+        self.get_artifact_path(
+            ArtifactKind::Bin,
+            binary,
+            target,
+            if release { "release" } else { "dev" },
+        )
+    }
 
-        if bin_products.is_empty() {
-            anyhow::bail!("Not a binary crate");
-        }
+    /// Build this crate with `cargo build` and the additional `args` and return all
+    /// produced artifacts.
+    pub fn build_artifacts(
+        &self,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> Result<Vec<Artifact>> {
+        build_artifacts(&self.0, args)
+    }
 
-        let bin_product = if let Some(binary) = binary {
-            bin_products
-                .iter()
-                .find(|p| match p.name.as_ref() {
-                    Some(b) => b == binary,
-                    _ => false,
-                })
-                .ok_or_else(|| anyhow::anyhow!("Cannot locate binary with name {}", binary))?
-        } else {
-            if bin_products.len() > 1 {
-                anyhow::bail!(
-                    "This crate defines multiple binaries ({:?}), please specify binary name",
-                    bin_products
-                );
-            }
+    /// Load the merged cargo configuration of this crate.
+    ///
+    /// See [`CargoConfig`] for how the configuration is merged.
+    pub fn load_config(&self) -> Result<CargoConfig> {
+        CargoConfig::load(&self.0)
+    }
+
+    /// Get the default target that would be used when building this crate.
+    ///
+    /// If `build.target` contains multiple targets, the first one is returned.
+    pub fn get_default_target(&self) -> Result<Option<String>> {
+        Ok(self.load_config()?.targets().first().cloned())
+    }
+
+    /// Get the linker configured for `target`, or for the default target if `None`.
+    pub fn get_linker(&self, target: Option<&str>) -> Result<Option<PathBuf>> {
+        let config = self.load_config()?;
 
-            &bin_products[0]
+        let target = match target {
+            Some(target) => target,
+            None => match config.targets().first() {
+                Some(target) => target,
+                None => return Ok(None),
+            },
         };
 
-        let mut path = self.0.join("target");
+        Ok(config.target(target)?.linker)
+    }
+}
 
-        if let Some(target) = target {
-            path = path.join(target)
+/// Get the table `key` of `table`, inserting it if it doesn't exist.
+fn table_mut<'a>(table: &'a mut toml_edit::Table, key: &str) -> Result<&'a mut toml_edit::Table> {
+    table
+        .entry(key)
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("`{}` is not a table", key))
+}
+
+/// Set the `build.target`, the `[unstable]` build-std settings and the `linker` of
+/// `[target.<target>]` in the cargo config `doc`, replacing existing values only if
+/// `overwrite` is set.
+fn set_config(
+    doc: &mut toml_edit::Document,
+    target: Option<&str>,
+    build_std: BuildStd,
+    linker: Option<(&str, &str)>,
+    overwrite: bool,
+) -> Result<()> {
+    let set = |table: &mut toml_edit::Table, key: &str, value: toml_edit::Item| {
+        if overwrite || !table.contains_key(key) {
+            table[key] = value;
         }
+    };
 
-        Ok(path
-            .join(if release { "release" } else { "debug" })
-            .join(bin_product.name.as_ref().unwrap()))
+    if let Some(target) = target {
+        set(
+            table_mut(doc.as_table_mut(), "build")?,
+            "target",
+            toml_edit::value(target),
+        );
     }
 
-    /// Get the default target that would be used when building this crate.
-    pub fn get_default_target(&self) -> Result<Option<String>> {
-        self.scan_config_toml(|value| {
-            value
-                .get("build")
-                .and_then(|table| table.get("target"))
-                .and_then(|value| value.as_str())
-                .map(|str| str.to_owned())
-        })
+    if build_std != BuildStd::None {
+        let unstable = table_mut(doc.as_table_mut(), "unstable")?;
+        set(
+            unstable,
+            "build-std",
+            toml_edit::value(toml_edit::Array::from_iter([
+                if build_std == BuildStd::Std {
+                    "std"
+                } else {
+                    "core"
+                },
+                "panic_abort",
+            ])),
+        );
+        set(
+            unstable,
+            "build-std-features",
+            toml_edit::value(toml_edit::Array::from_iter(["panic_immediate_abort"])),
+        );
     }
+
+    if let Some((target, linker)) = linker {
+        let targets = table_mut(doc.as_table_mut(), "target")?;
+        targets.set_implicit(true);
+
+        set(
+            table_mut(targets, target)?,
+            "linker",
+            toml_edit::value(linker),
+        );
+    }
+
+    Ok(())
 }
 
 /// Set metadata that gets passed to all dependent's build scripts.
@@ -414,3 +611,121 @@ pub fn workspace_dir() -> Option<PathBuf> {
     };
     Some(PathBuf::from(env::var_os("OUT_DIR")?).pop_times(pop_count))
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn edit_preserves_formatting() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+
+        let manifest = r#"# The package
+[package]
+name = "my-lib" # inline comment
+version = "0.1.0"
+
+[lib]
+crate-type = ["rlib"]
+"#;
+        fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        fs::write(
+            dir.join(".cargo").join("config.toml"),
+            "# Keep this\n[build]\ntarget = \"xtensa-esp32-espidf\"\n",
+        )
+        .unwrap();
+
+        let krate = Crate::new(dir);
+        #[cfg(feature = "manifest")]
+        {
+            assert_eq!(krate.add_library_types(["staticlib"]).unwrap(), "my_lib");
+            assert_eq!(krate.add_library_types(["staticlib"]).unwrap(), "my_lib");
+            assert_eq!(
+                fs::read_to_string(dir.join("Cargo.toml")).unwrap(),
+                manifest.replace("[\"rlib\"]", "[\"rlib\", \"staticlib\"]")
+            );
+        }
+
+        krate
+            .create_config_toml(Some("riscv32imc-esp-espidf"), BuildStd::Std)
+            .unwrap();
+        krate
+            .set_target_linker("riscv32imc-esp-espidf", "ldproxy")
+            .unwrap();
+
+        let config = fs::read_to_string(dir.join(".cargo").join("config.toml")).unwrap();
+        assert!(config.starts_with("# Keep this\n[build]\ntarget = \"riscv32imc-esp-espidf\"\n"));
+        assert!(config.contains("build-std = [\"std\", \"panic_abort\"]"));
+        assert!(config.contains("[target.riscv32imc-esp-espidf]\nlinker = \"ldproxy\"\n"));
+        assert!(!config.contains("[target]"));
+
+        let cargo_config = krate.load_config().unwrap();
+        assert_eq!(
+            cargo_config.target("riscv32imc-esp-espidf").unwrap().linker,
+            Some(PathBuf::from("ldproxy"))
+        );
+    }
+
+    #[test]
+    fn complete_config() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+
+        let config = r#"[build]
+target = "xtensa-esp32-espidf" # keep
+
+[unstable]
+build-std = ["core"]
+
+[target.riscv32imc-esp-espidf]
+linker = "my-linker"
+"#;
+        fs::write(dir.join(".cargo").join("config.toml"), config).unwrap();
+
+        let krate = Crate::new(dir);
+        krate
+            .complete_config_toml("riscv32imc-esp-espidf", BuildStd::Std, "ldproxy")
+            .unwrap();
+        krate
+            .complete_config_toml("xtensa-esp32-espidf", BuildStd::Std, "ldproxy")
+            .unwrap();
+
+        let cargo_config = krate.load_config().unwrap();
+        assert_eq!(cargo_config.targets(), ["xtensa-esp32-espidf"]);
+        assert_eq!(
+            cargo_config.unstable.build_std,
+            Some(vec!["core".to_owned()])
+        );
+        assert_eq!(
+            cargo_config.unstable.build_std_features,
+            Some(vec!["panic_immediate_abort".to_owned()])
+        );
+        assert_eq!(
+            cargo_config.target("riscv32imc-esp-espidf").unwrap().linker,
+            Some(PathBuf::from("my-linker"))
+        );
+        assert_eq!(
+            cargo_config.target("xtensa-esp32-espidf").unwrap().linker,
+            Some(PathBuf::from("ldproxy"))
+        );
+
+        #[cfg(feature = "manifest")]
+        {
+            fs::write(
+                dir.join("Cargo.toml"),
+                "[package]\nname = \"my-bin\"\nversion = \"0.1.0\"\n",
+            )
+            .unwrap();
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(dir.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+            let err = krate.check_library().unwrap_err().to_string();
+            assert_eq!(err, "Not a library crate");
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use strum::Display;
+
+use crate::cmd_output;
+use crate::utils::CmdError;
+
+/// The kind of a cargo build artifact.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ArtifactKind {
+    /// The executable of a `[[bin]]` target.
+    Bin,
+    /// The executable of an `[[example]]` target.
+    Example,
+    /// The static library of a `[lib]` target with the `staticlib` crate type.
+    StaticLib,
+}
+
+impl ArtifactKind {
+    /// Whether `target` produces an artifact of this kind.
+    pub fn matches(&self, target: &PackageTarget) -> bool {
+        match self {
+            Self::Bin => target.kind.iter().any(|k| k == "bin"),
+            Self::Example => target.kind.iter().any(|k| k == "example"),
+            Self::StaticLib => target.crate_types.iter().any(|t| t == "staticlib"),
+        }
+    }
+
+    /// Get the file name of the artifact of the target `name` for the target `triple`
+    /// (or the host if `None`).
+    pub fn file_name(&self, name: &str, triple: Option<&str>) -> String {
+        let (windows, msvc) = match triple {
+            Some(triple) => (triple.contains("windows"), triple.ends_with("msvc")),
+            None => (cfg!(windows), cfg!(target_env = "msvc")),
+        };
+
+        match self {
+            Self::Bin | Self::Example if windows => format!("{}.exe", name),
+            Self::Bin | Self::Example => name.to_owned(),
+            Self::StaticLib if msvc => format!("{}.lib", name.replace('-', "_")),
+            Self::StaticLib => format!("lib{}.a", name.replace('-', "_")),
+        }
+    }
+}
+
+/// The output of `cargo metadata --no-deps`.
+///
+/// Only the parts needed to locate build artifacts are included.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CargoMetadata {
+    /// The target directory, which respects `CARGO_TARGET_DIR` and `build.target-dir`.
+    pub target_directory: PathBuf,
+    pub workspace_root: PathBuf,
+    /// All packages of the workspace.
+    pub packages: Vec<Package>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub id: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<PackageTarget>,
+}
+
+/// A cargo target (not to be confused with a target triple) of a package.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct PackageTarget {
+    pub name: String,
+    pub kind: Vec<String>,
+    pub crate_types: Vec<String>,
+    pub src_path: PathBuf,
+}
+
+impl CargoMetadata {
+    /// Run `cargo metadata` in `dir`.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let output = cmd_output!(
+            "cargo", "metadata", "--format-version", "1", "--no-deps";
+            current_dir=(dir.as_ref())
+        )?;
+
+        serde_json::from_str(&output).context("Could not parse the output of `cargo metadata`")
+    }
+
+    /// Get the package whose manifest is in `dir`.
+    pub fn package(&self, dir: impl AsRef<Path>) -> Option<&Package> {
+        let manifest = dir.as_ref().join("Cargo.toml");
+        // Cargo reports canonical manifest paths, so `dir` may be relative or contain
+        // symlinks.
+        let manifest = manifest.canonicalize().unwrap_or(manifest);
+
+        self.packages.iter().find(|p| {
+            p.manifest_path == manifest
+                || matches!(p.manifest_path.canonicalize(), Ok(path) if path == manifest)
+        })
+    }
+
+    /// Find the target that produces an artifact of `kind` named `name`.
+    ///
+    /// Only the targets of `package` are searched if set, otherwise the targets of all
+    /// packages of the workspace. If `name` is `None` there must be exactly one target of
+    /// `kind`.
+    pub fn find_target<'a>(
+        &'a self,
+        package: Option<&'a Package>,
+        kind: ArtifactKind,
+        name: Option<&str>,
+    ) -> Result<&'a PackageTarget> {
+        let packages = match package {
+            Some(package) => std::slice::from_ref(package),
+            None => &self.packages[..],
+        };
+
+        let mut targets = packages
+            .iter()
+            .flat_map(|p| &p.targets)
+            .filter(|t| kind.matches(t));
+
+        if let Some(name) = name {
+            targets
+                .find(|t| t.name == name)
+                .ok_or_else(|| anyhow!("Cannot locate {} target with name {}", kind, name))
+        } else {
+            let targets = targets.collect::<Vec<_>>();
+
+            match targets[..] {
+                [target] => Ok(target),
+                [] => bail!("No {} target found", kind),
+                _ => bail!(
+                    "Found multiple {} targets ({}), please specify the name",
+                    kind,
+                    targets
+                        .iter()
+                        .map(|t| &t.name[..])
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+    }
+
+    /// Get the output directory of `profile` for the target `triple` (or the host if
+    /// `None`).
+    pub fn profile_dir(&self, triple: Option<&str>, profile: &str) -> PathBuf {
+        let mut dir = self.target_directory.clone();
+        if let Some(triple) = triple {
+            // Target specification files are named after their file stem.
+            let triple = triple.strip_suffix(".json").map_or(triple, |t| {
+                Path::new(t)
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or(t)
+            });
+            dir.push(triple);
+        }
+
+        dir.join(match profile {
+            "dev" | "test" => "debug",
+            "bench" => "release",
+            profile => profile,
+        })
+    }
+
+    /// Get the path to the artifact of `kind` of the target `name`, built for the target
+    /// `triple` (or the host if `None`) with `profile`.
+    pub fn artifact_path(
+        &self,
+        kind: ArtifactKind,
+        name: &str,
+        triple: Option<&str>,
+        profile: &str,
+    ) -> PathBuf {
+        let mut dir = self.profile_dir(triple, profile);
+        if kind == ArtifactKind::Example {
+            dir.push("examples");
+        }
+
+        dir.join(kind.file_name(name, triple))
+    }
+}
+
+/// A message of `cargo build --message-format=json`.
+///
+/// Only the messages needed to locate build artifacts are modeled.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum BuildMessage {
+    CompilerArtifact(Artifact),
+    BuildFinished {
+        success: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+impl BuildMessage {
+    /// Parse all messages of `output`, ignoring lines that are not JSON.
+    pub fn parse_all(output: &str) -> Result<Vec<Self>> {
+        output
+            .lines()
+            .filter(|line| line.starts_with('{'))
+            .map(|line| serde_json::from_str(line).context("Invalid cargo message"))
+            .collect()
+    }
+}
+
+/// A `compiler-artifact` message of cargo.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Artifact {
+    pub package_id: String,
+    pub target: PackageTarget,
+    /// All files produced for the target.
+    pub filenames: Vec<PathBuf>,
+    /// The executable of a binary, example or test.
+    pub executable: Option<PathBuf>,
+    /// Whether the artifact was up to date.
+    pub fresh: bool,
+}
+
+impl Artifact {
+    /// Get the path to the file of `kind` of this artifact.
+    pub fn path(&self, kind: ArtifactKind) -> Option<&Path> {
+        if !kind.matches(&self.target) {
+            return None;
+        }
+
+        match kind {
+            ArtifactKind::Bin | ArtifactKind::Example => self.executable.as_deref(),
+            // Import libraries of `cdylib`s on msvc are named `<name>.dll.lib`.
+            ArtifactKind::StaticLib => self
+                .filenames
+                .iter()
+                .find(|f| {
+                    let name = f.to_string_lossy();
+                    name.ends_with(".a") || (name.ends_with(".lib") && !name.ends_with(".dll.lib"))
+                })
+                .map(PathBuf::as_path),
+        }
+    }
+}
+
+/// Run `cargo build` with `args` in `dir` and return all artifacts it produced.
+///
+/// Diagnostics are rendered to `stderr` like in a normal build.
+pub fn build_artifacts(
+    dir: impl AsRef<Path>,
+    args: impl IntoIterator<Item = impl AsRef<std::ffi::OsStr>>,
+) -> Result<Vec<Artifact>> {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("build")
+        .arg("--message-format=json-render-diagnostics")
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::piped());
+
+    let mut child = cmd.spawn().map_err(|e| CmdError::no_run(&cmd, e))?;
+
+    let mut artifacts = Vec::new();
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        if !line.starts_with('{') {
+            continue;
+        }
+
+        if let BuildMessage::CompilerArtifact(artifact) =
+            serde_json::from_str(&line).context("Invalid cargo message")?
+        {
+            artifacts.push(artifact);
+        }
+    }
+
+    CmdError::status_into_result(child.wait()?, &cmd, || None)?;
+
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn parse_messages() {
+        let output = r#"{"reason":"compiler-message","package_id":"app 0.1.0","message":{}}
+{"reason":"compiler-artifact","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"app","src_path":"/app/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/target/riscv32imc-esp-espidf/debug/app"],"executable":"/target/riscv32imc-esp-espidf/debug/app","fresh":false}
+{"reason":"compiler-artifact","package_id":"lib 0.1.0 (path+file:///lib)","manifest_path":"/lib/Cargo.toml","target":{"kind":["staticlib","rlib"],"crate_types":["staticlib","rlib"],"name":"my-lib","src_path":"/lib/src/lib.rs"},"filenames":["/target/release/libmy_lib.a","/target/release/libmy_lib.rlib"],"executable":null,"fresh":true}
+warning: not json
+{"reason":"build-finished","success":true}
+"#;
+
+        let messages = BuildMessage::parse_all(output).unwrap();
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(messages[0], BuildMessage::Other));
+        assert!(matches!(
+            messages[3],
+            BuildMessage::BuildFinished { success: true }
+        ));
+
+        let artifacts = messages
+            .iter()
+            .filter_map(|m| match m {
+                BuildMessage::CompilerArtifact(a) => Some(a),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            artifacts[0].path(ArtifactKind::Bin),
+            Some(Path::new("/target/riscv32imc-esp-espidf/debug/app"))
+        );
+        assert_eq!(artifacts[0].path(ArtifactKind::StaticLib), None);
+        assert_eq!(
+            artifacts[1].path(ArtifactKind::StaticLib),
+            Some(Path::new("/target/release/libmy_lib.a"))
+        );
+    }
+
+    #[test]
+    fn artifact_paths() {
+        let metadata: CargoMetadata = serde_json::from_str(
+            r#"{
+                "target_directory": "/ws/out",
+                "workspace_root": "/ws",
+                "packages": [],
+                "version": 1
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            metadata.artifact_path(ArtifactKind::Bin, "app", Some("xtensa-esp32-espidf"), "dev"),
+            Path::new("/ws/out/xtensa-esp32-espidf/debug/app")
+        );
+        assert_eq!(
+            metadata.artifact_path(
+                ArtifactKind::Example,
+                "blink",
+                Some("/specs/custom.json"),
+                "size-opt"
+            ),
+            Path::new("/ws/out/custom/size-opt/examples/blink")
+        );
+        assert_eq!(
+            metadata.artifact_path(
+                ArtifactKind::StaticLib,
+                "my-lib",
+                Some("thumbv7em-none-eabihf"),
+                "release"
+            ),
+            Path::new("/ws/out/thumbv7em-none-eabihf/release/libmy_lib.a")
+        );
+        assert_eq!(
+            metadata.artifact_path(
+                ArtifactKind::Bin,
+                "app",
+                Some("x86_64-pc-windows-msvc"),
+                "bench"
+            ),
+            Path::new("/ws/out/x86_64-pc-windows-msvc/release/app.exe")
+        );
+    }
+
+    #[test]
+    fn package_of_dir() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("app").join("src")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let manifest = dir.join("app").join("Cargo.toml");
+        fs::write(&manifest, "").unwrap();
+
+        let metadata: CargoMetadata = serde_json::from_value(serde_json::json!({
+            "target_directory": dir.join("target"),
+            "workspace_root": dir,
+            "packages": [{
+                "name": "app",
+                "id": "app 0.1.0",
+                "manifest_path": manifest,
+                "targets": [],
+            }],
+            "version": 1
+        }))
+        .unwrap();
+
+        assert_eq!(
+            metadata
+                .package(dir.join("app").join("src").join(".."))
+                .map(|p| &p.name[..]),
+            Some("app")
+        );
+        assert!(metadata.package(dir.join("lib")).is_none());
+    }
+}
@@ -0,0 +1,654 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use anyhow::{anyhow, Context, Result};
+use log::*;
+use serde::{Deserialize, Deserializer};
+
+/// The typed and merged cargo configuration of a directory.
+///
+/// Like cargo, the configuration is merged from (in increasing precedence)
+/// - `$CARGO_HOME/config.toml`,
+/// - the `.cargo/config.toml` (or `.cargo/config`) files of the directory and all its
+///   parents, where nearer files take precedence,
+/// - the `CARGO_BUILD_*`, `CARGO_TARGET_<triple>_*` and `CARGO_UNSTABLE_*` environment
+///   variables,
+/// - the `--config` arguments.
+///
+/// Tables are merged recursively and arrays are concatenated (except for `build.target`
+/// and `target.<triple>.runner`), other values are replaced by the value with higher
+/// precedence. Relative paths are resolved like cargo does.
+///
+/// Only the `[build]`, `[target.<triple>]`, `[unstable]` and `[env]` tables are
+/// modeled.
+#[derive(Clone, Debug, Default)]
+pub struct CargoConfig {
+    pub build: BuildConfig,
+    pub unstable: UnstableConfig,
+    pub env: BTreeMap<String, EnvConfigValue>,
+    /// All loaded configuration files, highest precedence first.
+    pub files: Vec<PathBuf>,
+    file_targets: toml::value::Table,
+    env_targets: BTreeMap<String, toml::Value>,
+    cli_targets: toml::value::Table,
+}
+
+impl CargoConfig {
+    /// Load the cargo configuration of `dir` with the environment of this process.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        CargoConfigLoader::new(dir.as_ref()).load()
+    }
+
+    /// Get the merged `[target.<triple>]` configuration of `triple`.
+    ///
+    /// `[target.'cfg(..)']` tables are not evaluated.
+    pub fn target(&self, triple: &str) -> Result<TargetConfig> {
+        let mut value = toml::Value::Table(Default::default());
+        let path = format!("target.{}", triple);
+
+        for layer in [
+            self.file_targets.get(triple),
+            self.env_targets.get(&env_triple(triple)),
+            self.cli_targets.get(triple),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            merge(&mut value, layer.clone(), &path);
+        }
+
+        value
+            .try_into()
+            .with_context(|| anyhow!("Invalid cargo configuration `[{}]`", path))
+    }
+
+    /// Get the targets of `build.target`.
+    pub fn targets(&self) -> &[String] {
+        self.build.target.as_deref().unwrap_or_default()
+    }
+}
+
+/// The `[build]` table of the cargo configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildConfig {
+    /// The targets to build for, which may be paths to target specification files.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub target: Option<Vec<String>>,
+    pub target_dir: Option<PathBuf>,
+    pub rustc: Option<PathBuf>,
+    pub rustc_wrapper: Option<PathBuf>,
+    #[serde(default, deserialize_with = "string_list")]
+    pub rustflags: Option<Vec<String>>,
+    pub jobs: Option<i32>,
+    pub incremental: Option<bool>,
+}
+
+/// A `[target.<triple>]` table of the cargo configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct TargetConfig {
+    pub linker: Option<PathBuf>,
+    #[serde(default, deserialize_with = "string_list")]
+    pub runner: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "string_list")]
+    pub rustflags: Option<Vec<String>>,
+}
+
+/// The `[unstable]` table of the cargo configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UnstableConfig {
+    #[serde(default, deserialize_with = "comma_list")]
+    pub build_std: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "comma_list")]
+    pub build_std_features: Option<Vec<String>>,
+    /// All other unstable flags.
+    #[serde(flatten)]
+    pub other: BTreeMap<String, toml::Value>,
+}
+
+/// A value of the `[env]` table of the cargo configuration.
+///
+/// If `relative` is set, `value` has already been resolved.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(from = "RawEnvConfigValue")]
+pub struct EnvConfigValue {
+    pub value: String,
+    pub force: bool,
+    pub relative: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawEnvConfigValue {
+    Value(String),
+    Table {
+        value: String,
+        #[serde(default)]
+        force: bool,
+        #[serde(default)]
+        relative: bool,
+    },
+}
+
+impl From<RawEnvConfigValue> for EnvConfigValue {
+    fn from(raw: RawEnvConfigValue) -> Self {
+        match raw {
+            RawEnvConfigValue::Value(value) => Self {
+                value,
+                force: false,
+                relative: false,
+            },
+            RawEnvConfigValue::Table {
+                value,
+                force,
+                relative,
+            } => Self {
+                value,
+                force,
+                relative,
+            },
+        }
+    }
+}
+
+/// A builder for loading a [`CargoConfig`].
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct CargoConfigLoader {
+    pub(crate) dir: PathBuf,
+    pub(crate) cargo_home: Option<PathBuf>,
+    pub(crate) config_args: Vec<String>,
+    pub(crate) env: bool,
+}
+
+impl CargoConfigLoader {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            cargo_home: cargo_home(),
+            config_args: Vec::new(),
+            env: true,
+        }
+    }
+
+    /// Set the cargo home directory, `$CARGO_HOME` or `~/.cargo` by default.
+    pub fn cargo_home(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cargo_home = Some(dir.into());
+        self
+    }
+
+    /// Add a `--config` argument, which is either a `KEY=VALUE` pair in TOML syntax or
+    /// the path to an additional configuration file.
+    pub fn config(mut self, arg: impl Into<String>) -> Self {
+        self.config_args.push(arg.into());
+        self
+    }
+
+    /// Whether to apply the `CARGO_*` environment variables, `true` by default.
+    pub fn env(mut self, value: bool) -> Self {
+        self.env = value;
+        self
+    }
+
+    pub fn load(self) -> Result<CargoConfig> {
+        let vars = if self.env {
+            env::vars().collect()
+        } else {
+            Vec::new()
+        };
+
+        self.load_with_vars(vars)
+    }
+
+    fn load_with_vars(self, vars: Vec<(String, String)>) -> Result<CargoConfig> {
+        let cwd = env::current_dir()?;
+
+        let mut files: Vec<PathBuf> = Vec::new();
+        for dir in self
+            .dir
+            .ancestors()
+            .map(|dir| dir.join(".cargo"))
+            .chain(self.cargo_home.clone())
+        {
+            let file = ["config.toml", "config"]
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|file| file.is_file());
+
+            if let Some(file) = file {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+
+        let mut config = toml::Value::Table(Default::default());
+        for file in files.iter().rev() {
+            debug!("Loading cargo configuration {}", file.display());
+
+            merge(&mut config, read_config(file)?, "");
+        }
+
+        let (env_config, env_targets) = env_layer(vars, &cwd)?;
+        merge(&mut config, env_config, "");
+
+        let mut cli = toml::Value::Table(Default::default());
+        for arg in &self.config_args {
+            let path = Path::new(arg);
+            let value = if path.is_file() {
+                files.insert(0, path.to_owned());
+                read_config(path)?
+            } else {
+                let mut value = arg.parse::<toml::Value>().with_context(|| {
+                    anyhow!("Invalid --config argument '{}', expected KEY=VALUE", arg)
+                })?;
+                normalize(&mut value, &cwd);
+                value
+            };
+
+            merge(&mut cli, value, "");
+        }
+
+        let cli_targets = match cli.as_table_mut().and_then(|t| t.remove("target")) {
+            Some(toml::Value::Table(targets)) => targets,
+            _ => Default::default(),
+        };
+        merge(&mut config, cli, "");
+
+        let file_targets = match config.as_table_mut().and_then(|t| t.remove("target")) {
+            Some(toml::Value::Table(targets)) => targets,
+            _ => Default::default(),
+        };
+
+        #[derive(Deserialize)]
+        struct Raw {
+            #[serde(default)]
+            build: BuildConfig,
+            #[serde(default)]
+            unstable: UnstableConfig,
+            #[serde(default)]
+            env: BTreeMap<String, EnvConfigValue>,
+        }
+
+        let raw: Raw = config.try_into().context("Invalid cargo configuration")?;
+
+        Ok(CargoConfig {
+            build: raw.build,
+            unstable: raw.unstable,
+            env: raw.env,
+            files,
+            file_targets,
+            env_targets,
+            cli_targets,
+        })
+    }
+}
+
+/// Get the cargo home directory, `$CARGO_HOME` or `~/.cargo`.
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cargo")))
+}
+
+fn read_config(file: &Path) -> Result<toml::Value> {
+    let mut value = fs::read_to_string(file)
+        .with_context(|| anyhow!("Could not read '{}'", file.display()))?
+        .parse::<toml::Value>()
+        .with_context(|| anyhow!("Could not parse '{}'", file.display()))?;
+
+    // Relative paths are relative to the parent of the directory containing the file.
+    let dir = file.parent().unwrap_or(file);
+    normalize(&mut value, dir.parent().unwrap_or(dir));
+
+    Ok(value)
+}
+
+/// Resolve the paths of `value` relative to `base` and split whitespace separated
+/// flags the way cargo does.
+fn normalize(value: &mut toml::Value, base: &Path) {
+    let split = |value: &mut toml::Value| {
+        if let toml::Value::String(s) = value {
+            *value = split_whitespace(s);
+        }
+    };
+
+    if let Some(build) = value.get_mut("build") {
+        if let Some(target_dir) = build.get_mut("target-dir") {
+            resolve_path(target_dir, base, true);
+        }
+        for key in ["rustflags", "rustdocflags"] {
+            if let Some(value) = build.get_mut(key) {
+                split(value);
+            }
+        }
+        for key in ["rustc", "rustc-wrapper"] {
+            if let Some(value) = build.get_mut(key) {
+                resolve_path(value, base, false);
+            }
+        }
+
+        // Targets ending with `.json` are target specification files.
+        let targets = match build.get_mut("target") {
+            Some(toml::Value::Array(targets)) => targets.iter_mut().collect(),
+            Some(target) => vec![target],
+            None => vec![],
+        };
+        for target in targets {
+            if target.as_str().map_or(false, |t| t.ends_with(".json")) {
+                resolve_path(target, base, true);
+            }
+        }
+    }
+
+    if let Some(toml::Value::Table(targets)) = value.get_mut("target") {
+        for (_, target) in targets.iter_mut() {
+            if let Some(linker) = target.get_mut("linker") {
+                resolve_path(linker, base, false);
+            }
+            for key in ["runner", "rustflags"] {
+                if let Some(value) = target.get_mut(key) {
+                    split(value);
+                }
+            }
+        }
+    }
+
+    if let Some(toml::Value::Table(vars)) = value.get_mut("env") {
+        for (_, var) in vars.iter_mut() {
+            if var.get("relative").and_then(toml::Value::as_bool) == Some(true) {
+                if let Some(value) = var.get_mut("value") {
+                    resolve_path(value, base, true);
+                }
+            }
+        }
+    }
+}
+
+/// Resolve the string `value` relative to `base`.
+///
+/// Unless `always` is set, only values containing a path separator are resolved, all
+/// others are program names that are searched in `PATH`.
+fn resolve_path(value: &mut toml::Value, base: &Path, always: bool) {
+    if let toml::Value::String(s) = value {
+        if always || s.contains('/') || s.contains('\\') {
+            *s = base.join(&*s).to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Merge `src` into `dest` where `src` has higher precedence.
+fn merge(dest: &mut toml::Value, src: toml::Value, path: &str) {
+    match (dest, src) {
+        (toml::Value::Table(dest), toml::Value::Table(src)) => {
+            for (key, value) in src {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                match dest.get_mut(&key) {
+                    Some(dest) => merge(dest, value, &path),
+                    None => {
+                        dest.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(dest), toml::Value::Array(src))
+            if path != "build.target"
+                && !(path.starts_with("target.") && path.ends_with(".runner")) =>
+        {
+            dest.extend(src)
+        }
+        (dest, src) => *dest = src,
+    }
+}
+
+/// Get the configuration of the `CARGO_*` environment variables and the
+/// `CARGO_TARGET_<triple>_*` tables by their triple in environment variable form.
+fn env_layer(
+    vars: Vec<(String, String)>,
+    cwd: &Path,
+) -> Result<(toml::Value, BTreeMap<String, toml::Value>)> {
+    let key = |name: &str| name.to_ascii_lowercase().replace('_', "-");
+
+    let mut build = toml::value::Table::new();
+    let mut unstable = toml::value::Table::new();
+    let mut targets = BTreeMap::new();
+
+    for (var, value) in vars {
+        if let Some(name) = var.strip_prefix("CARGO_BUILD_") {
+            let value = match name {
+                "RUSTFLAGS" | "RUSTDOCFLAGS" => split_whitespace(&value),
+                "JOBS" => toml::Value::Integer(
+                    value
+                        .parse()
+                        .with_context(|| anyhow!("Invalid value of {}: '{}'", var, value))?,
+                ),
+                "INCREMENTAL" => toml::Value::Boolean(
+                    value
+                        .parse()
+                        .with_context(|| anyhow!("Invalid value of {}: '{}'", var, value))?,
+                ),
+                _ => toml::Value::String(value),
+            };
+            build.insert(key(name), value);
+        } else if let Some(name) = var.strip_prefix("CARGO_UNSTABLE_") {
+            let value = match name {
+                "BUILD_STD" | "BUILD_STD_FEATURES" => toml::Value::Array(
+                    value
+                        .split(',')
+                        .map(|s| toml::Value::String(s.trim().to_owned()))
+                        .collect(),
+                ),
+                _ => match value.as_str() {
+                    "true" => toml::Value::Boolean(true),
+                    "false" => toml::Value::Boolean(false),
+                    _ => toml::Value::String(value),
+                },
+            };
+            unstable.insert(key(name), value);
+        } else if let Some(name) = var.strip_prefix("CARGO_TARGET_") {
+            let entry = ["LINKER", "RUNNER", "RUSTFLAGS"].iter().find_map(|&key| {
+                name.strip_suffix(key)
+                    .and_then(|triple| triple.strip_suffix('_'))
+                    .map(|triple| (triple, key))
+            });
+
+            if let Some((triple, name)) = entry {
+                let value = match name {
+                    "LINKER" => toml::Value::String(value),
+                    _ => split_whitespace(&value),
+                };
+
+                let target = targets
+                    .entry(triple.to_owned())
+                    .or_insert_with(|| toml::Value::Table(Default::default()));
+                if let toml::Value::Table(target) = target {
+                    target.insert(key(name), value);
+                }
+            }
+        }
+    }
+
+    let mut config = toml::value::Table::new();
+    config.insert("build".into(), toml::Value::Table(build));
+    config.insert("unstable".into(), toml::Value::Table(unstable));
+
+    let mut config = toml::Value::Table(config);
+    normalize(&mut config, cwd);
+    for (_, target) in targets.iter_mut() {
+        if let Some(linker) = target.get_mut("linker") {
+            resolve_path(linker, cwd, false);
+        }
+    }
+
+    Ok((config, targets))
+}
+
+fn split_whitespace(s: &str) -> toml::Value {
+    toml::Value::Array(
+        s.split_whitespace()
+            .map(|s| toml::Value::String(s.to_owned()))
+            .collect(),
+    )
+}
+
+/// Get the environment variable form of `triple`, as used in `CARGO_TARGET_<triple>_*`.
+fn env_triple(triple: &str) -> String {
+    triple.to_ascii_uppercase().replace(['-', '.'], "_")
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Deserialize a string or an array of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<OneOrMany>::deserialize(d)?.map(|v| match v {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    }))
+}
+
+/// Deserialize a whitespace separated string or an array of strings.
+fn string_list<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<OneOrMany>::deserialize(d)?.map(|v| match v {
+        OneOrMany::One(s) => s.split_whitespace().map(str::to_owned).collect(),
+        OneOrMany::Many(v) => v,
+    }))
+}
+
+/// Deserialize a comma separated string or an array of strings.
+fn comma_list<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    Ok(Option::<OneOrMany>::deserialize(d)?.map(|v| match v {
+        OneOrMany::One(s) => s.split(',').map(|s| s.trim().to_owned()).collect(),
+        OneOrMany::Many(v) => v,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn merge_hierarchy() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        let home = dir.join("home");
+        let root = dir.join("root");
+        let krate = root.join("crate");
+        fs::create_dir_all(&home).unwrap();
+        fs::create_dir_all(root.join(".cargo")).unwrap();
+        fs::create_dir_all(krate.join(".cargo")).unwrap();
+
+        fs::write(
+            home.join("config.toml"),
+            r#"
+            [build]
+            jobs = 4
+            rustflags = ["-Chome"]
+
+            [env]
+            HOME_VAR = "home"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            root.join(".cargo").join("config.toml"),
+            r#"
+            [build]
+            target = "riscv32imc-esp-espidf"
+            rustflags = "-Croot1 -Croot2"
+
+            [target.riscv32imc-esp-espidf]
+            linker = "ldproxy"
+            runner = ["espflash", "--monitor"]
+
+            [unstable]
+            build-std = ["std", "panic_abort"]
+
+            [env]
+            IDF_PATH = { value = "esp-idf", relative = true }
+            "#,
+        )
+        .unwrap();
+        // The legacy file name without extension.
+        fs::write(
+            krate.join(".cargo").join("config"),
+            r#"
+            [target.riscv32imc-esp-espidf]
+            linker = "tools/ld"
+            runner = "espflash flash"
+
+            [env]
+            HOME_VAR = { value = "crate", force = true }
+            "#,
+        )
+        .unwrap();
+
+        let config = CargoConfigLoader::new(&krate)
+            .cargo_home(&home)
+            .config("build.jobs = 8")
+            .config("target.riscv32imc-esp-espidf.rustflags = [\"-Ccli\"]")
+            .load_with_vars(vec![
+                ("CARGO_BUILD_RUSTFLAGS".into(), "-Cenv".into()),
+                (
+                    "CARGO_UNSTABLE_BUILD_STD_FEATURES".into(),
+                    "panic_immediate_abort".into(),
+                ),
+                (
+                    "CARGO_TARGET_RISCV32IMC_ESP_ESPIDF_RUSTFLAGS".into(),
+                    "-Ctarget-env".into(),
+                ),
+                (
+                    "CARGO_TARGET_XTENSA_ESP32_ESPIDF_LINKER".into(),
+                    "xtensa-ld".into(),
+                ),
+            ])
+            .unwrap();
+
+        assert_eq!(config.files.len(), 3);
+        assert_eq!(config.targets(), ["riscv32imc-esp-espidf"]);
+        assert_eq!(config.build.jobs, Some(8));
+        assert_eq!(
+            config.build.rustflags.as_deref().unwrap(),
+            ["-Chome", "-Croot1", "-Croot2", "-Cenv"]
+        );
+        assert_eq!(
+            config.unstable.build_std.as_deref().unwrap(),
+            ["std", "panic_abort"]
+        );
+        assert_eq!(
+            config.unstable.build_std_features.as_deref().unwrap(),
+            ["panic_immediate_abort"]
+        );
+        assert_eq!(
+            config.env["IDF_PATH"].value,
+            root.join("esp-idf").to_string_lossy()
+        );
+        assert_eq!(config.env["HOME_VAR"].value, "crate");
+        assert!(config.env["HOME_VAR"].force);
+
+        let target = config.target("riscv32imc-esp-espidf").unwrap();
+        assert_eq!(target.linker, Some(krate.join("tools/ld")));
+        // Runners are replaced, not concatenated.
+        assert_eq!(target.runner.as_deref().unwrap(), ["espflash", "flash"]);
+        assert_eq!(
+            target.rustflags.as_deref().unwrap(),
+            ["-Ctarget-env", "-Ccli"]
+        );
+
+        let target = config.target("xtensa-esp32-espidf").unwrap();
+        assert_eq!(target.linker, Some(PathBuf::from("xtensa-ld")));
+    }
+}
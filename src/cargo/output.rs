@@ -0,0 +1,661 @@
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use strum::{Display, EnumString};
+
+/// The syntax of the instructions a build script prints.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Syntax {
+    /// `cargo:KEY=VALUE`, which is supported by all cargo versions.
+    Legacy,
+    /// `cargo::KEY=VALUE`, which requires cargo 1.77 or newer.
+    DoubleColon,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Self::Legacy
+    }
+}
+
+/// The kind of a library linked with `rustc-link-lib`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum LinkKind {
+    Static,
+    Dylib,
+    Framework,
+}
+
+/// A modifier of `rustc-link-lib`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum LinkModifier {
+    /// Only valid for `static` libraries.
+    Bundle,
+    /// Only valid for `static` libraries.
+    WholeArchive,
+    /// Only valid for `dylib` and `framework` libraries.
+    AsNeeded,
+    Verbatim,
+}
+
+/// The kind of a `rustc-link-search` directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Display, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum LinkSearchKind {
+    Dependency,
+    Crate,
+    Native,
+    Framework,
+    All,
+}
+
+/// The targets a `rustc-link-arg*` argument is passed for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LinkArgTargets {
+    /// All targets (`rustc-link-arg`).
+    All,
+    /// All binaries (`rustc-link-arg-bins`).
+    Bins,
+    /// The binary with this name (`rustc-link-arg-bin`).
+    Bin(String),
+    /// All tests (`rustc-link-arg-tests`).
+    Tests,
+    /// All examples (`rustc-link-arg-examples`).
+    Examples,
+    /// All benchmarks (`rustc-link-arg-benches`).
+    Benches,
+    /// The `cdylib` library (`rustc-link-arg-cdylib`).
+    Cdylib,
+}
+
+/// An instruction of a build script to cargo.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Directive {
+    RerunIfChanged(PathBuf),
+    RerunIfEnvChanged(String),
+    LinkArg {
+        targets: LinkArgTargets,
+        arg: String,
+    },
+    LinkLib {
+        kind: Option<LinkKind>,
+        modifiers: Vec<(bool, LinkModifier)>,
+        name: String,
+        rename: Option<String>,
+    },
+    LinkSearch {
+        kind: Option<LinkSearchKind>,
+        path: PathBuf,
+    },
+    Cfg {
+        name: String,
+        value: Option<String>,
+    },
+    /// A cfg that is expected by `rustc --check-cfg` with any of `values`, where `None`
+    /// means that the cfg has no value.
+    CheckCfg {
+        name: String,
+        values: Vec<Option<String>>,
+    },
+    Env {
+        key: String,
+        value: String,
+    },
+    Warning(String),
+    Metadata {
+        key: String,
+        value: String,
+    },
+}
+
+impl Directive {
+    /// Get the line that instructs cargo, using `syntax`.
+    pub fn to_line(&self, syntax: Syntax) -> String {
+        let prefix = match syntax {
+            Syntax::Legacy => "cargo:",
+            Syntax::DoubleColon => "cargo::",
+        };
+
+        format!("{}{}", prefix, DirectiveFmt(self, syntax))
+    }
+}
+
+struct DirectiveFmt<'a>(&'a Directive, Syntax);
+
+impl Display for DirectiveFmt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Directive::RerunIfChanged(path) => write!(f, "rerun-if-changed={}", path.display()),
+            Directive::RerunIfEnvChanged(var) => write!(f, "rerun-if-env-changed={}", var),
+            Directive::LinkArg { targets, arg } => match targets {
+                LinkArgTargets::All => write!(f, "rustc-link-arg={}", arg),
+                LinkArgTargets::Bins => write!(f, "rustc-link-arg-bins={}", arg),
+                LinkArgTargets::Bin(bin) => write!(f, "rustc-link-arg-bin={}={}", bin, arg),
+                LinkArgTargets::Tests => write!(f, "rustc-link-arg-tests={}", arg),
+                LinkArgTargets::Examples => write!(f, "rustc-link-arg-examples={}", arg),
+                LinkArgTargets::Benches => write!(f, "rustc-link-arg-benches={}", arg),
+                LinkArgTargets::Cdylib => write!(f, "rustc-link-arg-cdylib={}", arg),
+            },
+            Directive::LinkLib {
+                kind,
+                modifiers,
+                name,
+                rename,
+            } => {
+                write!(f, "rustc-link-lib=")?;
+                if let Some(kind) = kind {
+                    write!(f, "{}", kind)?;
+                    for (i, (enabled, modifier)) in modifiers.iter().enumerate() {
+                        let sep = if i == 0 { ':' } else { ',' };
+                        write!(f, "{}{}{}", sep, if *enabled { '+' } else { '-' }, modifier)?;
+                    }
+                    write!(f, "=")?;
+                }
+                write!(f, "{}", name)?;
+                if let Some(rename) = rename {
+                    write!(f, ":{}", rename)?;
+                }
+                Ok(())
+            }
+            Directive::LinkSearch { kind, path } => match kind {
+                Some(kind) => write!(f, "rustc-link-search={}={}", kind, path.display()),
+                None => write!(f, "rustc-link-search={}", path.display()),
+            },
+            Directive::Cfg { name, value } => match value {
+                Some(value) => write!(f, "rustc-cfg={}=\"{}\"", name, escape(value)),
+                None => write!(f, "rustc-cfg={}", name),
+            },
+            Directive::CheckCfg { name, values } => {
+                write!(f, "rustc-check-cfg=cfg({}", name)?;
+                if values != &[None] {
+                    write!(f, ", values(")?;
+                    for (i, value) in values.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        match value {
+                            Some(value) => write!(f, "\"{}\"", escape(value))?,
+                            None => write!(f, "none()")?,
+                        }
+                    }
+                    write!(f, ")")?;
+                }
+                write!(f, ")")
+            }
+            Directive::Env { key, value } => write!(f, "rustc-env={}={}", key, value),
+            Directive::Warning(warning) => write!(f, "warning={}", warning),
+            Directive::Metadata { key, value } => match self.1 {
+                Syntax::Legacy => write!(f, "{}={}", key, value),
+                Syntax::DoubleColon => write!(f, "metadata={}={}", key, value),
+            },
+        }
+    }
+}
+
+/// Keys that cargo interprets as instructions with the legacy syntax, and which can
+/// therefore not be used as metadata keys.
+const RESERVED_KEYS: &[&str] = &[
+    "rerun-if-changed",
+    "rerun-if-env-changed",
+    "rustc-link-arg",
+    "rustc-link-arg-bin",
+    "rustc-link-arg-bins",
+    "rustc-link-arg-tests",
+    "rustc-link-arg-examples",
+    "rustc-link-arg-benches",
+    "rustc-link-arg-cdylib",
+    "rustc-link-lib",
+    "rustc-link-search",
+    "rustc-flags",
+    "rustc-cfg",
+    "rustc-check-cfg",
+    "rustc-env",
+    "warning",
+    "error",
+    "metadata",
+];
+
+/// A builder for the output of a build script.
+///
+/// All instructions are validated and collected, so that they can be inspected (for
+/// example in tests) before they are [emitted](Self::emit) to cargo.
+///
+/// ```ignore
+/// let mut output = BuildOutput::new();
+/// output
+///     .link_lib(Some(LinkKind::Static), &[(true, LinkModifier::WholeArchive)], "espidf")?
+///     .link_search(Some(LinkSearchKind::Native), out_dir)?
+///     .check_cfg("esp_idf_version", &[Some("4.4"), Some("5.0")])?;
+/// output.emit();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct BuildOutput {
+    syntax: Syntax,
+    directives: Vec<Directive>,
+}
+
+impl BuildOutput {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the syntax of the emitted instructions, [`Syntax::Legacy`] by default.
+    ///
+    /// Fails if a collected metadata key is reserved with `syntax`.
+    pub fn syntax(&mut self, syntax: Syntax) -> Result<&mut Self> {
+        for directive in &self.directives {
+            if let Directive::Metadata { key, .. } = directive {
+                reserved_key(syntax, key)?;
+            }
+        }
+
+        self.syntax = syntax;
+        Ok(self)
+    }
+
+    /// Get all collected directives.
+    pub fn directives(&self) -> &[Directive] {
+        &self.directives
+    }
+
+    /// Get the lines of all collected directives.
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.directives.iter().map(move |d| d.to_line(self.syntax))
+    }
+
+    /// Write all collected directives to `w`.
+    pub fn write(&self, mut w: impl Write) -> io::Result<()> {
+        for line in self.lines() {
+            writeln!(w, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Print all collected directives to `stdout`, where cargo reads them.
+    pub fn emit(&self) {
+        for line in self.lines() {
+            println!("{}", line);
+        }
+    }
+
+    /// Add a directive after validating it.
+    pub fn directive(&mut self, directive: Directive) -> Result<&mut Self> {
+        self.validate(&directive)?;
+        self.directives.push(directive);
+        Ok(self)
+    }
+
+    /// Rerun the build script if the file or directory has changed.
+    pub fn rerun_if_changed(&mut self, path: impl AsRef<Path>) -> Result<&mut Self> {
+        self.directive(Directive::RerunIfChanged(path.as_ref().to_owned()))
+    }
+
+    /// Rerun the build script if the environment variable has changed.
+    pub fn rerun_if_env_changed(&mut self, var: impl Into<String>) -> Result<&mut Self> {
+        self.directive(Directive::RerunIfEnvChanged(var.into()))
+    }
+
+    /// Pass an argument to the linker when linking all targets.
+    pub fn link_arg(&mut self, arg: impl Into<String>) -> Result<&mut Self> {
+        self.link_arg_for(LinkArgTargets::All, arg)
+    }
+
+    /// Pass an argument to the linker when linking the binaries.
+    pub fn link_arg_bins(&mut self, arg: impl Into<String>) -> Result<&mut Self> {
+        self.link_arg_for(LinkArgTargets::Bins, arg)
+    }
+
+    /// Pass an argument to the linker when linking the tests.
+    pub fn link_arg_tests(&mut self, arg: impl Into<String>) -> Result<&mut Self> {
+        self.link_arg_for(LinkArgTargets::Tests, arg)
+    }
+
+    /// Pass an argument to the linker when linking the examples.
+    pub fn link_arg_examples(&mut self, arg: impl Into<String>) -> Result<&mut Self> {
+        self.link_arg_for(LinkArgTargets::Examples, arg)
+    }
+
+    /// Pass an argument to the linker when linking `targets`.
+    pub fn link_arg_for(
+        &mut self,
+        targets: LinkArgTargets,
+        arg: impl Into<String>,
+    ) -> Result<&mut Self> {
+        self.directive(Directive::LinkArg {
+            targets,
+            arg: arg.into(),
+        })
+    }
+
+    /// Link the library `name` of `kind` with `modifiers`, which are enabled (`+`) or
+    /// disabled (`-`).
+    pub fn link_lib(
+        &mut self,
+        kind: Option<LinkKind>,
+        modifiers: &[(bool, LinkModifier)],
+        name: impl Into<String>,
+    ) -> Result<&mut Self> {
+        self.directive(Directive::LinkLib {
+            kind,
+            modifiers: modifiers.to_vec(),
+            name: name.into(),
+            rename: None,
+        })
+    }
+
+    /// Add a library search directory of `kind`.
+    pub fn link_search(
+        &mut self,
+        kind: Option<LinkSearchKind>,
+        path: impl AsRef<Path>,
+    ) -> Result<&mut Self> {
+        self.directive(Directive::LinkSearch {
+            kind,
+            path: path.as_ref().to_owned(),
+        })
+    }
+
+    /// Set the cfg `name` (with an optional `value`) for conditional compilation.
+    pub fn cfg(&mut self, name: impl Into<String>, value: Option<&str>) -> Result<&mut Self> {
+        self.directive(Directive::Cfg {
+            name: name.into(),
+            value: value.map(str::to_owned),
+        })
+    }
+
+    /// Declare the cfg `name` as expected with any of `values`, where `None` allows the
+    /// cfg without a value.
+    pub fn check_cfg(
+        &mut self,
+        name: impl Into<String>,
+        values: &[Option<&str>],
+    ) -> Result<&mut Self> {
+        self.directive(Directive::CheckCfg {
+            name: name.into(),
+            values: values.iter().map(|v| v.map(str::to_owned)).collect(),
+        })
+    }
+
+    /// Set an environment variable that is available when compiling this package.
+    pub fn env(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<&mut Self> {
+        self.directive(Directive::Env {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    /// Display a warning, which may contain multiple lines.
+    pub fn warning(&mut self, warning: impl Display) -> Result<&mut Self> {
+        for line in warning.to_string().lines() {
+            self.directive(Directive::Warning(line.to_owned()))?;
+        }
+
+        Ok(self)
+    }
+
+    /// Set metadata that gets passed to the build scripts of all dependents.
+    pub fn metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<&mut Self> {
+        self.directive(Directive::Metadata {
+            key: key.into(),
+            value: value.into(),
+        })
+    }
+
+    fn validate(&self, directive: &Directive) -> Result<()> {
+        match directive {
+            Directive::RerunIfChanged(path) => single_line("path", path_str(path)?)?,
+            Directive::RerunIfEnvChanged(var) => key("environment variable", var)?,
+            Directive::LinkArg { targets, arg } => {
+                if let LinkArgTargets::Bin(bin) = targets {
+                    key("binary name", bin)?;
+                }
+                single_line("linker argument", arg)?;
+            }
+            Directive::LinkLib {
+                kind,
+                modifiers,
+                name,
+                rename,
+            } => {
+                key("library name", name)?;
+                if name.contains(':') {
+                    bail!("Invalid library name '{}'", name);
+                }
+                if let Some(rename) = rename {
+                    key("library name", rename)?;
+                }
+
+                if !modifiers.is_empty() {
+                    let kind = kind.ok_or_else(|| {
+                        anyhow!("Link modifiers of library '{}' require a kind", name)
+                    })?;
+
+                    for (i, (_, modifier)) in modifiers.iter().enumerate() {
+                        if modifiers[..i].iter().any(|(_, m)| m == modifier) {
+                            bail!("Duplicate link modifier '{}'", modifier);
+                        }
+
+                        let valid = match modifier {
+                            LinkModifier::Bundle | LinkModifier::WholeArchive => {
+                                kind == LinkKind::Static
+                            }
+                            LinkModifier::AsNeeded => kind != LinkKind::Static,
+                            LinkModifier::Verbatim => true,
+                        };
+                        if !valid {
+                            bail!(
+                                "Link modifier '{}' is not valid for {} library '{}'",
+                                modifier,
+                                kind,
+                                name
+                            );
+                        }
+                    }
+                }
+            }
+            Directive::LinkSearch { path, .. } => single_line("path", path_str(path)?)?,
+            Directive::Cfg { name, value } => {
+                ident("cfg", name)?;
+                if let Some(value) = value {
+                    single_line("cfg value", value)?;
+                }
+            }
+            Directive::CheckCfg { name, values } => {
+                ident("cfg", name)?;
+                for value in values.iter().flatten() {
+                    single_line("cfg value", value)?;
+                }
+            }
+            Directive::Env { key: name, value } => {
+                key("environment variable", name)?;
+                single_line("environment variable value", value)?;
+            }
+            Directive::Warning(warning) => single_line("warning", warning)?,
+            Directive::Metadata { key: name, value } => {
+                key("metadata key", name)?;
+                reserved_key(self.syntax, name)?;
+                single_line("metadata value", value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for BuildOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines() {
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn single_line(what: &str, value: &str) -> Result<()> {
+    if value.contains(['\n', '\r']) {
+        bail!(
+            "Invalid {} '{}': must not contain line breaks",
+            what,
+            value.escape_debug()
+        );
+    }
+
+    Ok(())
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("Path '{}' is not valid UTF-8", path.display()))
+}
+
+fn key(what: &str, value: &str) -> Result<()> {
+    single_line(what, value)?;
+    if value.is_empty() || value.contains('=') {
+        bail!(
+            "Invalid {} '{}': must be non-empty and not contain '='",
+            what,
+            value
+        );
+    }
+
+    Ok(())
+}
+
+fn reserved_key(syntax: Syntax, key: &str) -> Result<()> {
+    if syntax == Syntax::Legacy && RESERVED_KEYS.contains(&key) {
+        bail!(
+            "Metadata key '{}' is reserved for cargo instructions with the legacy syntax",
+            key
+        );
+    }
+
+    Ok(())
+}
+
+fn ident(what: &str, value: &str) -> Result<()> {
+    let mut chars = value.chars();
+    let valid = chars
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        bail!(
+            "Invalid {} name '{}': must be an identifier",
+            what,
+            value.escape_debug()
+        );
+    }
+
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let mut output = BuildOutput::new();
+        output
+            .link_lib(
+                Some(LinkKind::Static),
+                &[
+                    (true, LinkModifier::WholeArchive),
+                    (false, LinkModifier::Bundle),
+                ],
+                "espidf",
+            )
+            .unwrap()
+            .link_lib(None, &[], "m")
+            .unwrap()
+            .link_search(Some(LinkSearchKind::Native), "/out/lib")
+            .unwrap()
+            .link_arg_bins("-Tlink.x")
+            .unwrap()
+            .link_arg_for(LinkArgTargets::Bin("app".into()), "-Wl,--gc-sections")
+            .unwrap()
+            .cfg("esp_idf_version", Some("4.4"))
+            .unwrap()
+            .check_cfg("esp_idf_version", &[Some("4.4"), Some("5.\"0")])
+            .unwrap()
+            .check_cfg("esp32", &[None])
+            .unwrap()
+            .check_cfg("esp32_rev", &[None, Some("3")])
+            .unwrap()
+            .metadata("EMBUILD_LINK_ARGS", "a b")
+            .unwrap()
+            .warning("first\nsecond")
+            .unwrap();
+
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "cargo:rustc-link-lib=static:+whole-archive,-bundle=espidf",
+                "cargo:rustc-link-lib=m",
+                "cargo:rustc-link-search=native=/out/lib",
+                "cargo:rustc-link-arg-bins=-Tlink.x",
+                "cargo:rustc-link-arg-bin=app=-Wl,--gc-sections",
+                "cargo:rustc-cfg=esp_idf_version=\"4.4\"",
+                "cargo:rustc-check-cfg=cfg(esp_idf_version, values(\"4.4\", \"5.\\\"0\"))",
+                "cargo:rustc-check-cfg=cfg(esp32)",
+                "cargo:rustc-check-cfg=cfg(esp32_rev, values(none(), \"3\"))",
+                "cargo:EMBUILD_LINK_ARGS=a b",
+                "cargo:warning=first",
+                "cargo:warning=second",
+            ]
+        );
+
+        output.syntax(Syntax::DoubleColon).unwrap();
+        assert_eq!(
+            output.lines().nth(9).unwrap(),
+            "cargo::metadata=EMBUILD_LINK_ARGS=a b"
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let mut output = BuildOutput::new();
+
+        assert!(output.cfg("1abc", None).is_err());
+        assert!(output.cfg("esp-idf", None).is_err());
+        assert!(output.env("A=B", "value").is_err());
+        assert!(output.env("A", "multi\nline").is_err());
+        assert!(output.metadata("rustc-cfg", "x").is_err());
+        assert!(output
+            .link_lib(None, &[(true, LinkModifier::Verbatim)], "foo")
+            .is_err());
+        assert!(output
+            .link_lib(
+                Some(LinkKind::Dylib),
+                &[(true, LinkModifier::Bundle)],
+                "foo"
+            )
+            .is_err());
+        assert!(output.directives().is_empty());
+
+        output.syntax(Syntax::DoubleColon).unwrap();
+        assert!(output.metadata("rustc-cfg", "x").is_ok());
+
+        // Switching back would emit the metadata as a `rustc-cfg` instruction.
+        assert!(output.syntax(Syntax::Legacy).is_err());
+        assert_eq!(
+            output.lines().next().unwrap(),
+            "cargo::metadata=rustc-cfg=x"
+        );
+    }
+}
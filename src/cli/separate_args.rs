@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 /// An iterator that parses a command as windows command-line arguments and returns them
 /// as [`String`]s.
 ///
@@ -154,6 +156,51 @@ impl<'a> Iterator for WindowsCommandArgs<'a> {
     }
 }
 
+/// Quote `arg` so that it is parsed as a single argument by [`WindowsCommandArgs`] (and
+/// any program following the MSDN rules for parsing the command line).
+///
+/// Arguments that don't need quoting are returned unchanged.
+pub fn quote_windows_arg(arg: &str) -> Cow<'_, str> {
+    if !arg.is_empty() && !arg.contains(&[' ', '\t', '\n', '\x0b', '"'][..]) {
+        return Cow::Borrowed(arg);
+    }
+
+    let mut result = String::with_capacity(arg.len() + 2);
+    result.push('"');
+
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Backslashes before a quote must be escaped, as well as the quote itself.
+                result.extend(std::iter::repeat('\\').take(backslashes * 2 + 1));
+                result.push('"');
+                backslashes = 0;
+            }
+            c => {
+                result.extend(std::iter::repeat('\\').take(backslashes));
+                result.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    // Backslashes before the closing quote must be escaped.
+    result.extend(std::iter::repeat('\\').take(backslashes * 2));
+    result.push('"');
+
+    Cow::Owned(result)
+}
+
+/// Join `args` into a single windows command-line string, quoting each argument as
+/// needed (see [`quote_windows_arg`]).
+pub fn join_windows_args<'a>(args: impl IntoIterator<Item = &'a str>) -> String {
+    args.into_iter()
+        .map(quote_windows_arg)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 pub use shlex::join as join_unix_args;
 pub use shlex::quote as quote_unix_arg;
 pub use shlex::Shlex as UnixCommandArgs;
@@ -189,4 +236,25 @@ mod test {
         assert_eq!(iter.next(), Some("rest a b   "));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn join_windows_args_roundtrip() {
+        let args = [
+            "simple",
+            "with space",
+            r"C:\path\to\dir\",
+            r"C:\path with space\",
+            r#"quote"inside"#,
+            r#"back\"slash"#,
+            r"trailing\\",
+            "tab\tchar",
+        ];
+
+        let joined = join_windows_args(args.iter().copied());
+        let parsed = WindowsCommandArgs::new(&joined).collect::<Vec<_>>();
+
+        assert_eq!(parsed, args);
+        assert_eq!(quote_windows_arg("simple"), "simple");
+        assert_eq!(quote_windows_arg(r"a b\"), r#""a b\\""#);
+    }
 }
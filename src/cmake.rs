@@ -12,7 +12,7 @@ use anyhow::{Error, Result};
 
 use strum::{Display, EnumIter, EnumString, IntoStaticStr};
 
-use crate::build::{CInclArgs, LinkArgsBuilder};
+use crate::build::{CInclArgs, CompileCommandsBuilder, LinkArgsBuilder};
 use crate::cli::NativeCommandArgs;
 use crate::cmd_output;
 
@@ -154,12 +154,71 @@ impl TryFrom<&file_api::codemodel::target::CompileGroup> for CInclArgs {
     }
 }
 
+impl TryFrom<&file_api::codemodel::target::CompileGroup> for CompileCommandsBuilder {
+    type Error = Error;
+
+    fn try_from(value: &file_api::codemodel::target::CompileGroup) -> Result<Self, Self::Error> {
+        let args = value
+            .compile_command_fragments
+            .iter()
+            .flat_map(|f| NativeCommandArgs::new(&f.fragment))
+            .chain(value.defines.iter().map(|d| format!("-D{}", d.define)))
+            .chain(value.includes.iter().map(|i| format!("-I{}", i.path)))
+            .chain(
+                value
+                    .sysroot
+                    .iter()
+                    .map(|s| format!("--sysroot={}", s.path.display())),
+            )
+            .collect::<Vec<_>>();
+
+        Ok(CompileCommandsBuilder::new().args(args))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Write;
 
     use super::*;
 
+    #[test]
+    fn compile_commands_from_compile_group() {
+        let group: file_api::codemodel::target::CompileGroup =
+            serde_json::from_value(serde_json::json!({
+                "language": "C",
+                "compileCommandFragments": [{ "fragment": "-mlongcalls -Os" }],
+                "includes": [{ "path": "/idf/include" }],
+                "defines": [{ "define": "ESP_PLATFORM" }, { "define": "IDF_VER=1" }],
+                "sysroot": { "path": "/sysroot" },
+            }))
+            .unwrap();
+
+        let commands = CompileCommandsBuilder::try_from(&group)
+            .unwrap()
+            .compiler("/opt/bin/gcc")
+            .directory("/build")
+            .sources(["/src/main.c"])
+            .build()
+            .unwrap();
+
+        assert_eq!(commands.commands.len(), 1);
+        assert_eq!(
+            commands.commands[0].arguments,
+            [
+                "/opt/bin/gcc",
+                "-mlongcalls",
+                "-Os",
+                "-DESP_PLATFORM",
+                "-DIDF_VER=1",
+                "-I/idf/include",
+                "--sysroot=/sysroot",
+                "-c",
+                "/src/main.c",
+            ]
+        );
+    }
+
     #[test]
     fn test_get_script_variables() {
         let mut script = tempfile::NamedTempFile::new().unwrap();
@@ -1,13 +1,17 @@
 /// A quick and dirty parser for the .config files generated by kconfig systems like
 /// the ESP-IDF one.
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env, fmt, fs,
     io::{self, BufRead, Read},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use strum::Display;
+
+use crate::build::CfgArgs;
+use crate::cargo::BuildOutput;
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Tristate {
@@ -119,3 +123,744 @@ fn parse_config_value(str: impl AsRef<str>) -> Option<Value> {
         return None;
     })
 }
+
+const CONFIG_PREFIX: &str = "CONFIG_";
+
+/// A .config file (like the `sdkconfig` of ESP-IDF) that can be edited without losing
+/// its comments and the order of its options.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    lines: Vec<ConfigLine>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ConfigLine {
+    text: String,
+    /// The key (with the `CONFIG_` prefix) and the raw value if the line sets an option.
+    option: Option<(String, String)>,
+}
+
+impl ConfigFile {
+    pub fn parse(content: &str) -> Self {
+        Self {
+            lines: content
+                .lines()
+                .map(|line| ConfigLine {
+                    text: line.to_owned(),
+                    option: parse_config_line(line),
+                })
+                .collect(),
+        }
+    }
+
+    /// Try to load the .config file at `path`.
+    pub fn try_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        Ok(Self::parse(&fs::read_to_string(path).with_context(
+            || anyhow!("Could not read '{}'", path.display()),
+        )?))
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        fs::write(path, self.to_string())
+            .with_context(|| anyhow!("Could not write '{}'", path.display()))
+    }
+
+    /// Get the raw value of the option `key` (with or without the `CONFIG_` prefix).
+    ///
+    /// Options that are commented out as `# CONFIG_<key> is not set` have the value `n`.
+    /// String values include their quotes.
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&str> {
+        let key = config_key(key.as_ref());
+
+        self.options()
+            .filter(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+            .last()
+    }
+
+    /// Set the option `key` (with or without the `CONFIG_` prefix) to the raw `value`.
+    ///
+    /// Existing lines of the option are replaced, otherwise the option is appended.
+    pub fn set(&mut self, key: impl AsRef<str>, value: impl Into<String>) {
+        let key = config_key(key.as_ref());
+        let text = config_line(&key, &value.into());
+        let line = ConfigLine {
+            option: parse_config_line(&text),
+            text,
+        };
+
+        let mut found = false;
+        for existing in self
+            .lines
+            .iter_mut()
+            .filter(|l| matches!(&l.option, Some((k, _)) if *k == key))
+        {
+            *existing = line.clone();
+            found = true;
+        }
+
+        if !found {
+            self.lines.push(line);
+        }
+    }
+
+    /// Remove the option `key` (with or without the `CONFIG_` prefix), so that its
+    /// default value applies.
+    ///
+    /// Returns whether the option was set.
+    pub fn unset(&mut self, key: impl AsRef<str>) -> bool {
+        let key = config_key(key.as_ref());
+        let len = self.lines.len();
+
+        self.lines
+            .retain(|l| !matches!(&l.option, Some((k, _)) if *k == key));
+
+        self.lines.len() != len
+    }
+
+    /// All options with their raw values, in the order of the file.
+    pub fn options(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines
+            .iter()
+            .filter_map(|l| l.option.as_ref())
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+impl fmt::Display for ConfigFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line.text)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_config_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+
+    if let Some(comment) = line.strip_prefix('#') {
+        let key = comment.trim().strip_suffix("is not set")?.trim();
+
+        (key.starts_with(CONFIG_PREFIX) && !key.contains(char::is_whitespace))
+            .then(|| (key.to_owned(), "n".to_owned()))
+    } else {
+        let (key, value) = line.split_once('=')?;
+
+        Some((key.trim().to_owned(), value.trim().to_owned()))
+    }
+}
+
+fn config_line(key: &str, value: &str) -> String {
+    if value == "n" {
+        format!("# {} is not set", key)
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+fn config_key(key: &str) -> String {
+    if key.starts_with(CONFIG_PREFIX) {
+        key.to_owned()
+    } else {
+        format!("{}{}", CONFIG_PREFIX, key)
+    }
+}
+
+/// The type of a kconfig symbol.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum SymbolType {
+    Bool,
+    Tristate,
+    String,
+    Int,
+    Hex,
+    Unknown,
+}
+
+impl SymbolType {
+    /// Guess the type of the raw `value` of an option in a .config file.
+    ///
+    /// `y` and `n` are guessed as [`Bool`](Self::Bool), although they are valid
+    /// [`Tristate`](Self::Tristate) values too.
+    pub fn of_value(value: &str) -> Self {
+        match value {
+            "y" | "n" => Self::Bool,
+            "m" => Self::Tristate,
+            _ if value.starts_with('"') => Self::String,
+            _ if value.starts_with("0x") || value.starts_with("0X") => Self::Hex,
+            _ if value.parse::<i64>().is_ok() => Self::Int,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Check that `value` is valid for this type and convert it to the raw value of an
+    /// option in a .config file.
+    ///
+    /// Strings may be passed with or without quotes, hex values with or without the `0x`
+    /// prefix. Values of [`Unknown`](Self::Unknown) type must already be raw values.
+    pub fn to_config_value(&self, value: &str) -> Result<String> {
+        let invalid = || anyhow!("'{}' is not a valid {} value", value, self);
+
+        Ok(match self {
+            Self::Bool if value == "y" || value == "n" => value.to_owned(),
+            Self::Tristate if value == "y" || value == "n" || value == "m" => value.to_owned(),
+            Self::Int => value
+                .parse::<i64>()
+                .map(|_| value.to_owned())
+                .map_err(|_| invalid())?,
+            Self::Hex => {
+                let digits = value
+                    .strip_prefix("0x")
+                    .or_else(|| value.strip_prefix("0X"))
+                    .unwrap_or(value);
+                u64::from_str_radix(digits, 16).map_err(|_| invalid())?;
+
+                format!("0x{}", digits)
+            }
+            Self::String if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') => {
+                value.to_owned()
+            }
+            Self::String => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            Self::Unknown if Self::of_value(value) != Self::Unknown => value.to_owned(),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// A symbol (`config` or `menuconfig` entry) declared in a `Kconfig` source file.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Symbol {
+    /// The name without the `CONFIG_` prefix.
+    pub name: String,
+    pub ty: SymbolType,
+    /// All string literals of the `default` properties.
+    pub defaults: Vec<String>,
+}
+
+/// Try to load all symbols declared in the `Kconfig` source file at `path` and all files
+/// it sources.
+///
+/// Environment variables (`$VAR`, `${VAR}` and `$(VAR)`) in `source` statements are
+/// expanded. `source` paths are relative to the directory of `path` (or `$srctree` if
+/// set), `rsource` paths are relative to the directory of the sourcing file. Missing
+/// files of `osource` and `orsource` are ignored.
+pub fn try_symbols_from_kconfig_file(path: impl AsRef<Path>) -> Result<Vec<Symbol>> {
+    let path = path.as_ref();
+    let srctree = env::var_os("srctree")
+        .map(PathBuf::from)
+        .or_else(|| path.parent().map(Path::to_owned))
+        .unwrap_or_default();
+
+    let mut parser = KconfigParser {
+        srctree,
+        visited: HashSet::new(),
+        symbols: Vec::new(),
+    };
+    parser.parse_file(path)?;
+
+    Ok(parser.symbols)
+}
+
+struct KconfigParser {
+    srctree: PathBuf,
+    visited: HashSet<PathBuf>,
+    symbols: Vec<Symbol>,
+}
+
+impl KconfigParser {
+    fn parse_file(&mut self, path: &Path) -> Result<()> {
+        if !self.visited.insert(path.to_owned()) {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| anyhow!("Could not read '{}'", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        // The index of the symbol whose properties are parsed.
+        let mut current: Option<usize> = None;
+        // The indentation of the help text that is skipped, once it is known.
+        let mut help: Option<Option<usize>> = None;
+
+        let mut lines = content.lines();
+        while let Some(line) = lines.next() {
+            if let Some(help_indent) = help {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let indent = indentation(line);
+                match help_indent {
+                    None => {
+                        help = Some(Some(indent));
+                        continue;
+                    }
+                    Some(help_indent) if indent >= help_indent => continue,
+                    Some(_) => help = None,
+                }
+            }
+
+            let mut line = line.to_owned();
+            while line.ends_with('\\') {
+                line.pop();
+                line.push_str(lines.next().unwrap_or_default());
+            }
+
+            let line = strip_comment(&line);
+            let mut words = line.split_whitespace();
+            let keyword = match words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            let rest = line.trim_start()[keyword.len()..].trim();
+
+            match keyword {
+                "config" | "menuconfig" => {
+                    current = Some(self.symbol(rest));
+                }
+                "bool" | "tristate" | "string" | "int" | "hex" | "def_bool" | "def_tristate" => {
+                    if let Some(current) = current {
+                        let symbol = &mut self.symbols[current];
+                        if symbol.ty == SymbolType::Unknown {
+                            symbol.ty = match keyword {
+                                "bool" | "def_bool" => SymbolType::Bool,
+                                "tristate" | "def_tristate" => SymbolType::Tristate,
+                                "string" => SymbolType::String,
+                                "int" => SymbolType::Int,
+                                _ => SymbolType::Hex,
+                            };
+                        }
+                    }
+                }
+                "default" => {
+                    if let (Some(current), Some(value)) = (current, string_literal(rest)) {
+                        let defaults = &mut self.symbols[current].defaults;
+                        if !defaults.contains(&value) {
+                            defaults.push(value);
+                        }
+                    }
+                }
+                "help" | "---help---" => help = Some(None),
+                "source" | "rsource" | "osource" | "orsource" => {
+                    current = None;
+
+                    let file = match string_literal(rest) {
+                        Some(file) => expand_env(&file),
+                        None => bail!("Invalid {} in '{}': {}", keyword, path.display(), rest),
+                    };
+                    if file.is_empty() {
+                        continue;
+                    }
+
+                    let file = if keyword.ends_with("rsource") {
+                        dir.join(file)
+                    } else {
+                        self.srctree.join(file)
+                    };
+
+                    if file.is_file() {
+                        self.parse_file(&file)?;
+                    } else if !keyword.starts_with('o') {
+                        bail!(
+                            "File '{}' sourced in '{}' does not exist",
+                            file.display(),
+                            path.display()
+                        );
+                    }
+                }
+                "choice" | "endchoice" | "menu" | "endmenu" | "if" | "endif" | "comment"
+                | "mainmenu" => current = None,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the index of the symbol `name`, adding it if it doesn't exist yet.
+    fn symbol(&mut self, name: &str) -> usize {
+        match self.symbols.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => {
+                self.symbols.push(Symbol {
+                    name: name.to_owned(),
+                    ty: SymbolType::Unknown,
+                    defaults: Vec::new(),
+                });
+                self.symbols.len() - 1
+            }
+        }
+    }
+}
+
+fn indentation(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 8 } else { 1 })
+        .sum()
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('#', None) => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+/// Get the string literal at the start of `s`.
+fn string_literal(s: &str) -> Option<String> {
+    let mut chars = s.chars();
+    let quote = chars.next().filter(|&c| c == '"' || c == '\'')?;
+
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            c if c == quote => return Some(value),
+            c => value.push(c),
+        }
+    }
+
+    None
+}
+
+/// Expand `$VAR`, `${VAR}` and `$(VAR)` in `s` with the values of the environment
+/// variables, which are empty if the variable isn't set.
+fn expand_env(s: &str) -> String {
+    let mut result = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, len) = match rest.chars().next() {
+            Some(open @ ('{' | '(')) => {
+                let close = if open == '{' { '}' } else { ')' };
+                match rest.find(close) {
+                    Some(end) => (&rest[1..end], end + 1),
+                    None => (&rest[1..], rest.len()),
+                }
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+
+        result.push_str(&env::var(name).unwrap_or_default());
+        rest = &rest[len..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// The `rustc-check-cfg` declarations of the cfgs derived from kconfig options.
+///
+/// Declare all cfgs that [`Value::to_rustc_cfg`] can produce, so that rustc reports
+/// unknown cfg names and values. Options of type `bool` and `tristate` are declared
+/// without a value, options of type `string` with the values of their `default`
+/// properties and all values that are [added](Self::values), where an empty string is a
+/// cfg without a value. `int` and `hex` options are never set as cfgs and are therefore
+/// not declared.
+///
+/// ```ignore
+/// let mut check_cfg = kconfig::CheckCfg::new();
+/// check_cfg
+///     .symbols("esp_idf", kconfig::try_symbols_from_kconfig_file(idf_path.join("Kconfig"))?)
+///     .values("esp_idf", kconfig::try_from_json_file(sdkconfig_json)?);
+/// check_cfg.emit()?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CheckCfg {
+    /// The expected values of every cfg, where `None` is a cfg without a value.
+    cfgs: BTreeMap<String, BTreeSet<Option<String>>>,
+}
+
+impl CheckCfg {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Declare the cfgs of the kconfig `symbols` with `prefix`.
+    pub fn symbols(
+        &mut self,
+        prefix: impl AsRef<str>,
+        symbols: impl IntoIterator<Item = Symbol>,
+    ) -> &mut Self {
+        for symbol in symbols {
+            let name = cfg_name(prefix.as_ref(), &symbol.name);
+
+            match symbol.ty {
+                SymbolType::Bool | SymbolType::Tristate => self.cfg(name, [None]),
+                SymbolType::String => self.cfg(name, symbol.defaults.into_iter().map(string_value)),
+                SymbolType::Int | SymbolType::Hex | SymbolType::Unknown => {}
+            }
+        }
+
+        self
+    }
+
+    /// Declare the cfgs of the kconfig option `values` with `prefix`, as loaded by
+    /// [`try_from_json`] or [`try_from_config`].
+    pub fn values(
+        &mut self,
+        prefix: impl AsRef<str>,
+        values: impl IntoIterator<Item = (String, Value)>,
+    ) -> &mut Self {
+        for (key, value) in values {
+            let name = cfg_name(prefix.as_ref(), &key);
+
+            match value {
+                Value::Tristate(_) => self.cfg(name, [None]),
+                Value::String(value) => self.cfg(name, [string_value(value)]),
+            }
+        }
+
+        self
+    }
+
+    /// Declare the cfgs of `cfg_args`.
+    pub fn cfg_args(&mut self, cfg_args: &CfgArgs) -> &mut Self {
+        for arg in &cfg_args.args {
+            let (name, value) = parse_cfg(arg);
+            self.cfg(name.to_owned(), [value]);
+        }
+
+        self
+    }
+
+    /// Add the `rustc-check-cfg` declarations to `output`.
+    pub fn output(&self, output: &mut BuildOutput) -> Result<()> {
+        for (name, values) in &self.cfgs {
+            let values = values.iter().map(Option::as_deref).collect::<Vec<_>>();
+
+            output.check_cfg(name, &values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Print the `rustc-check-cfg` declarations to cargo.
+    ///
+    /// `rustc-check-cfg` requires cargo 1.80 or newer, older versions ignore it.
+    pub fn emit(&self) -> Result<()> {
+        let mut output = BuildOutput::new();
+        self.output(&mut output)?;
+        output.emit();
+
+        Ok(())
+    }
+
+    fn cfg(&mut self, name: String, values: impl IntoIterator<Item = Option<String>>) {
+        self.cfgs.entry(name).or_default().extend(values);
+    }
+}
+
+fn cfg_name(prefix: &str, key: &str) -> String {
+    format!("{}_{}", prefix.to_lowercase(), key.to_lowercase())
+}
+
+/// Split a `--cfg` argument like `name` or `name="value"` into its name and value.
+fn parse_cfg(arg: &str) -> (&str, Option<String>) {
+    match arg.split_once('=') {
+        Some((name, value)) => {
+            let value = value.strip_prefix('"').unwrap_or(value);
+            let value = value.strip_suffix('"').unwrap_or(value);
+            (name, Some(value.replace("\\\"", "\"")))
+        }
+        None => (arg, None),
+    }
+}
+
+/// The cfg value of a string option, which [`Value::to_rustc_cfg`] omits if it is empty.
+fn string_value(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn symbols() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("component")).unwrap();
+
+        fs::write(
+            dir.join("Kconfig"),
+            r#"mainmenu "Test"
+# A comment
+config FREERTOS_HZ
+    int "Tick rate"
+    default 100
+
+menu "Component" # trailing comment
+    rsource "component/Kconfig"
+    osource "$EMBUILD_KCONFIG_TEST_MISSING/Kconfig"
+endmenu
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("component").join("Kconfig"),
+            "menuconfig SPIRAM\n\tbool \"Support for external RAM\"\n\thelp\n\t\tSome help text\n\n\t\tconfig NOT_A_SYMBOL\n\
+             \nchoice LOG_LEVEL\n\tprompt \"Log level\"\nconfig LOG_LEVEL_INFO\n\tbool \"Info\"\nendchoice\n\
+             config LOG_TAG\n\tstring\n\tdefault \"app\" if SPIRAM\n\tdefault 'main'\n",
+        )
+        .unwrap();
+
+        let symbols = try_symbols_from_kconfig_file(dir.join("Kconfig")).unwrap();
+        assert_eq!(
+            symbols
+                .iter()
+                .map(|s| (&s.name[..], s.ty))
+                .collect::<Vec<_>>(),
+            [
+                ("FREERTOS_HZ", SymbolType::Int),
+                ("SPIRAM", SymbolType::Bool),
+                ("LOG_LEVEL_INFO", SymbolType::Bool),
+                ("LOG_TAG", SymbolType::String),
+            ]
+        );
+        assert_eq!(symbols[3].defaults, ["app", "main"]);
+
+        let mut check_cfg = CheckCfg::new();
+        check_cfg.symbols("esp_idf", symbols).values(
+            "esp_idf",
+            [("LOG_TAG".to_owned(), Value::String("custom".into()))],
+        );
+
+        let mut output = BuildOutput::new();
+        check_cfg.output(&mut output).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "cargo:rustc-check-cfg=cfg(esp_idf_log_level_info)",
+                "cargo:rustc-check-cfg=cfg(esp_idf_log_tag, values(\"app\", \"custom\", \"main\"))",
+                "cargo:rustc-check-cfg=cfg(esp_idf_spiram)",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_cfg_roundtrip() {
+        let values = [
+            ("SPIRAM", Value::Tristate(Tristate::True)),
+            ("LOG_TAG", Value::String("app".into())),
+            ("LOG_TAG", Value::String("".into())),
+            ("NAME", Value::String("say \"hi\"".into())),
+            ("EMPTY", Value::String("".into())),
+        ]
+        .map(|(key, value)| (key.to_owned(), value));
+
+        let mut check_cfg = CheckCfg::new();
+        check_cfg.values("esp_idf", values.clone());
+        check_cfg.cfg_args(&CfgArgs {
+            args: vec!["foo".into(), "foo=\"x\"".into()],
+        });
+
+        let cfgs = values
+            .iter()
+            .filter_map(|(key, value)| value.to_rustc_cfg("esp_idf", key))
+            .chain(["foo".to_owned(), "foo=\"x\"".to_owned()]);
+        for cfg in cfgs {
+            let (name, value) = parse_cfg(&cfg);
+            assert!(
+                check_cfg
+                    .cfgs
+                    .get(name)
+                    .map_or(false, |v| v.contains(&value)),
+                "{} is not declared",
+                cfg
+            );
+        }
+
+        let mut output = BuildOutput::new();
+        check_cfg.output(&mut output).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "cargo:rustc-check-cfg=cfg(esp_idf_empty)",
+                "cargo:rustc-check-cfg=cfg(esp_idf_log_tag, values(none(), \"app\"))",
+                "cargo:rustc-check-cfg=cfg(esp_idf_name, values(\"say \\\"hi\\\"\"))",
+                "cargo:rustc-check-cfg=cfg(esp_idf_spiram)",
+                "cargo:rustc-check-cfg=cfg(foo, values(none(), \"x\"))",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_file() {
+        let mut config = ConfigFile::parse(
+            "#\n# Automatically generated file. DO NOT EDIT.\n#\nCONFIG_FREERTOS_HZ=100\n\
+             # CONFIG_SPIRAM is not set\nCONFIG_LOG_TAG=\"app\"\n# CONFIG_SPIRAM is a comment\n",
+        );
+
+        assert_eq!(config.get("FREERTOS_HZ"), Some("100"));
+        assert_eq!(config.get("CONFIG_SPIRAM"), Some("n"));
+        assert_eq!(config.get("LOG_TAG"), Some("\"app\""));
+        assert_eq!(config.options().count(), 3);
+
+        config.set("SPIRAM", "y");
+        config.set("FREERTOS_HZ", "n");
+        config.set("CONFIG_PARTITION_TABLE_OFFSET", "0x8000");
+        assert!(config.unset("LOG_TAG"));
+        assert!(!config.unset("LOG_TAG"));
+
+        assert_eq!(
+            config.to_string(),
+            "#\n# Automatically generated file. DO NOT EDIT.\n#\n# CONFIG_FREERTOS_HZ is not set\n\
+             CONFIG_SPIRAM=y\n# CONFIG_SPIRAM is a comment\nCONFIG_PARTITION_TABLE_OFFSET=0x8000\n"
+        );
+        assert_eq!(ConfigFile::parse(&config.to_string()), config);
+    }
+
+    #[test]
+    fn config_values() {
+        assert_eq!(SymbolType::of_value("m"), SymbolType::Tristate);
+        assert_eq!(SymbolType::of_value("-3"), SymbolType::Int);
+        assert_eq!(SymbolType::of_value("0x1F"), SymbolType::Hex);
+        assert_eq!(SymbolType::of_value("\"\""), SymbolType::String);
+        assert_eq!(SymbolType::of_value("yes"), SymbolType::Unknown);
+
+        assert_eq!(SymbolType::Bool.to_config_value("y").unwrap(), "y");
+        assert!(SymbolType::Bool.to_config_value("m").is_err());
+        assert!(SymbolType::Int.to_config_value("0x10").is_err());
+        assert_eq!(SymbolType::Hex.to_config_value("ff").unwrap(), "0xff");
+        assert!(SymbolType::Hex.to_config_value("0x").is_err());
+        assert_eq!(
+            SymbolType::String.to_config_value(r#"say "hi""#).unwrap(),
+            r#""say \"hi\"""#
+        );
+        assert_eq!(
+            SymbolType::String.to_config_value("\"quoted\"").unwrap(),
+            "\"quoted\""
+        );
+        assert!(SymbolType::Unknown.to_config_value("yes").is_err());
+    }
+}
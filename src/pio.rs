@@ -1,12 +1,13 @@
+pub mod ini;
 pub mod project;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
 
 use anyhow::{bail, Result};
 use log::*;
@@ -393,27 +394,61 @@ impl Pio {
     }
 
     pub fn libraries(&self, names: &[impl AsRef<str>]) -> Result<Vec<Library>> {
+        self.lib_search(
+            names
+                .iter()
+                .flat_map(|name| [OsStr::new("--name"), OsStr::new(name.as_ref())]),
+            None,
+        )
+    }
+
+    /// Search the PlatformIO registry for libraries, returning at most `limit` of them.
+    ///
+    /// `args` are passed to `pio lib search`, e.g. `["json", "--framework", "espidf"]`.
+    pub fn search_libraries(
+        &self,
+        args: &[impl AsRef<OsStr>],
+        limit: usize,
+    ) -> Result<Vec<Library>> {
+        self.lib_search(args, Some(limit))
+    }
+
+    fn lib_search<I>(&self, args: I, limit: Option<usize>) -> Result<Vec<Library>>
+    where
+        I: IntoIterator + Clone,
+        I::Item: AsRef<OsStr>,
+    {
         let mut res = Vec::<Library>::new();
 
-        loop {
+        for page in 1.. {
             let mut cmd = self.cmd();
 
-            cmd.arg("lib").arg("search");
-
-            for name in names {
-                cmd.arg("--name").arg(name.as_ref());
-            }
+            cmd.arg("lib")
+                .arg("search")
+                .args(args.clone())
+                .arg("--page")
+                .arg(page.to_string());
 
             let page = Self::json::<LibrariesPage>(&mut cmd)?;
 
-            for library in page.items {
-                res.push(library);
+            // `total` is the number of libraries, not of pages
+            let last = page.items.is_empty() || page.page * page.perpage >= page.total;
+
+            res.extend(page.items);
+
+            if let Some(limit) = limit {
+                if res.len() >= limit {
+                    res.truncate(limit);
+                    break;
+                }
             }
 
-            if page.page == page.total {
-                break Ok(res);
+            if last {
+                break;
             }
         }
+
+        Ok(res)
     }
 
     pub fn platforms(&self, name: Option<impl AsRef<str>>) -> Result<Vec<Platform>> {
@@ -465,6 +500,7 @@ pub struct PioInstaller {
     _installer_temp: Option<TempPath>,
     pio_location: Option<PathBuf>,
     silent: bool,
+    stdout_to_stderr: bool,
 }
 
 impl PioInstaller {
@@ -484,6 +520,7 @@ impl PioInstaller {
             _installer_temp: None,
             pio_location: None,
             silent: false,
+            stdout_to_stderr: false,
         })
     }
 
@@ -493,6 +530,14 @@ impl PioInstaller {
         self
     }
 
+    /// Print the output of the installer to stderr, so that stdout only contains the
+    /// output of the caller.
+    pub fn stdout_to_stderr(&mut self) -> &mut Self {
+        self.stdout_to_stderr = true;
+
+        self
+    }
+
     fn create(download: bool) -> Result<Self> {
         check_python_at_least(3, 6)?;
 
@@ -514,6 +559,7 @@ impl PioInstaller {
             _installer_temp: Some(temp_path),
             pio_location: None,
             silent: false,
+            stdout_to_stderr: false,
         })
     }
 
@@ -550,7 +596,7 @@ impl PioInstaller {
             cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        self.status(&mut cmd)?;
 
         Ok(())
     }
@@ -570,11 +616,25 @@ impl PioInstaller {
             cmd.stderr(Stdio::null());
         }
 
-        cmd.status()?;
+        self.status(&mut cmd)?;
 
         Ok(serde_json::from_reader::<File, PioInstallerInfo>(file)?)
     }
 
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        if self.stdout_to_stderr && !self.silent {
+            cmd.stdout(Stdio::piped()).spawn().and_then(|mut child| {
+                if let Some(mut stdout) = child.stdout.take() {
+                    io::copy(&mut stdout, &mut io::stderr())?;
+                }
+
+                child.wait()
+            })
+        } else {
+            cmd.status()
+        }
+    }
+
     fn command(&self) -> Command {
         let mut command = Command::new(PYTHON);
         if let Some(pio_location) = self.pio_location.as_ref() {
@@ -629,12 +689,12 @@ impl TryFrom<ResolutionParams> for Resolution {
 }
 
 pub struct TargetConf {
-    platform: &'static str,
-    mcu: &'static str,
-    frameworks: Vec<&'static str>,
+    pub platform: &'static str,
+    pub mcu: &'static str,
+    pub frameworks: Vec<&'static str>,
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Resolution {
     pub board: String,
     pub mcu: String,
@@ -0,0 +1,408 @@
+//! Editing of `platformio.ini` files.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+const SECTION_PLATFORMIO: &str = "platformio";
+const SECTION_ENV: &str = "env";
+const OPTION_DEFAULT_ENVS: &str = "default_envs";
+const OPTION_EXTENDS: &str = "extends";
+
+/// A `platformio.ini` file that can be edited without losing comments, formatting and
+/// all sections and options that are not edited.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlatformioIni {
+    lines: Vec<String>,
+}
+
+impl PlatformioIni {
+    pub fn parse(content: &str) -> Self {
+        Self {
+            lines: content.lines().map(str::to_owned).collect(),
+        }
+    }
+
+    /// Try to load the `platformio.ini` file at `path`.
+    pub fn try_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        Ok(Self::parse(&fs::read_to_string(path).with_context(
+            || anyhow!("Could not read '{}'", path.display()),
+        )?))
+    }
+
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+
+        fs::write(path, self.to_string())
+            .with_context(|| anyhow!("Could not write '{}'", path.display()))
+    }
+
+    /// The names of all `[env:<name>]` sections.
+    pub fn environments(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .filter_map(|line| section_name(line))
+            .filter_map(|section| env_name(&section).map(str::to_owned))
+            .collect()
+    }
+
+    /// The environments of the `default_envs` option of the `[platformio]` section.
+    pub fn default_environments(&self) -> Vec<String> {
+        self.get(SECTION_PLATFORMIO, OPTION_DEFAULT_ENVS)
+            .map(|envs| split_list(&envs))
+            .unwrap_or_default()
+    }
+
+    /// Get the value of `option` in `section`.
+    ///
+    /// The lines of multi-line values are separated by `\n`.
+    pub fn get(&self, section: &str, option: &str) -> Option<String> {
+        let (start, end) = self.section(section)?;
+        let (line, end) = self.option(start + 1, end, option)?;
+
+        let mut value = option_value(&self.lines[line])
+            .unwrap_or_default()
+            .to_owned();
+        for continuation in &self.lines[line + 1..end] {
+            let continuation = strip_inline_comment(continuation.trim());
+            if !continuation.is_empty() && !is_comment(continuation) {
+                if !value.is_empty() {
+                    value.push('\n');
+                }
+                value.push_str(continuation);
+            }
+        }
+
+        Some(value)
+    }
+
+    /// Get the value of `option` of the environment `env`, falling back to the sections
+    /// it `extends` and to the common `[env]` section like PlatformIO does.
+    pub fn get_env_option(&self, env: &str, option: &str) -> Option<String> {
+        let section = format!("{}:{}", SECTION_ENV, env);
+
+        self.get(&section, option)
+            .or_else(|| {
+                self.get(&section, OPTION_EXTENDS)
+                    .into_iter()
+                    .flat_map(|extends| split_list(&extends))
+                    .find_map(|extended| self.get(&extended, option))
+            })
+            .or_else(|| self.get(SECTION_ENV, option))
+    }
+
+    /// Set `option` in `section` to `value`, adding the section if it doesn't exist.
+    pub fn set(&mut self, section: &str, option: &str, value: &str) {
+        let line = format!("{} = {}", option, value);
+
+        match self.section(section) {
+            Some((start, end)) => match self.option(start + 1, end, option) {
+                Some((line_index, option_end)) => {
+                    self.lines.splice(line_index..option_end, [line]);
+                }
+                None => {
+                    let end = self.content_end(start, end);
+                    self.lines.insert(end, line);
+                }
+            },
+            None => {
+                self.push_section(section);
+                self.lines.push(line);
+            }
+        }
+    }
+
+    /// Remove `option` from `section`.
+    ///
+    /// Returns whether the option existed.
+    pub fn remove(&mut self, section: &str, option: &str) -> bool {
+        let option = self
+            .section(section)
+            .and_then(|(start, end)| self.option(start + 1, end, option));
+
+        if let Some((line, end)) = option {
+            self.lines.drain(line..end);
+        }
+
+        option.is_some()
+    }
+
+    /// Add the environment `name` with `options`.
+    pub fn add_environment(
+        &mut self,
+        name: &str,
+        options: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<()> {
+        if self.environments().iter().any(|env| env == name) {
+            bail!("Environment '{}' already exists", name);
+        }
+
+        self.push_section(&format!("{}:{}", SECTION_ENV, name));
+        self.lines.extend(
+            options
+                .iter()
+                .map(|(key, value)| format!("{} = {}", key.as_ref(), value.as_ref())),
+        );
+
+        Ok(())
+    }
+
+    /// Remove the environment `name` and remove it from the default environments.
+    ///
+    /// Comments directly above the section are removed with it, while comments at the end
+    /// of the section are kept, as they usually belong to the next section. Returns
+    /// whether the environment existed.
+    pub fn remove_environment(&mut self, name: &str) -> bool {
+        let (start, end) = match self.section(&format!("{}:{}", SECTION_ENV, name)) {
+            Some(section) => section,
+            None => return false,
+        };
+
+        let end = self.content_end(start, end);
+        let start = self.lines[..start]
+            .iter()
+            .rposition(|line| !is_comment(line.trim()))
+            .map_or(0, |line| line + 1);
+        self.lines.drain(start..end);
+
+        // Don't leave two empty lines behind.
+        if start > 0
+            && self.lines[start - 1].trim().is_empty()
+            && self.lines.get(start).map_or(true, |l| l.trim().is_empty())
+        {
+            self.lines.remove(start - 1);
+        }
+
+        let default_envs = self.default_environments();
+        if default_envs.iter().any(|env| env == name) {
+            let default_envs = default_envs
+                .into_iter()
+                .filter(|env| env != name)
+                .collect::<Vec<_>>();
+
+            if default_envs.is_empty() {
+                self.remove(SECTION_PLATFORMIO, OPTION_DEFAULT_ENVS);
+            } else {
+                self.set(
+                    SECTION_PLATFORMIO,
+                    OPTION_DEFAULT_ENVS,
+                    &default_envs.join(", "),
+                );
+            }
+        }
+
+        true
+    }
+
+    /// Get the range of lines of `section`, including its header.
+    fn section(&self, section: &str) -> Option<(usize, usize)> {
+        let start = self
+            .lines
+            .iter()
+            .position(|line| section_name(line).as_deref() == Some(section))?;
+        let end = self.lines[start + 1..]
+            .iter()
+            .position(|line| section_name(line).is_some())
+            .map_or(self.lines.len(), |end| start + 1 + end);
+
+        Some((start, end))
+    }
+
+    /// Get the range of lines of `option` (including continuation lines) in the lines
+    /// `start..end`.
+    fn option(&self, start: usize, end: usize, option: &str) -> Option<(usize, usize)> {
+        let line = (start..end).find(|&i| option_name(&self.lines[i]) == Some(option))?;
+        let end = (line + 1..end)
+            .find(|&i| !is_continuation(&self.lines[i]))
+            .unwrap_or(end);
+
+        Some((line, end))
+    }
+
+    /// Get the end of the last option of the section at `start..end`, excluding trailing
+    /// empty lines and comments.
+    fn content_end(&self, start: usize, end: usize) -> usize {
+        (start + 1..end)
+            .rev()
+            .find(|&i| {
+                let line = self.lines[i].trim();
+                !line.is_empty() && !is_comment(line)
+            })
+            .map_or(start + 1, |last| last + 1)
+    }
+
+    fn push_section(&mut self, section: &str) {
+        if self.lines.last().map_or(false, |l| !l.trim().is_empty()) {
+            self.lines.push(String::new());
+        }
+
+        self.lines.push(format!("[{}]", section));
+    }
+}
+
+impl fmt::Display for PlatformioIni {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with(';') || line.starts_with('#')
+}
+
+fn is_continuation(line: &str) -> bool {
+    line.starts_with(char::is_whitespace) && !line.trim().is_empty()
+}
+
+fn section_name(line: &str) -> Option<String> {
+    let line = line.trim_end();
+    let line = line
+        .find([';', '#'])
+        .map_or(line, |comment| line[..comment].trim_end());
+
+    line.strip_prefix('[')?
+        .strip_suffix(']')
+        .map(|name| name.trim().to_owned())
+}
+
+fn env_name(section: &str) -> Option<&str> {
+    section
+        .strip_prefix(SECTION_ENV)?
+        .strip_prefix(':')
+        .map(str::trim)
+}
+
+fn option_name(line: &str) -> Option<&str> {
+    if line.starts_with(char::is_whitespace) || is_comment(line) {
+        return None;
+    }
+
+    line.split_once(['=', ':']).map(|(name, _)| name.trim())
+}
+
+fn option_value(line: &str) -> Option<&str> {
+    line.split_once(['=', ':'])
+        .map(|(_, value)| strip_inline_comment(value.trim()))
+}
+
+/// Strip a comment at the end of a value, which PlatformIO only recognizes after
+/// whitespace.
+fn strip_inline_comment(value: &str) -> &str {
+    value
+        .match_indices([';', '#'])
+        .find(|(i, _)| value[..*i].ends_with(char::is_whitespace))
+        .map_or(value, |(i, _)| value[..i].trim_end())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INI: &str = r#"; PlatformIO Project Configuration File
+[platformio]
+default_envs = debug, esp32c3
+
+[env]
+board = esp32dev ; inline comment
+platform = espressif32
+extra_scripts = pre:platformio.git.py, platformio.cargo.py
+git_repos =
+  a@b
+  c@d
+
+[env:debug]
+build_type = debug
+
+; The C3 dev board
+[env:esp32c3]
+extends = env:debug
+board = esp32-c3-devkitm-1
+rust_target = riscv32imc-esp-espidf
+
+; Release builds
+[env:release]
+build_type = release
+"#;
+
+    #[test]
+    fn read() {
+        let ini = PlatformioIni::parse(INI);
+
+        assert_eq!(ini.environments(), ["debug", "esp32c3", "release"]);
+        assert_eq!(ini.default_environments(), ["debug", "esp32c3"]);
+        assert_eq!(ini.get("env", "board").unwrap(), "esp32dev");
+        assert_eq!(ini.get("env", "git_repos").unwrap(), "a@b\nc@d");
+        assert_eq!(
+            ini.get_env_option("esp32c3", "board").unwrap(),
+            "esp32-c3-devkitm-1"
+        );
+        assert_eq!(
+            ini.get_env_option("esp32c3", "build_type").unwrap(),
+            "debug"
+        );
+        assert_eq!(
+            ini.get_env_option("release", "platform").unwrap(),
+            "espressif32"
+        );
+        assert_eq!(ini.get_env_option("release", "rust_target"), None);
+        assert_eq!(ini.to_string(), INI);
+    }
+
+    #[test]
+    fn edit() {
+        let mut ini = PlatformioIni::parse(INI);
+
+        assert!(ini.remove_environment("esp32c3"));
+        assert!(!ini.remove_environment("esp32c3"));
+        assert_eq!(ini.default_environments(), ["debug"]);
+
+        ini.add_environment("esp32s3", &[("board", "esp32-s3-devkitc-1")])
+            .unwrap();
+        assert!(ini
+            .add_environment("release", &[("board", "esp32dev")])
+            .is_err());
+
+        ini.set("env:debug", "upload_port", "/dev/ttyUSB0");
+        ini.set("env", "git_repos", "e@f");
+
+        assert!(ini.remove_environment("debug"));
+        assert!(ini.default_environments().is_empty());
+
+        assert_eq!(
+            ini.to_string(),
+            r#"; PlatformIO Project Configuration File
+[platformio]
+
+[env]
+board = esp32dev ; inline comment
+platform = espressif32
+extra_scripts = pre:platformio.git.py, platformio.cargo.py
+git_repos = e@f
+
+; Release builds
+[env:release]
+build_type = release
+
+[env:esp32s3]
+board = esp32-s3-devkitc-1
+"#
+        );
+    }
+}
@@ -4,14 +4,14 @@ use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::*;
 use serde::{Deserialize, Serialize};
 
 use super::Resolution;
-use crate::cargo::CargoCmd;
+use crate::cargo::{BuildStd, CargoCmd};
 use crate::utils::OsStrExt;
-use crate::{build, cargo};
+use crate::{build, cargo, cli};
 
 pub const OPTION_QUICK_DUMP: &str = "quick_dump";
 pub const OPTION_TERMINATE_AFTER_DUMP: &str = "terminate_after_dump";
@@ -22,6 +22,9 @@ const VAR_BUILD_RELEASE: &str = "CARGO_PIO_BUILD_RELEASE_BUILD";
 const VAR_BUILD_PROJECT_DIR: &str = "CARGO_PIO_BUILD_PROJECT_DIR";
 const VAR_BUILD_PATH: &str = "CARGO_PIO_BUILD_PATH";
 const VAR_BUILD_INC_FLAGS: &str = "CARGO_PIO_BUILD_INC_FLAGS";
+const VAR_BUILD_DEF_FLAGS: &str = "CARGO_PIO_BUILD_DEF_FLAGS";
+const VAR_BUILD_C_FLAGS: &str = "CARGO_PIO_BUILD_C_FLAGS";
+const VAR_BUILD_CC: &str = "CARGO_PIO_BUILD_CC";
 const VAR_BUILD_LIB_FLAGS: &str = "CARGO_PIO_BUILD_LIB_FLAGS";
 const VAR_BUILD_LIB_DIR_FLAGS: &str = "CARGO_PIO_BUILD_LIB_DIR_FLAGS";
 const VAR_BUILD_LIBS: &str = "CARGO_PIO_BUILD_LIBS";
@@ -43,13 +46,27 @@ const LIB_RS: &[u8] = include_bytes!("resources/lib.rs.resource");
 const MAIN_C: &[u8] = include_bytes!("resources/main.c.resource");
 const DUMMY_C: &[u8] = include_bytes!("resources/dummy.c.resource");
 
+/// The SCons variables of a PlatformIO project that are needed to build and link with it.
+///
+/// New variables may be added, so create values with [`Default`],
+/// [`from_piofirst`](Self::from_piofirst) or [`from_dump`](Self::from_dump).
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[non_exhaustive]
 pub struct SconsVariables {
     pub project_dir: PathBuf,
     pub release_build: bool,
 
     pub path: String,
     pub incflags: String,
+    /// The preprocessor defines of the C compiler.
+    #[serde(default)]
+    pub defflags: String,
+    /// The flags of the C compiler, without the defines and include directories.
+    #[serde(default)]
+    pub cflags: String,
+    /// The C compiler.
+    #[serde(default)]
+    pub cc: String,
     pub libflags: String,
     pub libdirflags: String,
     pub libs: String,
@@ -72,6 +89,9 @@ impl SconsVariables {
 
                 path: env::var(VAR_BUILD_PATH).ok()?,
                 incflags: env::var(VAR_BUILD_INC_FLAGS).ok()?,
+                defflags: env::var(VAR_BUILD_DEF_FLAGS).unwrap_or_default(),
+                cflags: env::var(VAR_BUILD_C_FLAGS).unwrap_or_default(),
+                cc: env::var(VAR_BUILD_CC).unwrap_or_default(),
                 libflags: env::var(VAR_BUILD_LIB_FLAGS).ok()?,
                 libdirflags: env::var(VAR_BUILD_LIB_DIR_FLAGS).ok()?,
                 libs: env::var(VAR_BUILD_LIBS).ok()?,
@@ -114,6 +134,7 @@ pub struct Builder {
     platform_packages_patches_enabled: bool,
     platform_packages_patches: Vec<(PathBuf, PathBuf)>,
     cargo_cmd: Option<CargoCmd>,
+    build_std: BuildStd,
     cargo_options: Vec<String>,
     scons_dump_enabled: bool,
     c_entry_points_enabled: bool,
@@ -131,6 +152,7 @@ impl Builder {
             platform_packages_patches_enabled: false,
             platform_packages_patches: Vec::new(),
             cargo_cmd: None,
+            build_std: BuildStd::None,
             cargo_options: Vec::new(),
             scons_dump_enabled: false,
             c_entry_points_enabled: false,
@@ -234,6 +256,13 @@ impl Builder {
         self
     }
 
+    /// Set the build-std configuration that is added to a crate upgraded with
+    /// [`CargoCmd::Upgrade`] if it has none, [`BuildStd::None`] by default.
+    pub fn build_std(&mut self, build_std: BuildStd) -> &mut Self {
+        self.build_std = build_std;
+        self
+    }
+
     pub fn enable_scons_dump(&mut self) -> &mut Self {
         self.scons_dump_enabled = true;
         self
@@ -300,14 +329,26 @@ impl Builder {
                             .chain(self.cargo_options.iter().map(|s| &s[..])),
                     )?;
 
-                    let rust_lib = cargo_crate.set_library_type(["staticlib"])?;
+                    let rust_lib = cargo_crate.add_library_types(["staticlib"])?;
                     cargo_crate.create_config_toml(Some(resolution.target.clone()), build_std)?;
 
                     self.create_file(PathBuf::from("src").join("lib.rs"), LIB_RS)?;
 
                     rust_lib
                 }
-                _ => cargo_crate.check_staticlib()?,
+                CargoCmd::Upgrade => {
+                    // Existing crates are edited in place, keeping their formatting and
+                    // all settings they already have.
+                    cargo_crate.check_library()?;
+                    cargo_crate.add_library_types(["staticlib"])?;
+                    cargo_crate.complete_config_toml(
+                        &resolution.target,
+                        self.build_std,
+                        "ldproxy",
+                    )?;
+
+                    cargo_crate.check_staticlib()?
+                }
             };
 
             self.create_file("platformio.cargo.py", PLATFORMIO_CARGO_PY)?;
@@ -504,6 +545,25 @@ impl TryFrom<&SconsVariables> for build::CInclArgs {
     }
 }
 
+impl TryFrom<&SconsVariables> for build::CompileCommandsBuilder {
+    type Error = anyhow::Error;
+
+    fn try_from(scons: &SconsVariables) -> Result<Self> {
+        if scons.cc.is_empty() {
+            bail!(
+                "The SCons variables don't contain the C compiler, update the PlatformIO project"
+            );
+        }
+
+        Ok(Self::new()
+            .compiler(scons.full_path(&scons.cc)?)
+            .directory(&scons.project_dir)
+            .args(cli::NativeCommandArgs::new(&scons.cflags))
+            .args(cli::NativeCommandArgs::new(&scons.defflags))
+            .args(cli::NativeCommandArgs::new(&scons.incflags)))
+    }
+}
+
 impl TryFrom<&SconsVariables> for build::LinkArgsBuilder {
     type Error = anyhow::Error;
 
@@ -548,3 +608,67 @@ impl TryFrom<&SconsVariables> for build::LinkArgsBuilder {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn compile_commands_from_scons() {
+        let temp = TempDir::new().unwrap();
+        let bin_dir = temp.path();
+
+        let cc = bin_dir.join(if cfg!(windows) {
+            "xtensa-esp32-elf-gcc.exe"
+        } else {
+            "xtensa-esp32-elf-gcc"
+        });
+        fs::write(&cc, "").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&cc, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let scons = SconsVariables {
+            project_dir: "/project".into(),
+            path: bin_dir.to_str().unwrap().to_owned(),
+            incflags: "-I/idf/include".to_owned(),
+            defflags: "-DESP_PLATFORM -DIDF_VER=\\\"v4.4\\\"".to_owned(),
+            cflags: "-std=gnu99 -mlongcalls -Os".to_owned(),
+            cc: "xtensa-esp32-elf-gcc".to_owned(),
+            link: "xtensa-esp32-elf-g++".to_owned(),
+            ..Default::default()
+        };
+
+        let commands = build::CompileCommandsBuilder::try_from(&scons)
+            .unwrap()
+            .sources(["src/main.c"])
+            .build()
+            .unwrap();
+
+        assert_eq!(commands.commands.len(), 1);
+        assert_eq!(
+            commands.commands[0].arguments,
+            [
+                cc.to_str().unwrap(),
+                "-std=gnu99",
+                "-mlongcalls",
+                "-Os",
+                "-DESP_PLATFORM",
+                "-DIDF_VER=\"v4.4\"",
+                "-I/idf/include",
+                "-c",
+                Path::new("/project").join("src/main.c").to_str().unwrap(),
+            ]
+        );
+
+        assert!(build::CompileCommandsBuilder::try_from(&SconsVariables {
+            cc: String::new(),
+            ..scons
+        })
+        .is_err());
+    }
+}